@@ -1,46 +1,720 @@
+pub mod checksum;
 pub mod connection;
+pub mod shared_file;
 
 use std::{
-    path::{PathBuf, Path},
+    borrow::Cow,
+    path::{Path, PathBuf},
     fs,
+    io::{ErrorKind, Write},
     collections::HashMap,
-    ffi::OsString, sync::Arc
+    ffi::{OsStr, OsString},
+    sync::{Arc, RwLock, RwLockReadGuard}
 };
 use self::connection::Connection;
+use self::shared_file::SharedFile;
+use crate::json::field::{JsonField, JsonFieldType, ParseJsonError};
+use crate::json::parser::{object_keys, parse_json};
+
+/// Collection names starting with this are reserved for the endpoints the
+/// server provides itself, such as `/__metrics`
+pub const RESERVED_PREFIX: &str = "__";
 
 pub struct JsonDb {
-    connections: HashMap<OsString, Arc<Connection>>
+    /// Collections by name, written to when collections are created or dropped at runtime
+    connections: RwLock<HashMap<OsString, Arc<Connection>>>,
+    /// Files which couldn't be loaded, the server keeps running without them
+    failures: Vec<LoadFailure>,
+    /// Where collections created at runtime are saved, `None` when the
+    /// single file couldn't be read as it would be overwritten otherwise
+    root: Option<Root>,
+    /// Serialized collections by name, by the name of the snapshot
+    snapshots: RwLock<HashMap<String, HashMap<OsString, String>>>,
+    /// Held shared by the requests using the collections and exclusively by
+    /// the snapshots, resets and removals, so that no request sees those half
+    /// done nor writes a collection back once removed
+    gate: RwLock<()>,
+    dry_run: bool,
+    versioned: bool
+}
+
+enum Root {
+    Dir(PathBuf),
+    File(Arc<SharedFile>)
+}
+
+#[derive(Debug)]
+pub struct LoadFailure {
+    pub file: String,
+    pub reason: String
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DbCollectionError {
+    /// Names are limited to letters, digits, `-` and `_`, and can't be reserved
+    InvalidName,
+    AlreadyExists,
+    NotFound,
+    Io(String)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DbSnapshotError {
+    /// Names are limited to letters, digits, `-` and `_`
+    InvalidName,
+    AlreadyExists,
+    NotFound,
+    /// The collection doesn't exist, or isn't part of the snapshot
+    CollectionNotFound(String),
+    /// The collection no longer has the shape it had in the snapshot
+    Mismatch(String)
 }
 
 impl JsonDb {
-    pub fn new(root_dir: &PathBuf, dry_run: bool) -> Self {
-        let files = fs::read_dir(root_dir.clone()).unwrap();
-        let mut connections = HashMap::new();
+    /// Loads every `*.json` file of the directory as a collection, or every
+    /// top-level key of the file as one when given a single file
+    pub fn new(root: &Path, dry_run: bool, versioned: bool) -> Self {
+        let mut db = Self::empty(dry_run, versioned);
 
         println!("=========== Reading JSON ===========");
-        for file in files {  
-            let file_name_os_str = file.unwrap().file_name();
-            let file_name = file_name_os_str.to_str().unwrap();
+        if root.is_file() {
+            db.load_file(root);
+        } else {
+            db.load_dir(root);
+        }
+        println!();
+
+        db
+    }
+
+    /// Database without any collection, for when loading them failed as a
+    /// whole. Collections can't be created as there is nowhere to save them
+    pub fn failed(root: &Path, reason: String, dry_run: bool, versioned: bool) -> Self {
+        let mut db = Self::empty(dry_run, versioned);
+        db.fail(root.to_string_lossy().into_owned(), reason);
+        db
+    }
+
+    fn empty(dry_run: bool, versioned: bool) -> Self {
+        Self {
+            connections: RwLock::new(HashMap::new()),
+            failures: Vec::new(),
+            root: None,
+            snapshots: RwLock::new(HashMap::new()),
+            gate: RwLock::new(()),
+            dry_run,
+            versioned
+        }
+    }
+
+    fn load_dir(&mut self, root_dir: &Path) {
+        self.root = Some(Root::Dir(root_dir.to_path_buf()));
+        self.load_namespace(root_dir, "");
+    }
+
+    /// Loads the files of the directory under the namespace, which is the path
+    /// of the directory relative to the root, such as `v1/` for `v1/users.json`
+    fn load_namespace(&mut self, dir: &Path, namespace: &str) {
+        let files = match fs::read_dir(dir) {
+            Ok(files) => files,
+            Err(err) => return self.fail(dir.to_string_lossy().into_owned(), err.to_string())
+        };
+
+        for file in files {
+            let file = match file {
+                Ok(file) => file,
+                Err(err) => {
+                    self.fail(dir.to_string_lossy().into_owned(), err.to_string());
+                    continue;
+                }
+            };
+            let file_name_os_str = file.file_name();
+            let file_name = match file_name_os_str.to_str() {
+                Some(file_name) => file_name,
+                None => {
+                    let file_name = file_name_os_str.to_string_lossy();
+                    self.fail(format!("{namespace}{file_name}"), "The file name isn't valid UTF-8".to_owned());
+                    continue;
+                }
+            };
+
+            // Symlinked directories aren't followed, so that a link to a parent can't loop
+            if file.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                if file_name.starts_with('.') { continue; }
+                if file_name.starts_with(RESERVED_PREFIX) {
+                    println!("Warning: skipping {namespace}{file_name}/, names starting with \"{RESERVED_PREFIX}\" are reserved");
+                    continue;
+                }
+
+                self.load_namespace(&file.path(), &format!("{namespace}{file_name}/"));
+                continue;
+            }
+
             if !file_name.ends_with(".json") || file_name == "schema.json" { continue; }
     
-            let file_stem = Path::new(file_name).file_stem().unwrap().to_owned();
+            let file_stem = Path::new(file_name).file_stem().unwrap().to_string_lossy();
+            if file_name.starts_with(RESERVED_PREFIX) {
+                println!("Warning: skipping {namespace}{file_name}, names starting with \"{RESERVED_PREFIX}\" are reserved");
+                continue;
+            }
+
+            println!("Connecting ... {namespace}{file_name}");
+
+            match Connection::new(file.path()) {
+                Ok(connection) => self.connect(OsString::from(format!("{namespace}{file_stem}")), connection),
+                Err(err) => self.fail(format!("{namespace}{file_name}"), err.0)
+            }
+        }
+    }
+
+    fn load_file(&mut self, path: &Path) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+        let fields = match read_json_object(path) {
+            Ok(fields) => fields,
+            Err(err) => return self.fail(file_name, err.0)
+        };
+
+        // Every key is written back along with the collections, including those which aren't served
+        let values = fields.iter().map(|(key, field)| (key.clone(), field.stringify())).collect();
+        let shared = Arc::new(SharedFile::new(path.to_path_buf(), values));
+        self.root = Some(Root::File(Arc::clone(&shared)));
+
+        for (key, field) in fields {
+            if key.starts_with(RESERVED_PREFIX) {
+                println!("Warning: skipping \"{key}\", names starting with \"{RESERVED_PREFIX}\" are reserved");
+                continue;
+            }
+            if !field.is(JsonFieldType::Array) && !field.is(JsonFieldType::Object) {
+                println!("Warning: skipping \"{key}\" of {file_name}, only arrays and objects are served");
+                continue;
+            }
+
+            println!("Connecting ... {file_name} \"{key}\"");
+
+            match Connection::shared(Arc::clone(&shared), key.clone(), field) {
+                Ok(connection) => self.connect(OsString::from(key), connection),
+                Err(err) => self.fail(file_name.clone(), err.0)
+            }
+        }
+    }
+
+    fn connect(&mut self, name: OsString, connection: Connection) {
+        let connection = self.configure(connection);
+        self.connections.get_mut().unwrap().insert(name, connection);
+    }
+
+    fn configure(&self, mut connection: Connection) -> Arc<Connection> {
+        if self.dry_run { connection.dry_run(); }
+        if self.versioned { connection.versioned(); }
+
+        Arc::new(connection)
+    }
+
+    fn fail(&mut self, file: String, reason: String) {
+        eprintln!("Failed to load {file}: {reason}");
+        self.failures.push(LoadFailure { file, reason });
+    }
+
+    /// Creates an empty collection, saved as a file of its own or as a key
+    /// of the single file depending on how the collections were loaded
+    pub fn create(&self, name: &str) -> Result<Arc<Connection>, DbCollectionError> {
+        if !is_valid_name(name) {
+            return Err(DbCollectionError::InvalidName);
+        }
+
+        // Held throughout so that the same name can't be created twice at once
+        let mut connections = self.connections.write().unwrap();
+        if connections.contains_key(OsStr::new(name)) {
+            return Err(DbCollectionError::AlreadyExists);
+        }
 
-            println!("Connecting ... {}", file_name);
+        let connection = match self.root.as_ref() {
+            Some(Root::Dir(dir)) => {
+                let file = dir.join(format!("{name}.json"));
+                if !self.dry_run {
+                    fs::create_dir_all(file.parent().unwrap()).map_err(|err| DbCollectionError::Io(err.to_string()))?;
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&file)
+                        .and_then(|mut file| file.write_all(b"[]"))
+                        .map_err(|err| match err.kind() {
+                            ErrorKind::AlreadyExists => DbCollectionError::AlreadyExists,
+                            _ => DbCollectionError::Io(err.to_string())
+                        })?;
+                }
+                Connection::empty(file)
+            },
+            Some(Root::File(shared)) => {
+                // Keys which aren't served, such as numbers, are still part of the file
+                if shared.contains(name) {
+                    return Err(DbCollectionError::AlreadyExists);
+                }
+                if !self.dry_run {
+                    shared.write(name, "[]".to_owned()).map_err(|err| DbCollectionError::Io(err.to_string()))?;
+                }
+                Connection::shared(Arc::clone(shared), name.to_owned(), JsonField::new_json_arr())
+                    .map_err(|err| DbCollectionError::Io(err.0))?
+            },
+            None => return Err(DbCollectionError::Io("The collections failed to load".to_owned()))
+        };
 
-            let file_path = root_dir.join(file_name);
-            let mut connection = Connection::new(file_path).unwrap();
+        let connection = self.configure(connection);
+        connections.insert(OsString::from(name), Arc::clone(&connection));
+        Ok(connection)
+    }
+
+    /// Removes the collection along with its file, or its key of the single
+    /// file, once no request uses the collections
+    pub fn remove(&self, name: &str) -> Result<(), DbCollectionError> {
+        let _gate = self.gate.write().unwrap();
+        let mut connections = self.connections.write().unwrap();
+        if !connections.contains_key(OsStr::new(name)) {
+            return Err(DbCollectionError::NotFound);
+        }
+
+        if !self.dry_run {
+            let removed = match self.root.as_ref() {
+                Some(Root::Dir(dir)) => fs::remove_file(dir.join(format!("{name}.json"))),
+                Some(Root::File(shared)) => shared.remove(name),
+                None => Ok(())
+            };
+            removed.map_err(|err| DbCollectionError::Io(err.to_string()))?;
+        }
+
+        connections.remove(OsStr::new(name));
+        Ok(())
+    }
+
+    /// Shared access to the collections, held for as long as a request uses them
+    pub fn access(&self) -> RwLockReadGuard<'_, ()> {
+        self.gate.read().unwrap()
+    }
+
+    /// Saves the current content of every collection under the name
+    pub fn snapshot(&self, name: &str) -> Result<(), DbSnapshotError> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(DbSnapshotError::InvalidName);
+        }
+
+        let _gate = self.gate.write().unwrap();
+        let mut snapshots = self.snapshots.write().unwrap();
+        if snapshots.contains_key(name) {
+            return Err(DbSnapshotError::AlreadyExists);
+        }
+
+        let collections = self.connections
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, connection)| (name.clone(), connection.snapshot()))
+            .collect();
+        snapshots.insert(name.to_owned(), collections);
+        Ok(())
+    }
+
+    pub fn snapshots(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.snapshots.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn remove_snapshot(&self, name: &str) -> Result<(), DbSnapshotError> {
+        match self.snapshots.write().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(DbSnapshotError::NotFound)
+        }
+    }
+
+    /// Restores the collections, every one when `None`, to the snapshot or to
+    /// their content as loaded when `None`. Nothing is restored unless all of
+    /// them can be, and no request is handled meanwhile. Returns the names of
+    /// the collections restored.
+    ///
+    /// Collections aren't created nor dropped: those created at runtime are
+    /// kept and reset to empty, as created, and those dropped stay dropped
+    pub fn reset(&self, names: Option<&[String]>, snapshot: Option<&str>) -> Result<Vec<String>, DbSnapshotError> {
+        let _gate = self.gate.write().unwrap();
+        let snapshots = self.snapshots.read().unwrap();
+        let snapshot = match snapshot {
+            Some(name) => Some(snapshots.get(name).ok_or(DbSnapshotError::NotFound)?),
+            None => None
+        };
+
+        let collections: Vec<(OsString, Arc<Connection>)> = match names {
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    self.get(OsStr::new(name))
+                        .map(|connection| (OsString::from(name), connection))
+                        .ok_or_else(|| DbSnapshotError::CollectionNotFound(name.clone()))
+                })
+                .collect::<Result<_, _>>()?,
+            // Collections created after the snapshot are left as they are
+            None => self.collections()
+                .into_iter()
+                .filter(|(name, _)| snapshot.is_none_or(|snapshot| snapshot.contains_key(name)))
+                .collect()
+        };
+
+        // Parsed up front, so that a collection which can't be restored leaves all of them untouched
+        let mut restored = Vec::with_capacity(collections.len());
+        for (name, connection) in collections {
+            let content = match snapshot {
+                Some(snapshot) => snapshot
+                    .get(&name)
+                    .map(|content| Cow::Borrowed(content.as_str()))
+                    .ok_or_else(|| DbSnapshotError::CollectionNotFound(name.to_string_lossy().into_owned()))?,
+                None => connection.initial()
+            };
+            let json = parse_json(&content, 0)
+                .ok()
+                .map(|(json, _)| json)
+                .filter(|json| json.is(JsonFieldType::Object) == connection.is_singular())
+                .ok_or_else(|| DbSnapshotError::Mismatch(name.to_string_lossy().into_owned()))?;
+
+            restored.push((name, connection, json));
+        }
+
+        let mut names = Vec::with_capacity(restored.len());
+        for (name, connection, json) in restored {
+            // Can't fail, the shape is checked above
+            let _ = connection.restore(json);
+            names.push(name.to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
 
-            if dry_run { connection.dry_run(); }
+    pub fn get(&self, name: &OsStr) -> Option<Arc<Connection>> {
+        self.connections.read().unwrap().get(name).cloned()
+    }
 
-            connections.insert(file_stem, Arc::new(connection));
+    /// Collection the segments of the URL path point at, along with the segment
+    /// following its name if any. `/v1/users` goes to the `v1/users` collection
+    /// rather than to the record `users` of `v1`
+    pub fn resolve<'a>(&self, segments: &[&'a str]) -> Option<(String, Arc<Connection>, Option<&'a str>)> {
+        let name = segments.join("/");
+        if let Some(connection) = self.get(OsStr::new(&name)) {
+            return Some((name, connection, None));
         }
-        println!("");
 
-        Self { connections }
+        let (id, namespace) = segments.split_last()?;
+        let name = namespace.join("/");
+        self.get(OsStr::new(&name)).map(|connection| (name, connection, Some(*id)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn failures(&self) -> &[LoadFailure] {
+        &self.failures
     }
 
-    pub fn get_entry(&self, entrypoint: OsString) -> Arc<Connection> {
-        Arc::clone(self.connections.get(&entrypoint).unwrap())
+    /// Collections sorted by name, as they are at the time of the call
+    pub fn collections(&self) -> Vec<(OsString, Arc<Connection>)> {
+        let mut collections: Vec<_> = self.connections
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, connection)| (name.clone(), Arc::clone(connection)))
+            .collect();
+        collections.sort_by(|(a, _), (b, _)| a.cmp(b));
+        collections
     }
 }
 
+/// Names are namespaced by `/`, as in `v1/users`
+fn is_valid_name(name: &str) -> bool {
+    name.split('/').all(|segment| {
+        !segment.is_empty()
+            && !segment.starts_with(RESERVED_PREFIX)
+            && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Top-level keys of the JSON object in the file along with their values,
+/// in the order of the file
+fn read_json_object(path: &Path) -> Result<Vec<(String, JsonField)>, ParseJsonError> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| ParseJsonError(format!("Encounter error while trying to read {path:?}: {err}")))?;
+
+    let mut fields = match parse_json(&content, 0)?.0 {
+        JsonField::Object(obj) => obj.into_inner().unwrap(),
+        json => return Err(ParseJsonError(format!("Reading {path:?} and the root isn't JsonField::Object type, instead got: {:?}", json.field_type())))
+    };
+    // A key given twice keeps its last value, at the position of its first
+    Ok(object_keys(&content)?.into_iter().filter_map(|key| fields.remove_entry(&key)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn it_loads_the_collections_of_a_single_file() {
+        let dir = TestDir::new();
+        let path = dir.write("db.json", r#"{
+            "users": [{ "id": 1, "name": "Ada" }],
+            "version": 3,
+            "profile": { "name": "Ada" },
+            "__metrics": []
+        }"#);
+
+        let jsondb = JsonDb::new(&path, false, false);
+        assert!(jsondb.failures().is_empty());
+        let names: Vec<_> = jsondb.collections().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["profile", "users"]);
+        assert!(jsondb.get(OsStr::new("profile")).unwrap().is_singular());
+    }
+
+    #[test]
+    fn it_writes_collections_back_into_the_single_file_in_place() {
+        let dir = TestDir::new();
+        let path = dir.write("db.json", r#"{ "users": [{ "id": 1 }], "version": 3, "posts": [] }"#);
+
+        let jsondb = JsonDb::new(&path, false, false);
+        jsondb.get(OsStr::new("posts")).unwrap().insert(JsonField::from(r#"{ "title": "Hello" }"#));
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(object_keys(&content).unwrap(), ["users", "version", "posts"]);
+        assert!(content.contains(r#""version": 3"#));
+
+        // Reloads as it was written
+        let jsondb = JsonDb::new(&path, false, false);
+        assert_eq!(jsondb.get(OsStr::new("posts")).unwrap().len(), 1);
+        assert_eq!(jsondb.get(OsStr::new("users")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_records_single_files_which_fail_to_load() {
+        let dir = TestDir::new();
+
+        let jsondb = JsonDb::new(&dir.write("broken.json", r#"{ "users": [ }"#), false, false);
+        assert_eq!(jsondb.failures().len(), 1);
+        assert_eq!(jsondb.failures()[0].file, "broken.json");
+        assert!(jsondb.is_empty());
+
+        let jsondb = JsonDb::new(&dir.write("array.json", "[]"), false, false);
+        assert!(jsondb.failures()[0].reason.contains("root isn't JsonField::Object"));
+
+        // Nothing to save collections created at runtime into
+        assert_eq!(jsondb.create("posts").unwrap_err(), DbCollectionError::Io("The collections failed to load".to_owned()));
+    }
+
+    #[test]
+    fn it_validates_collection_names() {
+        for name in ["users", "v1/users", "user-posts_2", "v1/admin/users"] {
+            assert!(is_valid_name(name), "{name}");
+        }
+        for name in ["", "/users", "users/", "v1//users", "__users", "v1/__users", "user posts", "users.json", "../users", "v1/../users"] {
+            assert!(!is_valid_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn it_creates_and_removes_collections() {
+        let dir = TestDir::new();
+        dir.write("users.json", "[]");
+        let jsondb = JsonDb::new(dir.path(), false, false);
+
+        let posts = jsondb.create("v1/posts").unwrap();
+        assert!(posts.is_empty());
+        assert_eq!(fs::read_to_string(dir.path().join("v1/posts.json")).unwrap(), "[]");
+        assert!(jsondb.get(OsStr::new("v1/posts")).is_some());
+
+        assert_eq!(jsondb.create("v1/posts").unwrap_err(), DbCollectionError::AlreadyExists);
+        assert_eq!(jsondb.create("users").unwrap_err(), DbCollectionError::AlreadyExists);
+        assert_eq!(jsondb.create("__posts").unwrap_err(), DbCollectionError::InvalidName);
+
+        // Files which weren't loaded aren't overwritten
+        dir.write("tags.json", r#"["rust"]"#);
+        assert_eq!(jsondb.create("tags").unwrap_err(), DbCollectionError::AlreadyExists);
+        assert_eq!(fs::read_to_string(dir.path().join("tags.json")).unwrap(), r#"["rust"]"#);
+
+        assert_eq!(jsondb.remove("v1/posts"), Ok(()));
+        assert!(!dir.path().join("v1/posts.json").exists());
+        assert!(jsondb.get(OsStr::new("v1/posts")).is_none());
+        assert_eq!(jsondb.remove("v1/posts"), Err(DbCollectionError::NotFound));
+    }
+
+    #[test]
+    fn it_creates_and_removes_keys_of_a_single_file() {
+        let dir = TestDir::new();
+        let path = dir.write("db.json", r#"{ "users": [], "version": 3 }"#);
+        let jsondb = JsonDb::new(&path, false, false);
+
+        jsondb.create("posts").unwrap();
+        assert_eq!(object_keys(&fs::read_to_string(&path).unwrap()).unwrap(), ["users", "version", "posts"]);
+        assert_eq!(jsondb.create("version").unwrap_err(), DbCollectionError::AlreadyExists);
+
+        jsondb.remove("users").unwrap();
+        assert_eq!(object_keys(&fs::read_to_string(&path).unwrap()).unwrap(), ["version", "posts"]);
+    }
+
+    #[test]
+    fn it_waits_for_the_requests_in_progress_to_remove_a_collection() {
+        let dir = TestDir::new();
+        dir.write("posts.json", "[]");
+        let jsondb = Arc::new(JsonDb::new(dir.path(), false, false));
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let request = {
+            let jsondb = Arc::clone(&jsondb);
+            std::thread::spawn(move || {
+                let _access = jsondb.access();
+                let posts = jsondb.get(OsStr::new("posts")).unwrap();
+                sender.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                posts.insert(JsonField::from(r#"{ "title": "Hello" }"#));
+            })
+        };
+
+        receiver.recv().unwrap();
+        jsondb.remove("posts").unwrap();
+        request.join().unwrap();
+        // The write went through before the removal, which has the last word
+        assert!(!dir.path().join("posts.json").exists());
+    }
+
+    #[test]
+    fn it_loads_nested_directories_as_namespaces() {
+        let dir = TestDir::new();
+        dir.write("users.json", "[]");
+        dir.write("schema.json", "{}");
+        dir.write("notes.txt", "");
+        dir.write("v1/users.json", "[]");
+        dir.write("v1/admin/settings.json", "{}");
+        dir.write("v1/__internal/users.json", "[]");
+        dir.write(".git/config.json", "{}");
+        dir.write("__metrics.json", "[]");
+
+        let jsondb = JsonDb::new(dir.path(), false, false);
+        assert!(jsondb.failures().is_empty());
+        let names: Vec<_> = jsondb.collections().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["users", "v1/admin/settings", "v1/users"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_records_file_names_which_are_not_utf8_as_load_failures() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TestDir::new();
+        dir.write("users.json", "[]");
+        fs::write(dir.path().join(OsStr::from_bytes(b"bad\xff.json")), "[]").unwrap();
+
+        let jsondb = JsonDb::new(dir.path(), false, false);
+        assert_eq!(jsondb.len(), 1);
+        assert_eq!(jsondb.failures().len(), 1);
+        assert_eq!(jsondb.failures()[0].file, "bad\u{fffd}.json");
+    }
+
+    #[test]
+    fn it_resolves_namespaced_collections_before_records() {
+        let dir = TestDir::new();
+        dir.write("v1.json", r#"[{ "id": 5 }]"#);
+        dir.write("v1/users.json", r#"[{ "id": 3 }]"#);
+        let jsondb = JsonDb::new(dir.path(), false, false);
+
+        let resolve = |segments: &[&str]| jsondb.resolve(segments).map(|(name, _, id)| (name, id.map(str::to_owned)));
+        assert_eq!(resolve(&["v1"]), Some(("v1".to_owned(), None)));
+        assert_eq!(resolve(&["v1", "5"]), Some(("v1".to_owned(), Some("5".to_owned()))));
+        assert_eq!(resolve(&["v1", "users"]), Some(("v1/users".to_owned(), None)));
+        assert_eq!(resolve(&["v1", "users", "3"]), Some(("v1/users".to_owned(), Some("3".to_owned()))));
+        assert_eq!(resolve(&["v1", "users", "3", "posts"]), None);
+        assert_eq!(resolve(&["v2", "users"]), None);
+        assert_eq!(resolve(&[]), None);
+    }
+
+    fn read(jsondb: &JsonDb, name: &str) -> JsonField {
+        JsonField::from(jsondb.get(OsStr::new(name)).unwrap().read().as_str())
+    }
+
+    #[test]
+    fn it_resets_to_the_initial_content_or_to_a_snapshot() {
+        let dir = TestDir::new();
+        dir.write("users.json", r#"[{ "id": 1, "name": "Ada" }]"#);
+        dir.write("profile.json", r#"{ "name": "Ada" }"#);
+        let jsondb = JsonDb::new(dir.path(), true, false);
+        let users = jsondb.get(OsStr::new("users")).unwrap();
+        let profile = jsondb.get(OsStr::new("profile")).unwrap();
+
+        users.insert(JsonField::from(r#"{ "name": "Linus" }"#));
+        jsondb.snapshot("seeded").unwrap();
+        users.delete(1, |_| true).unwrap();
+        profile.merge_singular(JsonField::from(r#"{ "name": "Grace" }"#), |_| true).unwrap();
+
+        assert_eq!(read(&jsondb, "users"), JsonField::from(r#"[{ "id": 2, "name": "Linus" }]"#));
+        assert_eq!(jsondb.reset(None, Some("seeded")).unwrap(), ["profile", "users"]);
+        assert_eq!(read(&jsondb, "users"), JsonField::from(r#"[{ "id": 1, "name": "Ada" }, { "id": 2, "name": "Linus" }]"#));
+        assert_eq!(read(&jsondb, "profile"), JsonField::from(r#"{ "name": "Ada" }"#));
+        assert_eq!(JsonField::from(users.get(1).ok().unwrap().as_str()), JsonField::from(r#"{ "id": 1, "name": "Ada" }"#));
+
+        // Back to the files as loaded rather than to the snapshot
+        profile.merge_singular(JsonField::from(r#"{ "name": "Grace" }"#), |_| true).unwrap();
+        assert_eq!(jsondb.reset(Some(&["users".to_owned()]), None).unwrap(), ["users"]);
+        assert_eq!(read(&jsondb, "users"), JsonField::from(r#"[{ "id": 1, "name": "Ada" }]"#));
+        assert_eq!(users.len(), 1);
+        assert!(users.get(2).is_err());
+        assert_eq!(read(&jsondb, "profile"), JsonField::from(r#"{ "name": "Grace" }"#));
+
+        // Records inserted since are given ids as if the others never were
+        assert_eq!(JsonField::from(users.insert(JsonField::from(r#"{ "name": "Alan" }"#)).as_str()), JsonField::from(r#"{ "id": 2, "name": "Alan" }"#));
+
+        assert_eq!(jsondb.reset(None, Some("missing")), Err(DbSnapshotError::NotFound));
+    }
+
+    #[test]
+    fn it_restores_nothing_when_a_collection_can_not_be_reset() {
+        let dir = TestDir::new();
+        let path = dir.write("db.json", r#"{ "users": [{ "id": 1 }], "profile": { "name": "Ada" } }"#);
+        let jsondb = JsonDb::new(&path, false, false);
+        jsondb.snapshot("before").unwrap();
+        jsondb.get(OsStr::new("users")).unwrap().delete(1, |_| true).unwrap();
+
+        let names = ["users".to_owned(), "posts".to_owned()];
+        assert_eq!(jsondb.reset(Some(&names), None), Err(DbSnapshotError::CollectionNotFound("posts".to_owned())));
+        assert_eq!(read(&jsondb, "users"), JsonField::from("[]"));
+
+        // Not part of the snapshot
+        jsondb.create("posts").unwrap();
+        assert_eq!(jsondb.reset(Some(&names), Some("before")), Err(DbSnapshotError::CollectionNotFound("posts".to_owned())));
+        assert_eq!(read(&jsondb, "users"), JsonField::from("[]"));
+
+        // Dropped and created again as a collection of records
+        jsondb.remove("profile").unwrap();
+        jsondb.create("profile").unwrap();
+        assert_eq!(jsondb.reset(None, Some("before")), Err(DbSnapshotError::Mismatch("profile".to_owned())));
+        assert_eq!(read(&jsondb, "users"), JsonField::from("[]"));
+    }
+
+    #[test]
+    fn it_keeps_collections_created_or_dropped_after_startup_on_reset() {
+        let dir = TestDir::new();
+        dir.write("users.json", r#"[{ "id": 1 }]"#);
+        dir.write("tags.json", "[]");
+        let jsondb = JsonDb::new(dir.path(), true, false);
+        jsondb.snapshot("before").unwrap();
+
+        let posts = jsondb.create("posts").unwrap();
+        posts.insert(JsonField::from(r#"{ "title": "Hello" }"#));
+        jsondb.remove("tags").unwrap();
+
+        // Left out of a snapshot taken before it was created
+        assert_eq!(jsondb.reset(None, Some("before")).unwrap(), ["users"]);
+        assert_eq!(posts.len(), 1);
+
+        // Emptied, as it was created
+        assert_eq!(jsondb.reset(None, None).unwrap(), ["posts", "users"]);
+        assert!(posts.is_empty());
+        assert!(jsondb.get(OsStr::new("posts")).is_some());
+        assert!(jsondb.get(OsStr::new("tags")).is_none());
+    }
+}
@@ -1,87 +1,595 @@
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io::{self, Write};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
 
-use crate::json::field::{JsonField, ParseJsonError};
+use super::checksum::Checksum;
+use super::shared_file::SharedFile;
+
+use crate::json::field::{JsonArray, JsonField, JsonFieldType, JsonObject, ParseJsonError};
 use crate::json::parser::read_json;
 
 #[derive(Debug)]
 pub struct Connection {
-    file: PathBuf,
+    storage: Storage,
     json: JsonField,
+    /// Serialized content as it was loaded, which a reset restores. Kept
+    /// from the first write on, until then the content is still the same
+    initial: OnceLock<String>,
     dry_run: bool,
-    mapped: HashMap<i32, String>
+    /// Whether every write bumps the `_version` field of the record
+    versioned: bool,
+    /// Serialized records by id, the write lock is held for the whole of
+    /// a write so that checking its precondition and applying it is atomic
+    mapped: RwLock<HashMap<i32, String>>,
+    /// Checksum of the serialized collection, computed on demand and
+    /// cleared whenever the collection is written to
+    checksum: Mutex<Option<u64>>,
+    modified: Mutex<SystemTime>,
+    persist_stats: PersistStats
+}
+
+/// Where the collection is written back to
+#[derive(Debug)]
+enum Storage {
+    /// File of its own, holding just the array of records
+    File(PathBuf),
+    /// Top-level key of a file the collection shares with others
+    Shared(Arc<SharedFile>, String)
+}
+
+impl Storage {
+    fn path(&self) -> &Path {
+        match self {
+            Self::File(file) => file,
+            Self::Shared(shared, _) => shared.path()
+        }
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(file) => write!(f, "{file:?}"),
+            Self::Shared(shared, key) => write!(f, "{key:?} of {:?}", shared.path())
+        }
+    }
+}
+
+/// How writing the collection back to its file has been going
+#[derive(Debug, Default)]
+pub struct PersistStats {
+    pub writes: AtomicU64,
+    pub errors: AtomicU64,
+    /// Whether the latest write failed, cleared by the next one to succeed
+    pub failing: AtomicBool,
+    /// Total time spent writing, in microseconds
+    pub duration_micros: AtomicU64
 }
 
 pub struct DbQueryError<'a>(pub &'a str);
 
+#[derive(Debug, PartialEq)]
+pub enum DbWriteError {
+    NotFound,
+    /// The precondition given along with the write rejected the current record
+    PreconditionFailed
+}
+
+const VERSION_KEY: &str = "_version";
+
+fn id_of(field: &JsonField) -> Option<i32> {
+    let obj = field.unwrap_as_ref_object().ok()?.read().unwrap();
+    obj.get("id")?.unwrap_as_ref_int().ok().copied()
+}
+
+fn position_of(arr: &JsonArray, id: i32) -> Option<usize> {
+    arr.iter().position(|field| id_of(field) == Some(id))
+}
+
+/// One more than the highest id, the lowest id free once that runs out
+fn next_id(mapped: &HashMap<i32, String>) -> i32 {
+    match mapped.keys().max() {
+        None => 1,
+        Some(&max) => max
+            .checked_add(1)
+            .unwrap_or_else(|| (1..i32::MAX).find(|id| !mapped.contains_key(id)).expect("No record id left"))
+    }
+}
+
+/// Version the record gets with the write, one more than the version it had
+fn next_version(obj: &JsonObject) -> i32 {
+    match obj.get(VERSION_KEY) {
+        Some(JsonField::Int(version)) => version + 1,
+        _ => 1
+    }
+}
+
+/// Serialized records by id, records without an integer id are skipped with a warning
+fn map_records(arr: &JsonArray, storage: &Storage) -> HashMap<i32, String> {
+    let mut mapped = HashMap::new();
+
+    for field in arr.iter() {
+        let obj_lock = field.unwrap_as_ref_object();
+        if obj_lock.is_err() {
+            println!("Warning: reading {storage} and expect to get JsonField::Object type, instead got: {}", field.stringify());
+            continue;
+        }
+        let obj = obj_lock.unwrap().read().unwrap();
+
+        match obj.get("id") {
+            Some(id_field) => {
+                match id_field.unwrap_as_ref_int() {
+                    Ok(id) => { mapped.insert(*id, field.stringify()); },
+                    Err(_) => println!("Warning: reading {storage} and its \"id\" field is not JsonField::Int type, instead got: {:?}", id_field.field_type())
+                }
+            },
+            None => {
+                println!("Warning: reading {storage} and it contains non relational record with content: {:?}", field.stringify());
+            }
+        };
+    }
+
+    mapped
+}
+
 impl Connection {
     pub fn new(file: PathBuf) -> Result<Self, ParseJsonError> {
         let json = read_json(&file)?;
+        Self::from_json(json, Storage::File(file))
+    }
+
+    /// Collection without records, saved to the file on its first write
+    pub fn empty(file: PathBuf) -> Self {
+        Self::from_json(JsonField::new_json_arr(), Storage::File(file)).unwrap()
+    }
 
-        let mut mapped = HashMap::new();
+    /// Collection under the key of the file it shares with other collections
+    pub fn shared(file: Arc<SharedFile>, key: String, json: JsonField) -> Result<Self, ParseJsonError> {
+        Self::from_json(json, Storage::Shared(file, key))
+    }
 
-        {
-            let arr_lock = json.unwrap_as_ref_array().unwrap_or_else(|err| {
-                panic!("Reading {file:?} and the root isn't JsonField::Array type: {err:?}");
-            });
-    
-            let arr = arr_lock.read().unwrap();
-    
-            for field in arr.iter() {
-                let obj_lock = field.unwrap_as_ref_object();
-                if obj_lock.is_err() {
-                    println!("Warning: reading {file:?} and expect to get JsonField::Object type, instead got: {}", field.stringify());
-                    continue;
-                }
-                let obj = obj_lock.unwrap().read().unwrap();
+    fn from_json(json: JsonField, storage: Storage) -> Result<Self, ParseJsonError> {
+        // Object-rooted files are singular resources, without records to map
+        let mapped = match json.unwrap_as_ref_array() {
+            Ok(arr_lock) => map_records(&arr_lock.read().unwrap(), &storage),
+            Err(_) if json.is(JsonFieldType::Object) => HashMap::new(),
+            Err(_) => return Err(ParseJsonError(format!(
+                "Reading {storage} and the root isn't JsonField::Array or JsonField::Object type, instead got: {:?}",
+                json.field_type()
+            )))
+        };
 
-                match obj.get("id") {
-                    Some(id_field) => {
-                        let id = id_field.unwrap_as_ref_int();
-                        if id.is_err() {
-                            println!("Warning: reading {file:?} and its \"id\" field is not JsonField::Int type, instead got: {:?}", id_field.field_type());
-                        } else {
-                            mapped.insert(*id.unwrap(), field.stringify());
-                        }
-                    },
-                    None => {
-                        println!("Warning: reading {file:?} and it contains non relational record with content: {:?}", field.stringify());
-                    }
-                };
-            }    
-        }
+        let modified = fs::metadata(storage.path())
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
 
-        Ok(Self { file, json, dry_run: false, mapped })
+        Ok(Self {
+            initial: OnceLock::new(),
+            storage,
+            json,
+            dry_run: false,
+            versioned: false,
+            mapped: RwLock::new(mapped),
+            checksum: Mutex::new(None),
+            modified: Mutex::new(modified),
+            persist_stats: PersistStats::default()
+        })
     }
 
     pub fn dry_run(&mut self) {
         self.dry_run = true;
     }
 
+    pub fn versioned(&mut self) {
+        self.versioned = true;
+    }
+
     // TODO: Provide option for pretty format JSON
     pub fn read(&self) -> String {
         self.json.stringify()
     }
 
-    pub fn get(&self, id: i32) -> Result<String, DbQueryError> {
-        match self.mapped.get(&id) {
+    pub fn read_into(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.json.write_json(writer)
+    }
+
+    /// Whether the file holds a single object rather than an array of records
+    pub fn is_singular(&self) -> bool {
+        self.json.is(JsonFieldType::Object)
+    }
+
+    /// Number of records in the collection, a singular resource counts as one
+    pub fn len(&self) -> usize {
+        match self.json.unwrap_as_ref_array() {
+            Ok(arr) => arr.read().unwrap().len(),
+            Err(_) => 1
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn checksum(&self) -> u64 {
+        *self.checksum.lock().unwrap().get_or_insert_with(|| {
+            let mut checksum = Checksum::new();
+            self.json.write_json(&mut checksum).unwrap();
+            checksum.value()
+        })
+    }
+
+    /// When the collection was last written to, the file modification time
+    /// until it is written to by the server
+    pub fn modified(&self) -> SystemTime {
+        *self.modified.lock().unwrap()
+    }
+
+    pub fn persist_stats(&self) -> &PersistStats {
+        &self.persist_stats
+    }
+
+    /// Serialized content as it was loaded
+    pub fn initial(&self) -> Cow<'_, str> {
+        match self.initial.get() {
+            Some(initial) => Cow::Borrowed(initial),
+            None => Cow::Owned(self.snapshot())
+        }
+    }
+
+    /// Keeps the content as loaded before it's first written to, called with
+    /// the write lock of `mapped` held
+    fn keep_initial(&self) {
+        self.initial.get_or_init(|| self.json.stringify());
+    }
+
+    /// Serialized content, consistent with respect to the writes in progress
+    pub fn snapshot(&self) -> String {
+        let _mapped = self.mapped.read().unwrap();
+        self.json.stringify()
+    }
+
+    /// Replaces the whole content with the JSON, such as a snapshot taken
+    /// earlier, which has to be of the same shape as the content
+    pub fn restore(&self, json: JsonField) -> Result<(), ParseJsonError> {
+        let mut mapped = self.mapped.write().unwrap();
+        self.keep_initial();
+
+        match (&self.json, json) {
+            (JsonField::Array(arr), JsonField::Array(restored)) => {
+                let restored = restored.into_inner().unwrap();
+                *mapped = map_records(&restored, &self.storage);
+                *arr.write().unwrap() = restored;
+            },
+            (JsonField::Object(obj), JsonField::Object(restored)) => {
+                *obj.write().unwrap() = restored.into_inner().unwrap();
+            },
+            (_, json) => return Err(ParseJsonError(format!(
+                "Restoring {} and expect {:?} type, instead got: {:?}",
+                self.storage,
+                self.json.field_type(),
+                json.field_type()
+            )))
+        }
+        self.persist();
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: i32) -> Result<String, DbQueryError<'_>> {
+        match self.mapped.read().unwrap().get(&id) {
             Some(value) => Ok(value.clone()),
             None => Err(DbQueryError("Trying to get record with id: {id}, instead not found"))
         }
     }
 
+    /// Appends the record, keeping the id it was given when no other record has
+    /// it and giving it one more than the highest id otherwise
     pub fn insert(&self, field: JsonField) -> String {
-        // TODO: Generate Unique ID
-        field.insert("id", JsonField::Int(123));
+        let mut mapped = self.mapped.write().unwrap();
+        let id = match id_of(&field) {
+            Some(id) if !mapped.contains_key(&id) => id,
+            _ => next_id(&mapped)
+        };
+
+        field.insert("id", JsonField::Int(id));
+        if self.versioned {
+            field.insert(VERSION_KEY, JsonField::Int(1));
+        }
         let result = field.stringify();
 
+        self.keep_initial();
         self.json.push(field);
+        mapped.insert(id, result.clone());
+        self.persist();
 
-        if !self.dry_run {
-            fs::write(&self.file, self.json.stringify()).unwrap();            
+        result
+    }
+
+    /// Replaces the record with the object, keeping its id. The precondition
+    /// is given the current serialized record and rejects the write if false
+    pub fn replace(
+        &self,
+        id: i32,
+        field: JsonField,
+        precondition: impl FnOnce(&str) -> bool
+    ) -> Result<String, DbWriteError> {
+        self.write(id, precondition, |record| self.replace_object(record, field, Some(id)))
+    }
+
+    /// Merges the top level fields of the object into the record, fields
+    /// set to `null` are removed and the id can't be changed
+    pub fn merge(
+        &self,
+        id: i32,
+        patch: JsonField,
+        precondition: impl FnOnce(&str) -> bool
+    ) -> Result<String, DbWriteError> {
+        self.write(id, precondition, |record| self.merge_object(record, patch, Some(id)))
+    }
+
+    /// Same as `replace` for the object of a singular resource
+    pub fn replace_singular(
+        &self,
+        field: JsonField,
+        precondition: impl FnOnce(&str) -> bool
+    ) -> Result<String, DbWriteError> {
+        self.write_singular(precondition, |obj| self.replace_object(obj, field, None))
+    }
+
+    /// Same as `merge` for the object of a singular resource
+    pub fn merge_singular(
+        &self,
+        patch: JsonField,
+        precondition: impl FnOnce(&str) -> bool
+    ) -> Result<String, DbWriteError> {
+        self.write_singular(precondition, |obj| self.merge_object(obj, patch, None))
+    }
+
+    fn replace_object(&self, obj: &mut JsonObject, field: JsonField, id: Option<i32>) {
+        let version = next_version(obj);
+
+        let mut replacement = match field {
+            JsonField::Object(replacement) => replacement.into_inner().unwrap(),
+            _ => panic!("Unable to replace with other than JsonField::Object variant")
+        };
+        if let Some(id) = id {
+            replacement.insert("id".to_owned(), JsonField::Int(id));
+        }
+        if self.versioned {
+            replacement.insert(VERSION_KEY.to_owned(), JsonField::Int(version));
         }
+        *obj = replacement;
+    }
 
-        result
+    fn merge_object(&self, obj: &mut JsonObject, patch: JsonField, id: Option<i32>) {
+        let patch = match patch {
+            JsonField::Object(patch) => patch.into_inner().unwrap(),
+            _ => panic!("Unable to merge other than JsonField::Object variant into record")
+        };
+        let version = next_version(obj);
+
+        for (key, value) in patch {
+            if id.is_some() && key == "id" { continue; }
+            if value.is_null() {
+                obj.remove(&key);
+            } else {
+                obj.insert(key, value);
+            }
+        }
+        if self.versioned {
+            obj.insert(VERSION_KEY.to_owned(), JsonField::Int(version));
+        }
+    }
+
+    pub fn delete(
+        &self,
+        id: i32,
+        precondition: impl FnOnce(&str) -> bool
+    ) -> Result<(), DbWriteError> {
+        let mut mapped = self.mapped.write().unwrap();
+        match mapped.get(&id) {
+            Some(record) if !precondition(record) => return Err(DbWriteError::PreconditionFailed),
+            Some(_) => {},
+            None => return Err(DbWriteError::NotFound)
+        }
+        self.keep_initial();
+
+        {
+            let mut arr = self.json.unwrap_as_ref_array().unwrap().write().unwrap();
+            if let Some(index) = position_of(&arr, id) {
+                arr.remove(index);
+            }
+        }
+        mapped.remove(&id);
+        self.persist();
+
+        Ok(())
+    }
+
+    /// Applies the update to the record once the precondition holds,
+    /// returns the serialized record after the update
+    fn write(
+        &self,
+        id: i32,
+        precondition: impl FnOnce(&str) -> bool,
+        update: impl FnOnce(&mut JsonObject)
+    ) -> Result<String, DbWriteError> {
+        let mut mapped = self.mapped.write().unwrap();
+        match mapped.get(&id) {
+            Some(record) if !precondition(record) => return Err(DbWriteError::PreconditionFailed),
+            Some(_) => {},
+            None => return Err(DbWriteError::NotFound)
+        }
+        self.keep_initial();
+
+        let result = {
+            let arr = self.json.unwrap_as_ref_array().unwrap().read().unwrap();
+            let index = position_of(&arr, id).ok_or(DbWriteError::NotFound)?;
+            update(&mut arr[index].unwrap_as_ref_object().unwrap().write().unwrap());
+            arr[index].stringify()
+        };
+        mapped.insert(id, result.clone());
+        self.persist();
+
+        Ok(result)
+    }
+
+    /// Applies the update to the object of the singular resource once the
+    /// precondition holds, returns the serialized object after the update
+    fn write_singular(
+        &self,
+        precondition: impl FnOnce(&str) -> bool,
+        update: impl FnOnce(&mut JsonObject)
+    ) -> Result<String, DbWriteError> {
+        // Held as for records, so that checking the precondition and applying the write is atomic
+        let _mapped = self.mapped.write().unwrap();
+        if !precondition(&self.json.stringify()) {
+            return Err(DbWriteError::PreconditionFailed);
+        }
+        self.keep_initial();
+
+        update(&mut self.json.unwrap_as_ref_object().unwrap().write().unwrap());
+        let result = self.json.stringify();
+        self.persist();
+
+        Ok(result)
+    }
+
+    /// Invalidates the validators of the collection and saves it to the file
+    fn persist(&self) {
+        *self.checksum.lock().unwrap() = None;
+        *self.modified.lock().unwrap() = SystemTime::now();
+
+        if self.dry_run { return; }
+
+        let start_time = Instant::now();
+        let result = match &self.storage {
+            Storage::File(file) => fs::write(file, self.json.stringify()),
+            Storage::Shared(shared, key) => shared.write(key, self.json.stringify())
+        };
+
+        let stats = &self.persist_stats;
+        stats.writes.fetch_add(1, Ordering::Relaxed);
+        stats.duration_micros.fetch_add(start_time.elapsed().as_micros() as u64, Ordering::Relaxed);
+        stats.failing.store(result.is_err(), Ordering::Relaxed);
+        if let Err(err) = result {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Failed to write {}: {err}", self.storage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn connection(content: &str, versioned: bool) -> Connection {
+        let mut connection = Connection::from_json(JsonField::from(content), Storage::File(PathBuf::from("users.json"))).unwrap();
+        connection.dry_run();
+        if versioned { connection.versioned(); }
+        connection
+    }
+
+    fn json(content: &str) -> JsonField {
+        JsonField::from(content)
+    }
+
+    #[test]
+    fn it_replaces_records_keeping_their_id() {
+        let connection = connection(r#"[{"id":1,"name":"Ada","role":"admin"}]"#, false);
+
+        let record = connection.replace(1, json(r#"{"id":7,"name":"Grace"}"#), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":1,"name":"Grace"}"#));
+        assert_eq!(json(&connection.get(1).ok().unwrap()), json(&record));
+        assert_eq!(json(&connection.read()), json(r#"[{"id":1,"name":"Grace"}]"#));
+        assert_eq!(connection.replace(2, json("{}"), |_| true), Err(DbWriteError::NotFound));
+    }
+
+    #[test]
+    fn it_merges_fields_and_removes_those_set_to_null() {
+        let connection = connection(r#"[{"id":1,"name":"Ada","role":"admin"}]"#, false);
+
+        let record = connection.merge(1, json(r#"{"id":7,"role":null,"team":"core"}"#), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":1,"name":"Ada","team":"core"}"#));
+    }
+
+    #[test]
+    fn it_deletes_records() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"},{"id":2,"name":"Linus"}]"#, false);
+
+        assert_eq!(connection.delete(1, |_| true), Ok(()));
+        assert!(connection.get(1).is_err());
+        assert_eq!(json(&connection.read()), json(r#"[{"id":2,"name":"Linus"}]"#));
+        assert_eq!(connection.delete(1, |_| true), Err(DbWriteError::NotFound));
+    }
+
+    #[test]
+    fn it_leaves_records_alone_when_the_precondition_fails() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"}]"#, false);
+
+        assert_eq!(connection.replace(1, json("{}"), |record| !record.contains("Ada")), Err(DbWriteError::PreconditionFailed));
+        assert_eq!(connection.merge(1, json("{}"), |_| false), Err(DbWriteError::PreconditionFailed));
+        assert_eq!(connection.delete(1, |_| false), Err(DbWriteError::PreconditionFailed));
+        assert_eq!(json(&connection.read()), json(r#"[{"id":1,"name":"Ada"}]"#));
+    }
+
+    #[test]
+    fn it_bumps_the_version_on_every_write_when_versioned() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"},{"id":2,"name":"Linus","_version":4}]"#, true);
+
+        let record = connection.merge(1, json(r#"{"role":"admin"}"#), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":1,"name":"Ada","role":"admin","_version":1}"#));
+        let record = connection.replace(1, json(r#"{"name":"Grace","_version":40}"#), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":1,"name":"Grace","_version":2}"#));
+        let record = connection.merge(2, json("{}"), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":2,"name":"Linus","_version":5}"#));
+
+        let unversioned = self::connection(r#"[{"id":1,"name":"Ada","_version":4}]"#, false);
+        let record = unversioned.merge(1, json(r#"{"name":"Grace"}"#), |_| true).unwrap();
+        assert_eq!(json(&record), json(r#"{"id":1,"name":"Grace","_version":4}"#));
+    }
+
+    #[test]
+    fn it_keeps_the_initial_content_from_the_first_write_on() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"}]"#, false);
+        assert!(connection.initial.get().is_none());
+        assert_eq!(json(&connection.initial()), json(r#"[{"id":1,"name":"Ada"}]"#));
+
+        connection.merge(1, json(r#"{"name":"Grace"}"#), |_| true).unwrap();
+        connection.delete(1, |_| true).unwrap();
+        assert_eq!(json(&connection.initial()), json(r#"[{"id":1,"name":"Ada"}]"#));
+    }
+
+    #[test]
+    fn it_restores_content_of_the_same_shape_only() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"}]"#, false);
+
+        assert!(connection.restore(json(r#"{"name":"Ada"}"#)).is_err());
+        assert_eq!(json(&connection.read()), json(r#"[{"id":1,"name":"Ada"}]"#));
+
+        connection.restore(json(r#"[{"id":2,"name":"Linus"}]"#)).unwrap();
+        assert_eq!(json(&connection.get(2).ok().unwrap()), json(r#"{"id":2,"name":"Linus"}"#));
+        assert!(connection.get(1).is_err());
+        assert_eq!(json(&connection.initial()), json(r#"[{"id":1,"name":"Ada"}]"#));
+    }
+
+    #[test]
+    fn it_gives_inserted_records_a_free_id() {
+        let connection = connection(r#"[{"id":1,"name":"Ada"},{"id":5,"name":"Linus"}]"#, false);
+
+        assert_eq!(json(&connection.insert(json(r#"{"name":"Grace"}"#))), json(r#"{"id":6,"name":"Grace"}"#));
+        assert_eq!(json(&connection.insert(json(r#"{"id":5,"name":"Alan"}"#))), json(r#"{"id":7,"name":"Alan"}"#));
+        assert_eq!(json(&connection.insert(json(r#"{"id":3,"name":"Barbara"}"#))), json(r#"{"id":3,"name":"Barbara"}"#));
+        assert_eq!(json(&connection.get(7).ok().unwrap()), json(r#"{"id":7,"name":"Alan"}"#));
+        assert_eq!(connection.delete(3, |_| true), Ok(()));
+
+        let empty = self::connection("[]", false);
+        assert_eq!(json(&empty.insert(json("{}"))), json(r#"{"id":1}"#));
     }
 }
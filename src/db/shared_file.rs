@@ -0,0 +1,115 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Single file holding several collections under its top-level keys, as in
+/// `{ "users": [...], "posts": [...] }`
+#[derive(Debug)]
+pub struct SharedFile {
+    path: PathBuf,
+    /// Serialized value of every top-level key in the order of the document,
+    /// the lock is held for the whole of a write so that collections written
+    /// at once don't drop each other's update
+    values: Mutex<Vec<(String, String)>>
+}
+
+impl SharedFile {
+    pub fn new(path: PathBuf, values: Vec<(String, String)>) -> Self {
+        Self { path, values: Mutex::new(values) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.values.lock().unwrap().iter().any(|(existing, _)| existing == key)
+    }
+
+    /// Replaces the value of the key, or adds the key at the end, and writes
+    /// the whole document through a temporary file so that the document is
+    /// never left half written
+    pub fn write(&self, key: &str, value: String) -> io::Result<()> {
+        let mut values = self.values.lock().unwrap();
+        match values.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value,
+            None => values.push((key.to_owned(), value))
+        }
+        self.save(&values)
+    }
+
+    /// Removes the key and writes the whole document
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        let mut values = self.values.lock().unwrap();
+        values.retain(|(existing, _)| existing != key);
+        self.save(&values)
+    }
+
+    /// Each top-level key goes on a line of its own, so that the document
+    /// stays readable and its changes show up key by key
+    fn save(&self, values: &[(String, String)]) -> io::Result<()> {
+        let document = values
+            .iter()
+            .map(|(key, value)| format!(r#"  "{key}": {value}"#))
+            .collect::<Vec<String>>()
+            .join(",\n");
+
+        let mut temp_name = OsString::from(".");
+        temp_name.push(self.path.file_name().unwrap_or_default());
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+
+        fs::write(&temp_path, format!("{{\n{document}\n}}\n"))?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn values(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn it_writes_keys_in_the_order_of_the_document() {
+        let dir = TestDir::new();
+        let path = dir.path().join("db.json");
+        let shared = SharedFile::new(path.clone(), values(&[("zebra", "[]"), ("ant", "{}"), ("count", "3")]));
+
+        shared.write("ant", r#"{"name":"Ada"}"#.to_owned()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\n  \"zebra\": [],\n  \"ant\": {\"name\":\"Ada\"},\n  \"count\": 3\n}\n"
+        );
+
+        shared.write("bee", "[]".to_owned()).unwrap();
+        shared.remove("zebra").unwrap();
+        assert!(!shared.contains("zebra"));
+        assert!(shared.contains("bee"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\n  \"ant\": {\"name\":\"Ada\"},\n  \"count\": 3,\n  \"bee\": []\n}\n"
+        );
+    }
+
+    #[test]
+    fn it_replaces_the_document_through_a_temporary_file() {
+        let dir = TestDir::new();
+        let path = dir.write("db.json", r#"{ "users": [] }"#);
+        let shared = SharedFile::new(path.clone(), values(&[("users", "[]")]));
+
+        shared.write("users", "[1]".to_owned()).unwrap();
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["db.json"]);
+
+        // The temporary file is in the way, the document is left as it was
+        fs::create_dir(dir.path().join(".db.json.tmp")).unwrap();
+        assert!(shared.write("users", "[2]".to_owned()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\n  \"users\": [1]\n}\n");
+    }
+}
@@ -52,7 +52,7 @@ impl PartialEq for JsonField {
                 let read_a = a.read().unwrap();
                 let read_b = b.read().unwrap();
                 
-                let keys_a: HashSet<&String> = read_a.iter().map(|(k, _)| k).collect();
+                let keys_a: HashSet<&String> = read_a.keys().collect();
                 let mut keys_b: HashSet<&String> = HashSet::new();
                 for (key_b, _) in read_b.iter() {
                     if !keys_a.contains(key_b) { return false; }
@@ -115,21 +115,21 @@ impl JsonField {
 
     pub fn unwrap_as_ref_array(&self) -> Result<&RwLock<JsonArray>, ParseJsonError> {
         match self {
-            JsonField::Array(arr_lock) => return Ok(arr_lock),
+            JsonField::Array(arr_lock) => Ok(arr_lock),
             _ => Err(ParseJsonError(format!("Expect to unwrap as JsonField::Array type, instead got: {:?}", self.field_type())))
         }
     }
 
     pub fn unwrap_as_ref_object(&self) -> Result<&RwLock<JsonObject>, ParseJsonError> {
         match self {
-            JsonField::Object(obj_lock) => return Ok(obj_lock),
+            JsonField::Object(obj_lock) => Ok(obj_lock),
             _ => Err(ParseJsonError(format!("Expect to unwrap as JsonField::Object type, instead got: {:?}", self.field_type())))
         }
     }
 
     pub fn unwrap_as_ref_int(&self) -> Result<&i32, ParseJsonError> {
         match self {
-            JsonField::Int(value) => return Ok(value),
+            JsonField::Int(value) => Ok(value),
             _ => Err(ParseJsonError(format!("Expect to unwrap as JsonField::Int type, instead got: {:?}", self.field_type())))
         }
     }
@@ -19,7 +19,7 @@ pub fn read_json(path: &PathBuf) -> Result<JsonField, ParseJsonError> {
     Ok(data)
 }
 
-fn peek_next_non_white_space_char(start_index: usize, chars: &Vec<char>) -> Option<(char, usize)> {
+fn peek_next_non_white_space_char(start_index: usize, chars: &[char]) -> Option<(char, usize)> {
     let mut index = start_index + 1;
 
     while index < chars.len() {
@@ -180,6 +180,39 @@ pub fn parse_json(content: &str, starting_index: usize) -> ParseJsonResult {
     Err(ParseJsonError(r#"Unexpected end of JSON, couldn't parse correctly, perhaps missing closing braces "}" or bracket "]""#.to_owned()))
 }
 
+/// Keys of the object at the root of the JSON in the order they appear,
+/// which `JsonObject` doesn't keep. Empty when the root isn't an object
+pub fn object_keys(content: &str) -> Result<Vec<String>, ParseJsonError> {
+    let chars: Vec<char> = content.chars().collect();
+    if chars.iter().find(|c| !c.is_whitespace()) != Some(&'{') {
+        return Ok(Vec::new());
+    }
+
+    let mut keys = Vec::new();
+    let mut depth = 0;
+    let mut is_key = true;
+    let mut cur_index = 0;
+    while cur_index < chars.len() {
+        match chars[cur_index] {
+            '"' => {
+                // Parsed the same way as by `parse_json` so that the keys match
+                let string = string_parser::parse(&mut cur_index, &chars)?;
+                if depth == 1 && is_key {
+                    keys.push(string);
+                    is_key = false;
+                }
+            },
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            ',' if depth == 1 => is_key = true,
+            _ => {}
+        }
+        cur_index += 1;
+    }
+
+    Ok(keys)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -361,4 +394,25 @@ mod test {
             Err(ParseJsonError(r#"JSON object's key must be double quoted string"#.to_owned()))
         );
     }
+
+    #[test]
+    fn it_lists_object_keys_in_order() {
+        let content = r#"{
+            "zebra": { "ant": 1, "bee": [2, "cat"] },
+            "ant": "zebra",
+            "\/weird": [{ "z": "y" }, "x"],
+            "bee": null
+        }"#;
+
+        let keys = object_keys(content).unwrap();
+        assert_eq!(keys, ["zebra", "ant", "\\/weird", "bee"]);
+
+        let (json, _) = parse_json(content, 0).unwrap();
+        let obj = json.unwrap_as_ref_object().unwrap().read().unwrap();
+        assert!(keys.iter().all(|key| obj.contains_key(key)));
+
+        assert_eq!(object_keys(r#"["a", "b"]"#).unwrap(), Vec::<String>::new());
+        assert_eq!(object_keys("{}").unwrap(), Vec::<String>::new());
+    }
 }
+
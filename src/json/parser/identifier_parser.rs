@@ -1,6 +1,6 @@
 use crate::json::field::{JsonField, ParseJsonError};
 
-pub fn parse(cur_index: &mut usize, chars: &Vec<char>) -> Result<JsonField, ParseJsonError> {
+pub fn parse(cur_index: &mut usize, chars: &[char]) -> Result<JsonField, ParseJsonError> {
     let mut ident_segment = String::new();
     let mut cur_char = chars[*cur_index];
     let len = chars.len();
@@ -1,6 +1,6 @@
 use crate::json::field::{JsonField, ParseJsonError};
 
-pub fn parse(cur_index: &mut usize, chars: &Vec<char>) -> Result<JsonField, ParseJsonError> {
+pub fn parse(cur_index: &mut usize, chars: &[char]) -> Result<JsonField, ParseJsonError> {
     let mut num_str = String::new();
     let mut is_float = false;
     let len = chars.len();
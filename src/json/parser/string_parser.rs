@@ -1,6 +1,6 @@
 use crate::json::field::ParseJsonError;
 
-pub fn parse(cur_index: &mut usize, chars: &Vec<char>) -> Result<String, ParseJsonError> {
+pub fn parse(cur_index: &mut usize, chars: &[char]) -> Result<String, ParseJsonError> {
     let mut str_segment = String::new();
     let len = chars.len();
 
@@ -1,52 +1,46 @@
+use std::io::{self, Write};
+
 use super::field::JsonField;
 
 impl JsonField {
     pub fn stringify(&self) -> String {
+        let mut buffer: Vec<u8> = Vec::new();
+        self.write_json(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Serializes the field straight into the writer, so large collections
+    /// can be streamed without building the whole JSON string in memory
+    pub fn write_json<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
         match self {
-            Self::Null => "null".to_owned(),
-            Self::Boolean(true) => "true".to_owned(),
-            Self::Boolean(false) => "false".to_owned(),
-            Self::Int(value) => format!("{}", value),
-            Self::Float(value) => format!("{}", value),
-            Self::String(value) => format!(r#""{value}""#),
+            Self::Null => writer.write_all(b"null"),
+            Self::Boolean(true) => writer.write_all(b"true"),
+            Self::Boolean(false) => writer.write_all(b"false"),
+            Self::Int(value) => write!(writer, "{}", value),
+            Self::Float(value) => write!(writer, "{}", value),
+            Self::String(value) => write!(writer, r#""{value}""#),
             Self::Array(rw_lock) => {
                 let arr = rw_lock.read().unwrap();
-                let mut result = "[".to_owned();
+                writer.write_all(b"[")?;
 
-                let len = arr.len();
-                for i in 0..(len - 1) {
-                    let field = &arr[i];
-                    result.push_str(&field.stringify());
-                    result.push(',');
+                for (index, field) in arr.iter().enumerate() {
+                    if index > 0 { writer.write_all(b",")?; }
+                    field.write_json(writer)?;
                 }
 
-                result.push_str(&arr[len - 1].stringify());
-                result.push(']');
-                result
+                writer.write_all(b"]")
             },
             Self::Object(rw_lock) => {
                 let obj = rw_lock.read().unwrap();
-                let mut result = "{".to_owned();
-
-                let pairs: Vec<(&String, &JsonField)> = obj.iter().collect();
-                let len = pairs.len();
+                writer.write_all(b"{")?;
 
-                for i in 0..(len - 1) {
-                    let (key, field) = pairs[i];
-                    result.push('"');
-                    result.push_str(key);
-                    result.push_str("\":");
-                    result.push_str(&field.stringify());
-                    result.push(',');
+                for (index, (key, field)) in obj.iter().enumerate() {
+                    if index > 0 { writer.write_all(b",")?; }
+                    write!(writer, r#""{key}":"#)?;
+                    field.write_json(writer)?;
                 }
 
-                let (key, field) = pairs[len - 1];
-                result.push('"');
-                result.push_str(key);
-                result.push_str("\":");
-                result.push_str(&field.stringify());
-                result.push('}');
-                result
+                writer.write_all(b"}")
             }
         }
     }
@@ -1,3 +1,5 @@
 pub mod db;
 pub mod json;
 pub mod server;
+#[cfg(test)]
+mod test_dir;
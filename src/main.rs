@@ -12,7 +12,12 @@ fn main() {
         process::exit(1)
     });
 
+    let mut server = Server::from(config).unwrap_or_else(|err| {
+        eprintln!("Starting server error: {err}");
+        process::exit(1)
+    });
+
     // Clear up terminal and then position cursor at row 1 col 1
     println!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-    Server::from(config).start();
+    server.start();
 }
@@ -1,198 +1,1129 @@
+pub mod access_log;
+pub mod chaos;
+pub mod compression;
 pub mod config;
+pub mod cors;
+pub mod delay;
+pub mod headers;
+pub mod http_date;
+pub mod metrics;
 pub mod request;
 pub mod response;
+pub mod rewrite;
+pub mod static_files;
 pub mod status_code;
+pub mod stream;
+mod chunked;
+mod conditional;
+#[cfg(target_os = "linux")]
+mod event_loop;
 mod thread_pool;
 mod request_handler;
 
-use std::collections::HashSet;
-use std::ffi::{OsString, OsStr};
 use std::path::{Path, PathBuf};
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, prelude::*, ErrorKind};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
 use std::fs;
+use std::panic::{self, UnwindSafe};
 use std::process;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::thread;
 use std::time::Instant;
 
-use crate::db::JsonDb;
+use crate::db::{DbCollectionError, JsonDb, RESERVED_PREFIX};
 
-use self::response::Response;
-use self::request::{Request, RequestMethod};
+use self::access_log::{AccessLog, Entry};
+use self::chaos::{Chaos, CutOff, Fault};
+use self::compression::CompressionConfig;
+use self::metrics::Metrics;
+use self::cors::CorsConfig;
+use self::delay::{DelayConfig, Timer};
+use self::response::{Response, ResponseBuilder};
+use self::rewrite::Rewriter;
+use self::static_files::StaticFiles;
+use self::request::{Recorder, Request, RequestInitializationError, RequestLimits, RequestMethod, RequestReader};
 use self::status_code::StatusCode;
+use self::stream::{DeadlineReader, Listener, Stream};
 use self::thread_pool::ThreadPool;
-use self::config::Config;
+use self::config::{Config, Engine};
 
 pub struct Server {
-    port: usize,
-    listener: TcpListener,
+    listener: Listener,
     pool_capacity: Option<usize>,
+    engine: Engine,
     verbose: bool,
     dry_run: bool,
-    jsondb_dir: PathBuf,
-    jsondb: Option<Arc<JsonDb>>,
-    main_entrypoints: Option<Arc<HashSet<OsString>>>
+    read_only: bool,
+    versioned: bool,
+    require_if_match: bool,
+    auto_create: bool,
+    cors: Arc<CorsConfig>,
+    limits: RequestLimits,
+    compression: CompressionConfig,
+    access_log: Arc<AccessLog>,
+    metrics: Arc<Metrics>,
+    rewriter: Arc<Rewriter>,
+    static_files: Option<Arc<StaticFiles>>,
+    delay: Arc<DelayConfig>,
+    chaos: Option<Arc<Chaos>>,
+    jsondb_path: PathBuf
 }
 
-const DEFAULT_PORT: usize = 5000;
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 5000;
 const DEFAULT_POOL_CAPACITY: usize = 4;
 
+/// State shared by every connection, regardless of the engine serving it
+struct Context {
+    /// Set once the collections are loaded, requests are answered meanwhile
+    /// so that `/__ready` can report the loading
+    jsondb: OnceLock<Arc<JsonDb>>,
+    cors: Arc<CorsConfig>,
+    limits: RequestLimits,
+    compression: CompressionConfig,
+    require_if_match: bool,
+    auto_create: bool,
+    read_only: bool,
+    access_log: Arc<AccessLog>,
+    metrics: Arc<Metrics>,
+    rewriter: Arc<Rewriter>,
+    static_files: Option<Arc<StaticFiles>>,
+    delay: Arc<DelayConfig>,
+    chaos: Option<Arc<Chaos>>,
+    /// Holds the delayed responses back, only set when serving with the thread pool
+    timer: OnceLock<Timer>
+}
+
 impl Server {
-    pub fn from(config: Config) -> Self {
-        let port = match config.port {
-            Some(port) => port,
-            None => DEFAULT_PORT
+    pub fn from(config: Config) -> Result<Self, String> {
+        let listener = match &config.socket {
+            Some(socket) => {
+                if config.host.is_some() || config.port.is_some() {
+                    return Err(r#"The option "--socket" can't be combined with "--host" or "--port""#.to_owned());
+                }
+                Self::bind_unix(socket)?
+            },
+            None => {
+                let host = config.host.as_deref().unwrap_or(DEFAULT_HOST);
+                let port = config.port.unwrap_or(DEFAULT_PORT);
+                Listener::Tcp(Self::bind(host, port)?)
+            }
         };
 
-        let mut server = Self::new(port, config.jsondb_dir);
+        let mut server = Self::new(listener, config.jsondb_path);
         server.pool_capacity = config.pool_capacity;
+        server.engine = config.engine;
         server.verbose = config.verbose;
         server.dry_run = config.dry_run;
+        server.read_only = config.read_only;
+        server.versioned = config.versioned;
+        server.require_if_match = config.require_if_match;
+        server.auto_create = config.auto_create;
+        let mut cors = config.cors;
+        if config.read_only {
+            cors.allow_reads_only();
+        }
+        server.cors = Arc::new(cors);
+        server.limits = config.limits;
+        server.compression = config.compression;
+        server.delay = Arc::new(config.delay);
+        if let Some(chaos) = &config.chaos {
+            server.chaos = Some(Arc::new(Chaos::load(chaos, config.chaos_seed).map_err(|err| {
+                format!("Unable to load chaos {chaos:?}: {err}")
+            })?));
+        }
+        server.access_log = Arc::new(AccessLog::open(&config.access_log, config.verbose).map_err(|err| {
+            format!("Unable to open access log {:?}: {err}", config.access_log.file.as_ref().unwrap())
+        })?);
+        if let Some(routes) = &config.routes {
+            server.rewriter = Arc::new(Rewriter::load(routes).map_err(|err| {
+                format!("Unable to load routes {routes:?}: {err}")
+            })?);
+        }
+        if let Some(static_dir) = &config.static_dir {
+            server.static_files = Some(Arc::new(StaticFiles::new(static_dir).map_err(|err| {
+                format!("Unable to serve static files from {static_dir:?}: {err}")
+            })?));
+        }
 
-        return server;
+        Ok(server)
     }
 
     fn new(
-        port: usize,
-        jsondb_dir: PathBuf
+        listener: Listener,
+        jsondb_path: PathBuf
     ) -> Self {
-        let host = format!("localhost:{port}");
-        let listener = TcpListener::bind(host).unwrap();
-
         Self {
-            port,
             listener,
             pool_capacity: None,
+            engine: Engine::ThreadPool,
             verbose: false,
             dry_run: false,
-            jsondb_dir,
-            jsondb: None,
-            main_entrypoints: None
+            read_only: false,
+            versioned: false,
+            require_if_match: false,
+            auto_create: false,
+            cors: Arc::new(CorsConfig::default()),
+            limits: RequestLimits::default(),
+            compression: CompressionConfig::default(),
+            // Logging to stdout can't fail to open
+            access_log: Arc::new(AccessLog::open(&Default::default(), false).unwrap()),
+            metrics: Arc::new(Metrics::default()),
+            rewriter: Arc::new(Rewriter::default()),
+            static_files: None,
+            delay: Arc::new(DelayConfig::default()),
+            chaos: None,
+            jsondb_path
         }
     }
 
-    pub fn start(&mut self) {
-        self.jsondb = Some(Arc::new(JsonDb::new(&self.jsondb_dir, self.dry_run)));
+    /// Binds the listener, host can be a hostname, an IPv4 or an IPv6 address
+    /// (with or without brackets) and port 0 picks any free port
+    fn bind(host: &str, port: u16) -> Result<TcpListener, String> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addrs: Vec<SocketAddr> = (host, port)
+            .to_socket_addrs()
+            .map_err(|err| format!(r#"Unable to resolve host "{host}": {err}"#))?
+            .collect();
+
+        TcpListener::bind(&addrs[..]).map_err(|err| match err.kind() {
+            ErrorKind::AddrInUse => format!(
+                "Port {port} is already in use on {host}, stop the other process or choose another --port"
+            ),
+            ErrorKind::AddrNotAvailable => format!(
+                r#"Address "{host}" is not assigned to any interface of this machine, use "0.0.0.0" or "::" to listen on all interfaces"#
+            ),
+            ErrorKind::PermissionDenied => format!(
+                "Permission denied to listen on port {port}, ports below 1024 usually require elevated privileges"
+            ),
+            _ => format!("Unable to listen on {host}:{port}: {err}")
+        })
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> Result<Listener, String> {
+        Listener::bind_unix(path)
+    }
 
-        let mut main_entrypoints: HashSet<OsString> = HashSet::new();
-        let files = fs::read_dir(self.jsondb_dir.clone()).unwrap_or_else(|err| {
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &Path) -> Result<Listener, String> {
+        Err(r#"The option "--socket" is only supported on Unix platforms"#.to_owned())
+    }
+
+    /// Address the server is actually bound to, reports the assigned port when started with port 0,
+    /// `None` when listening on Unix domain socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn start(&mut self) {
+        let readable = match self.jsondb_path.is_file() {
+            true => fs::File::open(&self.jsondb_path).map(|_| ()),
+            false => fs::read_dir(&self.jsondb_path).map(|_| ())
+        };
+        if let Err(err) = readable {
             eprintln!(
-                r#"Unable to read directory "{:?}": {}"#,
-                self.jsondb_dir,
+                r#"Unable to read "{:?}": {}"#,
+                self.jsondb_path,
                 err
             );
             process::exit(1);
+        }
+
+        let context = Arc::new(Context {
+            jsondb: OnceLock::new(),
+            cors: Arc::clone(&self.cors),
+            limits: self.limits.clone(),
+            compression: self.compression.clone(),
+            require_if_match: self.require_if_match,
+            auto_create: self.auto_create,
+            read_only: self.read_only,
+            access_log: Arc::clone(&self.access_log),
+            metrics: Arc::clone(&self.metrics),
+            rewriter: Arc::clone(&self.rewriter),
+            static_files: self.static_files.clone(),
+            delay: Arc::clone(&self.delay),
+            chaos: self.chaos.clone(),
+            timer: OnceLock::new()
         });
 
-        for file in files {
-            let file = file.unwrap();
-            let file_name_os_str = file.file_name();
-            let file_name = file_name_os_str.to_str().unwrap();
-            if !file_name.ends_with(".json") || file_name == "schema.json" { continue; }
+        let loader = Arc::clone(&context);
+        let (jsondb_path, read_only, versioned) = (self.jsondb_path.clone(), self.read_only, self.versioned);
+        // Writes are rejected anyway, the files are left alone should any get through
+        let dry_run = self.dry_run || self.read_only;
+        thread::spawn(move || {
+            let jsondb = Self::load_guarded(&jsondb_path, dry_run, versioned, || {
+                let jsondb = JsonDb::new(&jsondb_path, dry_run, versioned);
+                Self::print_routes(&jsondb, read_only);
+                jsondb
+            });
+            let _ = loader.jsondb.set(Arc::new(jsondb));
+        });
 
-            let file_stem = Path::new(file_name).file_stem().unwrap().to_owned();
+        if self.read_only {
+            println!("Read-only mode, POST, PUT, PATCH and DELETE requests are rejected");
+        }
+        if self.cors.is_enabled() {
+            println!("CORS enabled for origins: {}", self.cors.allowed_origins.join(", "));
+        }
+        if let Some(static_files) = &self.static_files {
+            println!("Serving static files from {}", static_files.root().display());
+        }
+        for (pattern, target) in self.rewriter.rules() {
+            println!("Rewriting {pattern} -> {target}");
+        }
+        if let Some(delay) = &self.delay.global {
+            println!("Delaying responses by {delay}");
+        }
+        for (pattern, delay) in &self.delay.routes {
+            println!("Delaying {} by {delay}", pattern.as_str());
+        }
+        if let Some(chaos) = &self.chaos {
+            println!("Chaos enabled with seed {}, rerun with --chaos-seed={} to reproduce", chaos.seed(), chaos.seed());
+            for rule in chaos.rules() {
+                println!("    {rule}");
+            }
+        }
 
-            main_entrypoints.insert(file_stem.clone());
+        println!("Listening on {}...", self.listener.describe());
+        match self.engine {
+            Engine::ThreadPool => self.serve_with_thread_pool(context),
+            Engine::EventLoop => self.serve_with_event_loop(context)
         }
+    }
+
+    /// Runs the loading, a panic is recorded as a load failure so that
+    /// `/__ready` reports it instead of the loading never ending
+    fn load_guarded<F>(path: &Path, dry_run: bool, versioned: bool, load: F) -> JsonDb
+    where F: FnOnce() -> JsonDb + UnwindSafe
+    {
+        panic::catch_unwind(load).unwrap_or_else(|payload| {
+            let reason = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "Loading panicked".to_owned())
+            };
+            JsonDb::failed(path, reason, dry_run, versioned)
+        })
+    }
+
+    fn print_routes(jsondb: &JsonDb, read_only: bool) {
+        for (entrypoint, connection) in jsondb.collections() {
+            let entrypoint = entrypoint.to_string_lossy();
+            if read_only {
+                println!("    GET :: /{}", entrypoint);
+                if !connection.is_singular() {
+                    println!("    GET :: /{}/:id", entrypoint);
+                }
+                println!();
+                continue;
+            }
+
+            if connection.is_singular() {
+                println!("    GET :: /{}", entrypoint);
+                println!("    PUT :: /{}", entrypoint);
+                println!("  PATCH :: /{}", entrypoint);
+                println!();
+                continue;
+            }
 
-        for entrypoint in main_entrypoints.iter() {
-            let entrypoint = entrypoint.to_str().unwrap();
             println!("    GET :: /{}", entrypoint);
             println!("    GET :: /{}/:id", entrypoint);
             println!("   POST :: /{}", entrypoint);
-            // println!("    PUT :: /{}/:id", entrypoint);
-            // println!("  PATCH :: /{}/:id", entrypoint);
-            // println!(" DELETE :: /{}/:id", entrypoint);
-            println!("");
+            println!("    PUT :: /{}/:id", entrypoint);
+            println!("  PATCH :: /{}/:id", entrypoint);
+            println!(" DELETE :: /{}/:id", entrypoint);
+            println!();
         }
+    }
+
+    fn serve_with_thread_pool(&self, context: Arc<Context>) {
+        let pool_capacity = self.pool_capacity.unwrap_or(DEFAULT_POOL_CAPACITY);
+        let pool = Arc::new(ThreadPool::new(pool_capacity));
+        context.metrics.set_pool(pool.stats());
+
+        let timer_pool = Arc::clone(&pool);
+        let _ = context.timer.set(Timer::new(move |job| timer_pool.execute(job)));
+
+        loop {
+            let stream = match self.listener.accept() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Failed to accept connection: {err}");
+                    continue;
+                }
+            };
+            let context = Arc::clone(&context);
+
+            pool.execute(move || Self::handle_connection(stream, context));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn serve_with_event_loop(&self, context: Arc<Context>) {
+        let threads = self.pool_capacity.unwrap_or(DEFAULT_POOL_CAPACITY);
+        let pool = ThreadPool::new(threads);
+        context.metrics.set_pool(pool.stats());
+        let limits = context.limits.clone();
+        let handler: Arc<event_loop::Handler> = Arc::new(move |message: &mut dyn RequestReader, start_time: Instant, remote_addr: &str, writer: &mut Vec<u8>| {
+            let (response, entry) = Self::respond(message, start_time, remote_addr, &context);
+            let delay = entry.timings.delay;
+            Self::send(response, entry, writer, &context).map(|_| delay)
+        });
+
+        if let Err(err) = event_loop::run(&self.listener, threads, &limits, &pool, &handler) {
+            eprintln!("Event loop stopped: {err}");
+            process::exit(1);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn serve_with_event_loop(&self, _context: Arc<Context>) {
+        unreachable!("Config only accepts the event loop engine on Linux")
+    }
+
+    fn handle_connection(mut stream: Stream, context: Arc<Context>) {
+        let now = Instant::now();
+        let limits = &context.limits;
+        let remote_addr = stream.remote_addr();
+
+        let mut reader = DeadlineReader::new(&mut stream, limits.header_timeout, limits.body_timeout);
+        let (response, entry) = Self::respond(&mut reader, now, &remote_addr, &context);
+
+        let delay = entry.timings.delay;
+        let timer = match context.timer.get() {
+            Some(timer) if !delay.is_zero() => timer,
+            _ => {
+                let sent = stream
+                    .set_write_timeout(Some(limits.write_timeout))
+                    .and_then(|_| Self::send(response, entry, &mut stream, &context));
+                if let Err(err) = sent {
+                    eprintln!("Failed to send the response to {remote_addr}: {err}");
+                }
+                return;
+            }
+        };
+
+        // Buffered so that the worker is free to take other connections meanwhile
+        let mut buffer = Vec::new();
+        let _ = Self::send(response, entry, &mut buffer, &context);
+        let write_timeout = limits.write_timeout;
+        timer.schedule(Instant::now() + delay, move || {
+            let sent = stream
+                .set_write_timeout(Some(write_timeout))
+                .and_then(|_| stream.write_all(&buffer));
+            if let Err(err) = sent {
+                eprintln!("Failed to send the delayed response to {remote_addr}: {err}");
+            }
+        });
+    }
+
+    /// Parses the request out of the message and produces its response,
+    /// this is the part of the pipeline both engines have in common
+    fn respond(
+        message: &mut dyn RequestReader,
+        start_time: Instant,
+        remote_addr: &str,
+        context: &Context
+    ) -> (Response, Entry) {
+        let mut entry = Entry::new(remote_addr, start_time);
+
+        let mut recorder = Recorder::new(message, context.limits.max_header_size);
+        let request = Request::new(&mut recorder, start_time, &context.limits);
+        let parsed_time = Instant::now();
+        entry.timings.parse = parsed_time - start_time;
+
+        let mut request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                entry.error = Some(err.to_string());
+                entry.raw_head = Some(recorder.head());
+                return (Self::reject(err), entry);
+            }
+        };
 
-        self.main_entrypoints = Some(Arc::new(main_entrypoints));
+        let rewritten = request.url
+            .to_str()
+            .and_then(|path| context.rewriter.rewrite(path, request.query.as_deref()));
+        if let Some(target) = rewritten {
+            request.set_target(&target);
+        }
 
-        let pool_capacity = if self.pool_capacity.is_some() {
-            self.pool_capacity.unwrap()
+        let mut response = if context.cors.is_preflight(&request) {
+            context.cors.preflight(&request)
         } else {
-            DEFAULT_POOL_CAPACITY
+            entry.fault = context.chaos.as_ref().and_then(|chaos| chaos.pick(&request));
+
+            // Collections take precedence over static files of the same path
+            let mut response = entry.fault
+                .and_then(|fault| fault.failure(&request))
+                .or_else(|| Self::route(&request, context))
+                // Until loaded, paths of the app can't be told from those of collections
+                .or_else(|| context.static_files.as_ref()?.serve(&request, context.jsondb.get().is_some()))
+                .unwrap_or_else(|| match context.jsondb.get() {
+                    Some(_) => ResponseBuilder::build_404(request.version.clone()),
+                    None => request_handler::loading(&request)
+                });
+            if let Some(fault) = entry.fault {
+                fault.apply(&mut response);
+            }
+            if context.read_only {
+                request_handler::allow_reads_only(&mut response);
+            }
+            context.cors.apply(&request, &mut response);
+            context.compression.apply(&request, &mut response);
+            response
         };
 
-        let pool = ThreadPool::new(pool_capacity);
-        let main_entrypoints = self.main_entrypoints.as_ref().unwrap();
+        if request.method == RequestMethod::HEAD {
+            response.omit_body();
+        }
+
+        entry.timings.handle = parsed_time.elapsed();
+        entry.timings.delay = context.delay.delay_for(&request);
+        entry.route = Self::route_pattern(&request, context);
+        entry.request = Some(request);
+        (response, entry)
+    }
 
-        println!("Listening on localhost:{}...", self.port);
-        for stream in self.listener.incoming() {
-            let stream = stream.unwrap();
-            let main_entrypoints = Arc::clone(main_entrypoints);
-            let jsondb = Arc::clone(self.jsondb.as_ref().unwrap());
+    /// Writes the response and records it in the access log, even when the
+    /// client went away before the whole of it was written
+    fn send<W: Write + ?Sized>(response: Response, mut entry: Entry, writer: &mut W, context: &Context) -> io::Result<()> {
+        let write_time = Instant::now();
+        entry.set_response(&response, context.access_log.is_verbose());
+        let sent = match entry.fault {
+            Some(Fault::Drop) => {
+                let limit = response.format_head().len() + response.content_length().unwrap_or(0) / 2;
+                response.send(&mut CutOff::new(writer, limit))
+            },
+            _ => response.send(writer)
+        };
 
-            pool.execute(move || Self::handle_connection(
-                stream,
-                main_entrypoints,
-                jsondb
-            ));
-        }    
+        entry.timings.write = write_time.elapsed();
+        entry.bytes = *sent.as_ref().unwrap_or(&0);
+        context.access_log.log(&entry);
+        context.metrics.observe(&entry);
+        sent.map(|_| ())
     }
 
-    fn handle_connection(
-        mut stream: TcpStream,
-        main_entrypoints: Arc<HashSet<OsString>>,
-        jsondb: Arc<JsonDb>
-    ) {
-        let now = Instant::now();
-        let request = Request::new(&mut stream, now);
-        if request.is_err() {
-            return Response::not_found("HTTP/1.1".to_owned(), now, stream)
-        }
-        let request = request.unwrap();
-        let version = request.version.clone();
-
-        let path_segments: Vec<&OsStr> = request.url.iter().collect();
-        if path_segments.len() < 2 {
-            return Response::not_found(version, now, stream);
-        }
-
-        let entrypoint = path_segments[1].to_owned();
-        if !main_entrypoints.contains(&entrypoint) {
-            return Response::not_found(version, now, stream);
-        }
-
-        let connection = Arc::clone(&jsondb.get_entry(entrypoint));
-
-        /* Get all or insert new record */
-        if path_segments.len() == 2 {
-            match request.method {
-                RequestMethod::GET => request_handler::get(
-                    request,
-                    stream,
-                    connection
-                ),
-                RequestMethod::POST => request_handler::post(
-                    request,
-                    stream,
-                    connection
-                ),
-                _ => Response::not_found(version, now, stream)
+    /// Response to the request which couldn't be read
+    fn reject(err: RequestInitializationError) -> Response {
+        let version = "HTTP/1.1".to_owned();
+        match &err {
+            RequestInitializationError::Malformed(_) => {
+                ResponseBuilder::build_error(version, StatusCode::BadRequest, "400 Bad Request")
+            },
+            RequestInitializationError::Timeout => {
+                ResponseBuilder::build_error(version, StatusCode::RequestTimeout, "408 Request Timeout")
+            },
+            RequestInitializationError::HeadersTooLarge => {
+                ResponseBuilder::build_error(version, StatusCode::PayloadTooLarge, "413 Request Headers Too Large")
+            },
+            RequestInitializationError::BodyTooLarge => {
+                ResponseBuilder::build_error(version, StatusCode::PayloadTooLarge, "413 Payload Too Large")
             }
-            return;
         }
+    }
+
+    /// Route pattern the request matches, labels metrics without creating
+    /// one series for each record
+    fn route_pattern(request: &Request, context: &Context) -> Option<String> {
+        let path_segments = Self::path_segments(request)?;
 
-        /* Get specific record */
-        if path_segments.len() == 3 {
-            let id: i32 = match path_segments[2].to_os_string().into_string() {
-                Ok(string) => match string.parse() {
-                    Ok(value) => value,
-                    Err(_) => return Response::not_found(version, now, stream)
-                },
-                Err(_) => return Response::not_found(version, now, stream)
+        match path_segments.as_slice() {
+            [endpoint @ ("__metrics" | "__health" | "__ready" | "__collections" | "__reset" | "__snapshots")] => {
+                Some(format!("/{endpoint}"))
+            },
+            ["__collections", _, ..] => Some("/__collections/:name".to_owned()),
+            ["__snapshots", _] => Some("/__snapshots/:name".to_owned()),
+            segments => match context.jsondb.get()?.resolve(segments)? {
+                (name, _, None) => Some(format!("/{name}")),
+                (name, connection, Some(_)) if !connection.is_singular() => Some(format!("/{name}/:id")),
+                _ => None
+            }
+        }
+    }
+
+    /// Segments of the URL path, `None` when they aren't valid UTF-8
+    fn path_segments(request: &Request) -> Option<Vec<&str>> {
+        request.url
+            .iter()
+            .skip(1)
+            .map(|segment| segment.to_str())
+            .collect()
+    }
+
+    /// Dispatches the request to its handler, `None` stands for no matching route
+    fn route(request: &Request, context: &Context) -> Option<Response> {
+        let path_segments = Self::path_segments(request)?;
+
+        match path_segments.as_slice() {
+            ["__health"] => return Some(request_handler::health(request)),
+            ["__ready"] => return Some(request_handler::ready(request, context.jsondb.get().map(Arc::as_ref))),
+            _ => {}
+        }
+
+        if context.read_only && request.method.is_write() {
+            return Some(request_handler::read_only(request));
+        }
+
+        // Other paths may still be static files, which don't wait for the collections
+        let jsondb = match context.jsondb.get() {
+            Some(jsondb) => jsondb,
+            None if path_segments.first().is_some_and(|segment| segment.starts_with(RESERVED_PREFIX)) => {
+                return Some(request_handler::loading(request));
+            },
+            None => return None
+        };
+
+        match path_segments.as_slice() {
+            ["__metrics"] => return Some(request_handler::metrics(request, &context.metrics, jsondb)),
+            ["__reset"] => return Some(request_handler::reset(request, jsondb)),
+            ["__snapshots"] => return Some(request_handler::snapshots(request, jsondb)),
+            ["__snapshots", name] => return request_handler::drop_snapshot(request, jsondb, name),
+            ["__collections"] => return Some(request_handler::create_collection(request, jsondb)),
+            ["__collections", name @ ..] => return request_handler::drop_collection(request, jsondb, &name.join("/")),
+            _ => {}
+        }
+
+        // Released once routed, resets, snapshots and removals wait for it
+        let _access = jsondb.access();
+
+        let (connection, id) = match jsondb.resolve(&path_segments) {
+            Some((_, connection, id)) => (connection, id),
+            None if context.auto_create && request.method == RequestMethod::POST && !path_segments.is_empty() => {
+                match jsondb.create(&path_segments.join("/")) {
+                    Ok(connection) => (connection, None),
+                    // Created by another request since it was resolved
+                    Err(DbCollectionError::AlreadyExists) => match jsondb.resolve(&path_segments) {
+                        Some((_, connection, id)) => (connection, id),
+                        None => return Some(request_handler::collection_error(request, DbCollectionError::AlreadyExists))
+                    },
+                    Err(err) => return Some(request_handler::collection_error(request, err))
+                }
+            },
+            None => return None
+        };
+
+        /* Get, replace or merge singular resource */
+        if connection.is_singular() {
+            if id.is_some() {
+                return None;
+            }
+
+            let allowed_methods = [
+                RequestMethod::GET,
+                RequestMethod::HEAD,
+                RequestMethod::PUT,
+                RequestMethod::PATCH,
+                RequestMethod::OPTIONS
+            ];
+            let require_if_match = context.require_if_match;
+
+            return match request.method {
+                RequestMethod::GET | RequestMethod::HEAD => Some(request_handler::get(request, connection)),
+                RequestMethod::PUT => request_handler::put(request, connection, None, require_if_match),
+                RequestMethod::PATCH => request_handler::patch(request, connection, None, require_if_match),
+                RequestMethod::OPTIONS => Some(request_handler::options(request, &allowed_methods)),
+                _ => Some(request_handler::method_not_allowed(request, &allowed_methods))
             };
+        }
+
+        match id {
+            /* Get all or insert new record */
+            None => {
+                let allowed_methods = [
+                    RequestMethod::GET,
+                    RequestMethod::HEAD,
+                    RequestMethod::POST,
+                    RequestMethod::OPTIONS
+                ];
+
+                Some(match request.method {
+                    RequestMethod::GET | RequestMethod::HEAD => request_handler::get(request, connection),
+                    RequestMethod::POST => request_handler::post(request, connection),
+                    RequestMethod::OPTIONS => request_handler::options(request, &allowed_methods),
+                    _ => request_handler::method_not_allowed(request, &allowed_methods)
+                })
+            },
+            /* Get, update or delete specific record */
+            Some(id) => {
+                let id: i32 = id.parse().ok()?;
+                let allowed_methods = [
+                    RequestMethod::GET,
+                    RequestMethod::HEAD,
+                    RequestMethod::PUT,
+                    RequestMethod::PATCH,
+                    RequestMethod::DELETE,
+                    RequestMethod::OPTIONS
+                ];
+                let require_if_match = context.require_if_match;
 
-            match request.method {
-                RequestMethod::GET => request_handler::get_id(
-                    request,
-                    stream,
-                    connection,
-                    id
-                ),
-                _ => Response::not_found(version, now, stream)
+                match request.method {
+                    RequestMethod::GET | RequestMethod::HEAD => request_handler::get_id(request, connection, id),
+                    RequestMethod::PUT => request_handler::put(request, connection, Some(id), require_if_match),
+                    RequestMethod::PATCH => request_handler::patch(request, connection, Some(id), require_if_match),
+                    RequestMethod::DELETE => request_handler::delete(request, connection, id, require_if_match),
+                    RequestMethod::OPTIONS => Some(request_handler::options(request, &allowed_methods)),
+                    _ => Some(request_handler::method_not_allowed(request, &allowed_methods))
+                }
             }
-            return;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json::field::JsonField;
+    use crate::test_dir::TestDir;
+
+    fn context() -> Context {
+        Context {
+            jsondb: OnceLock::new(),
+            cors: Arc::new(CorsConfig::default()),
+            limits: RequestLimits::default(),
+            compression: CompressionConfig::default(),
+            require_if_match: false,
+            auto_create: false,
+            read_only: false,
+            access_log: Arc::new(AccessLog::open(&Default::default(), false).unwrap()),
+            metrics: Arc::new(Metrics::default()),
+            rewriter: Arc::new(Rewriter::default()),
+            static_files: None,
+            delay: Arc::new(DelayConfig::default()),
+            chaos: None,
+            timer: OnceLock::new()
+        }
+    }
+
+    fn load(context: &Context, path: &Path) {
+        let _ = context.jsondb.set(Arc::new(JsonDb::new(path, false, false)));
+    }
+
+    /// Runs the request through the whole pipeline, returns the response as written
+    fn exchange(context: &Context, message: &str) -> String {
+        let (response, entry) = Server::respond(&mut message.as_bytes(), Instant::now(), "127.0.0.1", context);
+        let mut sent = Vec::new();
+        Server::send(response, entry, &mut sent, context).unwrap();
+        String::from_utf8_lossy(&sent).into_owned()
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        let (head, _) = response.split_once("\r\n\r\n")?;
+        head.split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(": "))
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    fn users() -> TestDir {
+        let dir = TestDir::new();
+        dir.write("users.json", r#"[{"id":1,"name":"Ada"},{"id":2,"name":"Linus"}]"#);
+        dir
+    }
+
+    #[test]
+    fn it_binds_ipv6_hosts_with_or_without_brackets() {
+        let listener = Server::bind("[::1]", 0).unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv6());
+        let listener = Server::bind("::1", 0).unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn it_explains_bind_errors() {
+        let listener = Server::bind("127.0.0.1", 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let err = Server::bind("127.0.0.1", port).unwrap_err();
+        assert_eq!(err, format!("Port {port} is already in use on 127.0.0.1, stop the other process or choose another --port"));
+
+        // Documentation range, never assigned to an interface
+        let err = Server::bind("192.0.2.1", 0).unwrap_err();
+        assert!(err.starts_with(r#"Address "192.0.2.1" is not assigned to any interface"#), "{err}");
+    }
+
+    #[test]
+    fn it_answers_head_with_the_length_of_the_get_body() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let get = exchange(&context, "GET /users/1 HTTP/1.1\r\n\r\n");
+        let head = exchange(&context, "HEAD /users/1 HTTP/1.1\r\n\r\n");
+
+        let (get_head, get_body) = get.split_once("\r\n\r\n").unwrap();
+        assert!(get_head.contains(&format!("Content-Length: {}\r\n", get_body.len())));
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", get_body.len())));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn it_answers_options_and_unsupported_methods_with_allow() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let options = exchange(&context, "OPTIONS /users HTTP/1.1\r\n\r\n");
+        assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(options.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+
+        let options = exchange(&context, "OPTIONS /users/1 HTTP/1.1\r\n\r\n");
+        assert!(options.contains("Allow: GET, HEAD, PUT, PATCH, DELETE, OPTIONS\r\n"));
+
+        let delete = exchange(&context, "DELETE /users HTTP/1.1\r\n\r\n");
+        assert!(delete.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(delete.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+
+        let trace = exchange(&context, "TRACE /users/1 HTTP/1.1\r\n\r\n");
+        assert!(trace.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(trace.contains("Allow: GET, HEAD, PUT, PATCH, DELETE, OPTIONS\r\n"));
+    }
+
+    #[test]
+    fn it_accepts_if_match_with_the_etag_of_a_compressed_response() {
+        let dir = users();
+        let mut context = context();
+        context.compression = CompressionConfig { level: 6, threshold: 0 };
+        context.require_if_match = true;
+        load(&context, dir.path());
+
+        let get = exchange(&context, "GET /users/1 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert_eq!(header(&get, "Content-Encoding"), Some("gzip"));
+        let etag = header(&get, "ETag").unwrap();
+        assert!(etag.starts_with("W/"));
+
+        let body = r#"{"name":"Grace"}"#;
+        let put = exchange(&context, &format!(
+            "PUT /users/1 HTTP/1.1\r\nIf-Match: {etag}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ));
+        assert!(put.starts_with("HTTP/1.1 200 OK\r\n"), "{put}");
+
+        // The record changed since, the tag no longer matches
+        let patch = exchange(&context, &format!(
+            "PATCH /users/1 HTTP/1.1\r\nIf-Match: {etag}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ));
+        assert!(patch.starts_with("HTTP/1.1 412 Precondition Failed\r\n"), "{patch}");
+
+        let patch = exchange(&context, &format!("PATCH /users/1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}", body.len()));
+        assert!(patch.starts_with("HTTP/1.1 428 Precondition Required\r\n"), "{patch}");
+    }
+
+    #[test]
+    fn it_updates_and_deletes_records_created_by_post() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let created = post(&context, "/users", r#"{"name":"Grace"}"#);
+        assert_eq!(JsonField::from(body(&created)), JsonField::from(r#"{"id":3,"name":"Grace"}"#));
+        let created = post(&context, "/users", r#"{"id":1,"name":"Alan"}"#);
+        assert_eq!(JsonField::from(body(&created)), JsonField::from(r#"{"id":4,"name":"Alan"}"#));
+
+        let patch = r#"{"role":"admin"}"#;
+        let patched = exchange(&context, &format!("PATCH /users/3 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{patch}", patch.len()));
+        assert_eq!(JsonField::from(body(&patched)), JsonField::from(r#"{"id":3,"name":"Grace","role":"admin"}"#));
+
+        let deleted = exchange(&context, "DELETE /users/3 HTTP/1.1\r\n\r\n");
+        assert!(deleted.starts_with("HTTP/1.1 204 No Content\r\n"), "{deleted}");
+        assert!(exchange(&context, "GET /users/3 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(exchange(&context, "GET /users/4 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn it_rejects_posts_without_a_json_object() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let missing = exchange(&context, "POST /users HTTP/1.1\r\n\r\n");
+        assert!(missing.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{missing}");
+        for content in ["{", "[1]", "not json"] {
+            let invalid = post(&context, "/users", content);
+            assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{invalid}");
+        }
+        let users = exchange(&context, "GET /users HTTP/1.1\r\n\r\n");
+        assert_eq!(JsonField::from(body(&users)), JsonField::from(r#"[{"id":1,"name":"Ada"},{"id":2,"name":"Linus"}]"#));
+    }
+
+    #[test]
+    fn it_answers_not_modified_with_the_validators_of_the_compressed_response() {
+        let dir = users();
+        let mut context = context();
+        context.compression = CompressionConfig { level: 6, threshold: 0 };
+        load(&context, dir.path());
+
+        for target in ["/users", "/users/1"] {
+            let get = exchange(&context, &format!("GET {target} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"));
+            let etag = header(&get, "ETag").unwrap().to_owned();
+            assert!(etag.starts_with("W/"));
+
+            let not_modified = exchange(&context, &format!("GET {target} HTTP/1.1\r\nAccept-Encoding: gzip\r\nIf-None-Match: {etag}\r\n\r\n"));
+            assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{not_modified}");
+            assert_eq!(header(&not_modified, "ETag"), Some(etag.as_str()));
+            assert_eq!(header(&not_modified, "Vary"), Some("Accept-Encoding"));
+            assert_eq!(header(&not_modified, "Content-Type"), None);
+            assert_eq!(header(&not_modified, "Content-Encoding"), None);
+            assert!(not_modified.ends_with("\r\n\r\n"));
+
+            // The identity representation keeps its strong tag
+            let not_modified = exchange(&context, &format!("GET {target} HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n"));
+            assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{not_modified}");
+            assert_eq!(header(&not_modified, "ETag"), etag.strip_prefix("W/"));
+            assert_eq!(header(&not_modified, "Vary"), Some("Accept-Encoding"));
+        }
+
+        // Too small to be compressed
+        context.compression.threshold = 1024;
+        let get = exchange(&context, "GET /users/1 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let etag = header(&get, "ETag").unwrap();
+        let not_modified = exchange(&context, &format!("GET /users/1 HTTP/1.1\r\nAccept-Encoding: gzip\r\nIf-None-Match: {etag}\r\n\r\n"));
+        assert_eq!(header(&not_modified, "ETag"), Some(etag));
+    }
+
+    #[test]
+    fn it_records_what_was_received_of_unreadable_requests() {
+        let context = context();
+
+        let message = "GET /users HTTP/1.1\r\nHost: localhost\r\nBroken\r\n\r\n{\"ignored\":true}";
+        let (response, entry) = Server::respond(&mut message.as_bytes(), Instant::now(), "127.0.0.1", &context);
+        assert_eq!(*response.status_code(), StatusCode::BadRequest);
+        assert!(entry.request.is_none());
+        assert_eq!(entry.error.as_deref(), Some("Malformed request: Malformed header line: \"Broken\""));
+        assert_eq!(entry.raw_head.as_deref(), Some("GET /users HTTP/1.1\r\nHost: localhost\r\nBroken"));
+
+        let (_, entry) = Server::respond(&mut "GET /users HTTP/1.1\r\n\r\n".as_bytes(), Instant::now(), "127.0.0.1", &context);
+        assert_eq!((entry.error, entry.raw_head), (None, None));
+    }
+
+    #[test]
+    fn it_answers_health_checks_while_loading() {
+        let context = context();
+
+        let health = exchange(&context, "GET /__health HTTP/1.1\r\n\r\n");
+        assert!(health.starts_with("HTTP/1.1 200 OK\r\n"), "{health}");
+        assert!(health.ends_with(r#"{ "status": "ok" }"#));
+        let health = exchange(&context, "POST /__health HTTP/1.1\r\n\r\n");
+        assert!(health.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{health}");
+
+        let ready = exchange(&context, "GET /__ready HTTP/1.1\r\n\r\n");
+        assert!(ready.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{ready}");
+        assert_eq!(header(&ready, "Retry-After"), Some("1"));
+        assert!(ready.contains(r#""status": "loading""#));
+
+        let users = exchange(&context, "GET /users HTTP/1.1\r\n\r\n");
+        assert!(users.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{users}");
+        assert_eq!(header(&users, "Retry-After"), Some("1"));
+    }
+
+    #[test]
+    fn it_serves_static_files_while_loading() {
+        let dir = TestDir::new();
+        dir.write("public/index.html", "<h1>Hello</h1>");
+        let mut context = context();
+        context.static_files = Some(Arc::new(StaticFiles::new(&dir.path().join("public")).unwrap()));
+
+        let index = exchange(&context, "GET / HTTP/1.1\r\n\r\n");
+        assert!(index.starts_with("HTTP/1.1 200 OK\r\n"), "{index}");
+        assert_eq!(body(&index), "<h1>Hello</h1>");
+
+        for target in ["/users", "/__metrics"] {
+            let loading = exchange(&context, &format!("GET {target} HTTP/1.1\r\n\r\n"));
+            assert!(loading.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{loading}");
+        }
+    }
+
+    #[test]
+    fn it_is_ready_once_loaded() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let ready = exchange(&context, "GET /__ready HTTP/1.1\r\n\r\n");
+        assert!(ready.starts_with("HTTP/1.1 200 OK\r\n"), "{ready}");
+        assert!(ready.contains(r#""status": "ready", "loaded": true, "persistence_healthy": true, "collections": 1"#));
+        assert!(exchange(&context, "GET /users HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn it_is_not_ready_when_a_collection_failed_to_load() {
+        let dir = users();
+        dir.write("broken.json", "[{");
+        let context = context();
+        load(&context, dir.path());
+
+        let ready = exchange(&context, "GET /__ready HTTP/1.1\r\n\r\n");
+        assert!(ready.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{ready}");
+        assert!(ready.contains(r#""status": "unavailable", "loaded": false"#));
+        assert!(ready.contains("broken.json"));
+        // The other collections are served meanwhile
+        assert!(exchange(&context, "GET /users HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn it_records_a_panicking_load_as_a_load_failure() {
+        let dir = users();
+        let jsondb = Server::load_guarded(dir.path(), false, false, || panic!("Unexpected file"));
+        assert_eq!(jsondb.failures().len(), 1);
+        assert_eq!(jsondb.failures()[0].reason, "Unexpected file");
+
+        let context = context();
+        let _ = context.jsondb.set(Arc::new(jsondb));
+        let ready = exchange(&context, "GET /__ready HTTP/1.1\r\n\r\n");
+        assert!(ready.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{ready}");
+        assert!(ready.contains(r#""reason": "Unexpected file""#));
+
+        let jsondb = Server::load_guarded(dir.path(), false, false, || JsonDb::new(dir.path(), false, false));
+        assert!(jsondb.failures().is_empty());
+        assert_eq!(jsondb.len(), 1);
+    }
+
+    #[test]
+    fn it_serves_singular_resources() {
+        let dir = users();
+        dir.write("profile.json", r#"{"name":"Ada","theme":"dark"}"#);
+        let context = context();
+        load(&context, dir.path());
+
+        let get = exchange(&context, "GET /profile HTTP/1.1\r\n\r\n");
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"), "{get}");
+        assert_eq!(JsonField::from(body(&get)), JsonField::from(r#"{"name":"Ada","theme":"dark"}"#));
+
+        let content = r#"{"name":"Grace","theme":"light","lang":"en"}"#;
+        let put = exchange(&context, &format!("PUT /profile HTTP/1.1\r\nContent-Length: {}\r\n\r\n{content}", content.len()));
+        assert!(put.starts_with("HTTP/1.1 200 OK\r\n"), "{put}");
+        assert_eq!(JsonField::from(body(&put)), JsonField::from(content));
+
+        let content = r#"{"theme":null,"lang":"fr"}"#;
+        let patch = exchange(&context, &format!("PATCH /profile HTTP/1.1\r\nContent-Length: {}\r\n\r\n{content}", content.len()));
+        assert!(patch.starts_with("HTTP/1.1 200 OK\r\n"), "{patch}");
+        assert_eq!(JsonField::from(body(&patch)), JsonField::from(r#"{"name":"Grace","lang":"fr"}"#));
+
+        let get = exchange(&context, "GET /profile HTTP/1.1\r\n\r\n");
+        assert_eq!(JsonField::from(body(&get)), JsonField::from(r#"{"name":"Grace","lang":"fr"}"#));
+    }
+
+    #[test]
+    fn it_rejects_posts_deletes_and_ids_on_singular_resources() {
+        let dir = users();
+        dir.write("profile.json", r#"{"name":"Ada"}"#);
+        let context = context();
+        load(&context, dir.path());
+
+        for method in ["POST", "DELETE"] {
+            let response = exchange(&context, &format!("{method} /profile HTTP/1.1\r\nContent-Length: 2\r\n\r\n{{}}"));
+            assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{response}");
+            assert_eq!(header(&response, "Allow"), Some("GET, HEAD, PUT, PATCH, OPTIONS"));
+        }
+
+        for method in ["GET", "PUT", "DELETE"] {
+            let response = exchange(&context, &format!("{method} /profile/1 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{{}}"));
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        }
+
+        let get = exchange(&context, "GET /profile HTTP/1.1\r\n\r\n");
+        assert_eq!(JsonField::from(body(&get)), JsonField::from(r#"{"name":"Ada"}"#));
+    }
+
+    fn post(context: &Context, target: &str, content: &str) -> String {
+        exchange(context, &format!("POST {target} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{content}", content.len()))
+    }
+
+    #[test]
+    fn it_creates_and_drops_collections() {
+        let dir = users();
+        let context = context();
+        load(&context, dir.path());
+
+        let created = post(&context, "/__collections", r#"{"name":"v1/posts"}"#);
+        assert!(created.starts_with("HTTP/1.1 201 Created\r\n"), "{created}");
+        assert_eq!(header(&created, "Location"), Some("/v1/posts"));
+        assert!(exchange(&context, "GET /v1/posts HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n[]"));
+
+        let duplicate = post(&context, "/__collections", r#"{"name":"users"}"#);
+        assert!(duplicate.starts_with("HTTP/1.1 409 Conflict\r\n"), "{duplicate}");
+        let invalid = post(&context, "/__collections", r#"{"name":"__posts"}"#);
+        assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{invalid}");
+
+        for confirmation in ["", "X-Confirm-Delete: users\r\n"] {
+            let unconfirmed = exchange(&context, &format!("DELETE /__collections/v1/posts HTTP/1.1\r\n{confirmation}\r\n"));
+            assert!(unconfirmed.starts_with("HTTP/1.1 428 Precondition Required\r\n"), "{unconfirmed}");
+        }
+        let dropped = exchange(&context, "DELETE /__collections/v1/posts HTTP/1.1\r\nX-Confirm-Delete: v1/posts\r\n\r\n");
+        assert!(dropped.starts_with("HTTP/1.1 204 No Content\r\n"), "{dropped}");
+        assert!(!dir.path().join("v1/posts.json").exists());
+        assert!(exchange(&context, "GET /v1/posts HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let unknown = exchange(&context, "DELETE /__collections/v1/posts HTTP/1.1\r\nX-Confirm-Delete: v1/posts\r\n\r\n");
+        assert!(unknown.starts_with("HTTP/1.1 404 Not Found\r\n"), "{unknown}");
+    }
+
+    #[test]
+    fn it_creates_collections_on_post_when_enabled() {
+        let dir = users();
+        let mut context = context();
+        context.auto_create = true;
+        load(&context, dir.path());
+
+        let created = post(&context, "/posts", r#"{"title":"Hello"}"#);
+        assert!(created.starts_with("HTTP/1.1 200 OK\r\n"), "{created}");
+        let posts = exchange(&context, "GET /posts HTTP/1.1\r\n\r\n");
+        assert!(body(&posts).contains(r#""title":"Hello""#), "{posts}");
+
+        let invalid = post(&context, "/posts.json", "{}");
+        assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{invalid}");
+        assert!(exchange(&context, "GET /comments HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn it_rejects_writes_when_read_only() {
+        let dir = users();
+        dir.write("profile.json", r#"{"name":"Ada"}"#);
+        let mut context = context();
+        context.read_only = true;
+        load(&context, dir.path());
+
+        for (method, target) in [("POST", "/users"), ("PUT", "/users/1"), ("PATCH", "/profile"), ("DELETE", "/users/1"), ("POST", "/__collections")] {
+            let response = exchange(&context, &format!("{method} {target} HTTP/1.1\r\nContent-Length: 2\r\n\r\n{{}}"));
+            assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{response}");
+            assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+            assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+            assert!(body(&response).contains(&format!("the server is read-only and rejects {method} requests")));
+        }
+        let users = exchange(&context, "GET /users/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(JsonField::from(body(&users)), JsonField::from(r#"{"id":1,"name":"Ada"}"#));
+    }
+
+    #[test]
+    fn it_only_advertises_reads_when_read_only() {
+        let dir = users();
+        dir.write("profile.json", r#"{"name":"Ada"}"#);
+        let mut context = context();
+        context.read_only = true;
+        let mut cors = CorsConfig { allowed_origins: vec!["http://app.test".to_owned()], ..Default::default() };
+        cors.allow_reads_only();
+        context.cors = Arc::new(cors);
+        load(&context, dir.path());
+
+        for target in ["/users", "/users/1", "/profile"] {
+            let options = exchange(&context, &format!("OPTIONS {target} HTTP/1.1\r\n\r\n"));
+            assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"), "{options}");
+            assert_eq!(header(&options, "Allow"), Some("GET, HEAD, OPTIONS"), "{target}");
+        }
+
+        let preflight = exchange(&context, "OPTIONS /users HTTP/1.1\r\nOrigin: http://app.test\r\nAccess-Control-Request-Method: POST\r\n\r\n");
+        assert!(preflight.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{preflight}");
+        let preflight = exchange(&context, "OPTIONS /users HTTP/1.1\r\nOrigin: http://app.test\r\nAccess-Control-Request-Method: GET\r\n\r\n");
+        assert_eq!(header(&preflight, "Access-Control-Allow-Methods"), Some("GET, HEAD"));
+    }
+}
+
@@ -0,0 +1,541 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::chaos::Fault;
+use super::http_date;
+use super::request::Request;
+use super::response::Response;
+use super::status_code::StatusCode;
+
+/// Bytes of the response body shown in verbose mode
+const VERBOSE_BODY_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Common Log Format, as written by Apache and nginx, followed by the
+    /// duration of the request in milliseconds
+    Common,
+    /// Common Log Format followed by the referer, the user agent and the
+    /// duration of the request in milliseconds
+    Combined,
+    /// One JSON object per line
+    Json
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub format: LogFormat,
+    /// File to append the log to, the log goes to stdout when unset
+    pub file: Option<PathBuf>,
+    /// Size in bytes the file is rotated at
+    pub max_size: usize,
+    /// Rotated files kept as `<file>.1` (the newest) to `<file>.<max_files>`
+    pub max_files: usize
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Common,
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5
+        }
+    }
+}
+
+/// Time spent on each phase of handling a request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Reading and parsing the request, including waiting for the client
+    pub parse: Duration,
+    /// Routing the request and producing the response
+    pub handle: Duration,
+    /// Serializing the response and writing it out, the event loop engine
+    /// only writes it into its buffer in this phase
+    pub write: Duration,
+    /// Artificial latency the response is held back for once written, see `--delay`
+    pub delay: Duration
+}
+
+/// One request and its response as recorded in the access log
+pub struct Entry {
+    pub remote_addr: String,
+    /// When the request started to be handled
+    pub time: SystemTime,
+    pub start_time: Instant,
+    /// `None` when the request couldn't be read
+    pub request: Option<Request>,
+    /// Why the request couldn't be read
+    pub error: Option<String>,
+    /// Request line and headers as received when the request couldn't be read
+    pub raw_head: Option<String>,
+    /// Route pattern the request matched, such as "/users/:id"
+    pub route: Option<String>,
+    pub status_code: StatusCode,
+    /// Bytes of the response body sent
+    pub bytes: usize,
+    pub timings: Timings,
+    /// Fault injected into the response by the chaos mode
+    pub fault: Option<Fault>,
+    /// Status line and headers of the response, only kept in verbose mode
+    pub response_head: Option<String>,
+    /// Start of the response body, only kept in verbose mode
+    pub response_body: Option<String>
+}
+
+impl Entry {
+    pub fn new(remote_addr: &str, start_time: Instant) -> Self {
+        Self {
+            remote_addr: remote_addr.to_owned(),
+            time: SystemTime::now(),
+            start_time,
+            request: None,
+            error: None,
+            raw_head: None,
+            route: None,
+            status_code: StatusCode::Ok,
+            bytes: 0,
+            timings: Timings::default(),
+            fault: None,
+            response_head: None,
+            response_body: None
+        }
+    }
+
+    /// Records the response about to be sent, with its head and the start of
+    /// its body when the access log is verbose
+    pub fn set_response(&mut self, response: &Response, verbose: bool) {
+        self.status_code = *response.status_code();
+        if verbose {
+            self.response_head = Some(response.format_head());
+            self.response_body = response.body_preview(VERBOSE_BODY_LIMIT);
+        }
+    }
+
+    /// Time from the start of the request until its response is let out,
+    /// the delay included as the response is recorded before it is held back
+    pub fn duration(&self) -> Duration {
+        self.start_time.elapsed() + self.timings.delay
+    }
+
+    fn request_line(&self) -> Option<String> {
+        match &self.request {
+            Some(request) => Some(format!("{} {} {}", request.method.as_str(), request.target(), request.version)),
+            None => self.raw_head.as_ref()?.lines().next().map(str::to_owned)
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request.as_ref()?.headers.get(name)
+    }
+}
+
+/// Log file which is moved aside to `<file>.1` once it grows past the size limit
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: usize, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self { path: path.to_owned(), file, size, max_size: max_size as u64, max_files })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, content: &str) -> io::Result<()> {
+        let len = content.len() as u64;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(content.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile)
+}
+
+/// Records every request the server answers, in verbose mode along with
+/// the headers and the bodies of the request and the response
+pub struct AccessLog {
+    format: LogFormat,
+    verbose: bool,
+    output: Mutex<Output>
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig, verbose: bool) -> io::Result<Self> {
+        let output = match &config.file {
+            Some(path) => Output::File(RotatingFile::open(path, config.max_size, config.max_files)?),
+            None => Output::Stdout
+        };
+
+        Ok(Self { format: config.format, verbose, output: Mutex::new(output) })
+    }
+
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let content = match self.format {
+            LogFormat::Common | LogFormat::Combined => self.format_common(entry),
+            LogFormat::Json => self.format_json(entry)
+        };
+
+        let result = match &mut *self.output.lock().unwrap() {
+            Output::Stdout => io::stdout().lock().write_all(content.as_bytes()),
+            Output::File(file) => file.write(&content)
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to write access log: {err}");
+        }
+    }
+
+    fn format_common(&self, entry: &Entry) -> String {
+        let quoted = |value: Option<&str>| match value {
+            Some(value) => format!(r#""{}""#, escape_common(value)),
+            None => r#""-""#.to_owned()
+        };
+
+        let mut line = format!(
+            "{} - - [{}] {} {} {}",
+            entry.remote_addr,
+            http_date::format_common_log(entry.time),
+            quoted(entry.request_line().as_deref()),
+            entry.status_code.get_value(),
+            entry.bytes
+        );
+        if self.format == LogFormat::Combined {
+            let _ = write!(line, " {} {}", quoted(entry.header("Referer")), quoted(entry.header("User-Agent")));
+        }
+        let _ = writeln!(line, " {:.3}ms", millis(entry.duration()));
+
+        if !self.verbose { return line; }
+
+        if let Some(request) = &entry.request {
+            for (key, value) in request.headers.iter() {
+                let _ = writeln!(line, "> {key}: {value}");
+            }
+            if let Some(body) = &request.body {
+                let _ = writeln!(line, ">\n{body}");
+            }
+        } else if let Some(head) = &entry.raw_head {
+            for head_line in head.lines().skip(1) {
+                let _ = writeln!(line, "> {}", escape_common(head_line));
+            }
+        }
+        if let Some(error) = &entry.error {
+            let _ = writeln!(line, "* {error}");
+        }
+        if let Some(head) = &entry.response_head {
+            for head_line in head.lines().filter(|head_line| !head_line.is_empty()) {
+                let _ = writeln!(line, "< {head_line}");
+            }
+        }
+        if let Some(body) = &entry.response_body {
+            let _ = writeln!(line, "<\n{body}");
+        }
+
+        let timings = &entry.timings;
+        let _ = write!(
+            line,
+            "* parse {:?}, handle {:?}, write {:?}",
+            timings.parse,
+            timings.handle,
+            timings.write
+        );
+        if !timings.delay.is_zero() {
+            let _ = write!(line, ", delay {:?}", timings.delay);
+        }
+        line.push('\n');
+
+        line
+    }
+
+    fn format_json(&self, entry: &Entry) -> String {
+        let string = |value: Option<&str>| match value {
+            Some(value) => format!(r#""{}""#, escape_json(value)),
+            None => "null".to_owned()
+        };
+        let request = entry.request.as_ref();
+
+        let mut line = format!(
+            r#"{{"time":"{}","remote_addr":{},"method":{},"path":{},"protocol":{},"status":{},"bytes":{},"duration_ms":{:.3},"parse_ms":{:.3},"handle_ms":{:.3},"write_ms":{:.3},"delay_ms":{:.3},"fault":{},"error":{},"referer":{},"user_agent":{}"#,
+            http_date::format_rfc3339(entry.time),
+            string(Some(&entry.remote_addr)),
+            string(request.map(|request| request.method.as_str())),
+            string(request.map(|request| request.target())),
+            string(request.map(|request| request.version.as_str())),
+            entry.status_code.get_value(),
+            entry.bytes,
+            millis(entry.duration()),
+            millis(entry.timings.parse),
+            millis(entry.timings.handle),
+            millis(entry.timings.write),
+            millis(entry.timings.delay),
+            string(entry.fault.map(|fault| fault.to_string()).as_deref()),
+            string(entry.error.as_deref()),
+            string(entry.header("Referer")),
+            string(entry.header("User-Agent"))
+        );
+
+        if self.verbose {
+            let pair = |key: &str, value: &str| format!("[{},{}]", string(Some(key)), string(Some(value)));
+
+            if let Some(request) = request {
+                let headers: Vec<String> = request.headers.iter().map(|(key, value)| pair(key, value)).collect();
+                let _ = write!(
+                    line,
+                    r#","request_headers":[{}],"request_body":{}"#,
+                    headers.join(","),
+                    string(request.body.as_deref())
+                );
+            } else if let Some(head) = &entry.raw_head {
+                let _ = write!(line, r#","request_head":{}"#, string(Some(head)));
+            }
+
+            // The status line is left out, the status is logged already
+            let headers: Vec<String> = entry.response_head
+                .iter()
+                .flat_map(|head| head.lines().skip(1))
+                .filter_map(|head_line| head_line.split_once(": "))
+                .map(|(key, value)| pair(key, value))
+                .collect();
+            let _ = write!(
+                line,
+                r#","response_headers":[{}],"response_body":{}"#,
+                headers.join(","),
+                string(entry.response_body.as_deref())
+            );
+        }
+        line.push_str("}\n");
+
+        line
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Escapes quotes, backslashes and control characters the way Apache does
+fn escape_common(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => { let _ = write!(escaped, "\\x{:02x}", c as u32); },
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+pub(super) fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => { let _ = write!(escaped, "\\u{:04x}", c as u32); },
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use super::super::response::ResponseBuilder;
+
+    fn entry(message: &str) -> Entry {
+        let start_time = Instant::now();
+        let mut entry = Entry::new("127.0.0.1", start_time);
+        entry.timings.delay = Duration::from_secs(3600);
+        entry.time = UNIX_EPOCH + Duration::from_secs(784111777);
+        entry.request = Some(Request::new(&mut message.as_bytes(), start_time, &Default::default()).unwrap());
+        entry.bytes = 47;
+        entry
+    }
+
+    fn access_log(format: LogFormat) -> AccessLog {
+        AccessLog::open(&AccessLogConfig { format, ..Default::default() }, false).unwrap()
+    }
+
+    #[test]
+    fn it_formats_common_and_combined_log_lines() {
+        let entry = entry("GET /users HTTP/1.1\r\nUser-Agent: curl/8.0 \"quoted\"\r\n\r\n");
+
+        assert!(access_log(LogFormat::Common).format_common(&entry).starts_with(
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /users HTTP/1.1\" 200 47 3600000."
+        ));
+        assert!(access_log(LogFormat::Combined).format_common(&entry).starts_with(
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /users HTTP/1.1\" 200 47 \"-\" \"curl/8.0 \\\"quoted\\\"\" 3600000."
+        ));
+        assert!(access_log(LogFormat::Common).format_common(&entry).ends_with("ms\n"));
+    }
+
+    #[test]
+    fn it_formats_json_log_lines() {
+        let line = access_log(LogFormat::Json).format_json(&entry("GET /users HTTP/1.1\r\n\r\n"));
+
+        assert!(line.starts_with(
+            r#"{"time":"1994-11-06T08:49:37.000Z","remote_addr":"127.0.0.1","method":"GET","path":"/users","protocol":"HTTP/1.1","status":200,"bytes":47,"duration_ms":"#
+        ));
+        assert!(line.ends_with(",\"referer\":null,\"user_agent\":null}\n"));
+    }
+
+    fn created(content: &str) -> Response {
+        ResponseBuilder::new()
+            .set_status_code(StatusCode::Created)
+            .set_protocol("HTTP/1.1".to_owned())
+            .set_content(content.to_owned())
+            .set_content_type("application/json".to_owned())
+            .build()
+    }
+
+    fn verbose_entry() -> Entry {
+        let mut entry = entry("POST /users HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 16\r\n\r\n{\"name\":\"Grace\"}");
+        entry.set_response(&created(r#"{"id":3,"name":"Grace"}"#), true);
+        entry.timings = Timings {
+            parse: Duration::from_millis(1),
+            handle: Duration::from_millis(2),
+            write: Duration::from_millis(3),
+            delay: Duration::ZERO
+        };
+        entry
+    }
+
+    #[test]
+    fn it_dumps_requests_and_responses_in_verbose_mode() {
+        let mut entry = verbose_entry();
+        let log = AccessLog::open(&AccessLogConfig::default(), true).unwrap();
+
+        let lines = log.format_common(&entry);
+        let (_, dump) = lines.split_once('\n').unwrap();
+        assert_eq!(
+            dump,
+            "> Content-Type: application/json\n\
+             > Content-Length: 16\n\
+             >\n{\"name\":\"Grace\"}\n\
+             < HTTP/1.1 201 Created\n\
+             < Content-Length: 23\n\
+             < Content-Type: application/json\n\
+             <\n{\"id\":3,\"name\":\"Grace\"}\n\
+             * parse 1ms, handle 2ms, write 3ms\n"
+        );
+
+        entry.timings.delay = Duration::from_millis(4);
+        assert!(log.format_common(&entry).ends_with("* parse 1ms, handle 2ms, write 3ms, delay 4ms\n"));
+
+        // Only the summary line when not verbose
+        assert_eq!(access_log(LogFormat::Common).format_common(&entry).lines().count(), 1);
+    }
+
+    #[test]
+    fn it_adds_headers_and_bodies_to_json_lines_in_verbose_mode() {
+        let config = AccessLogConfig { format: LogFormat::Json, ..Default::default() };
+        let line = AccessLog::open(&config, true).unwrap().format_json(&verbose_entry());
+
+        assert!(line.contains(r#""parse_ms":1.000,"handle_ms":2.000,"write_ms":3.000,"delay_ms":0.000"#));
+        assert!(line.ends_with(concat!(
+            r#","request_headers":[["Content-Type","application/json"],["Content-Length","16"]],"request_body":"{\"name\":\"Grace\"}""#,
+            r#","response_headers":[["Content-Length","23"],["Content-Type","application/json"]],"response_body":"{\"id\":3,\"name\":\"Grace\"}"}"#,
+            "\n"
+        )));
+    }
+
+    #[test]
+    fn it_previews_response_bodies() {
+        let preview = |response: &Response| {
+            let mut entry = Entry::new("127.0.0.1", Instant::now());
+            entry.set_response(response, true);
+            entry.response_body
+        };
+
+        assert_eq!(preview(&created("{}")), Some("{}".to_owned()));
+
+        let long = "a".repeat(VERBOSE_BODY_LIMIT + 10);
+        assert_eq!(
+            preview(&created(&long)),
+            Some(format!("{}... ({} bytes in total)", &long[..VERBOSE_BODY_LIMIT], long.len()))
+        );
+
+        let mut head = created("{}");
+        head.omit_body();
+        assert_eq!(preview(&head), None);
+        assert_eq!(preview(&ResponseBuilder::new().set_status_code(StatusCode::NoContent).build()), None);
+
+        let encoded = ResponseBuilder::new().set_bytes(vec![0x1f, 0x8b, 8]).set_header("Content-Encoding", "gzip").build();
+        assert_eq!(preview(&encoded), Some("(3 bytes of gzip encoded content)".to_owned()));
+
+        let streamed = ResponseBuilder::new().set_stream(Box::new(|_| Ok(()))).build();
+        assert_eq!(preview(&streamed), Some("(streamed content)".to_owned()));
+
+        // Kept only in verbose mode
+        let mut entry = Entry::new("127.0.0.1", Instant::now());
+        entry.set_response(&created("{}"), false);
+        assert_eq!((entry.response_head, entry.response_body), (None, None));
+    }
+
+    #[test]
+    fn it_logs_what_was_received_of_unreadable_requests() {
+        let mut entry = Entry::new("127.0.0.1", Instant::now());
+        entry.time = UNIX_EPOCH + Duration::from_secs(784111777);
+        entry.status_code = StatusCode::BadRequest;
+        entry.error = Some("Malformed request: Malformed header line: \"Broken\"".to_owned());
+        entry.raw_head = Some("GET /users HTTP/1.1\r\nHost: localhost\r\nBroken".to_owned());
+
+        let common = AccessLog::open(&AccessLogConfig::default(), true).unwrap().format_common(&entry);
+        assert!(common.starts_with("127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /users HTTP/1.1\" 400 0 "));
+        assert!(common.contains("\n> Host: localhost\n> Broken\n* Malformed request: Malformed header line: \"Broken\"\n"));
+
+        let json = AccessLog::open(&AccessLogConfig { format: LogFormat::Json, ..Default::default() }, true).unwrap().format_json(&entry);
+        assert!(json.contains(r#""method":null,"#));
+        assert!(json.contains(r#""error":"Malformed request: Malformed header line: \"Broken\"","#));
+        assert!(json.contains(r#","request_head":"GET /users HTTP/1.1\r\nHost: localhost\r\nBroken","#));
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::RESERVED_PREFIX;
+use crate::json::field::JsonField;
+use crate::json::parser::parse_json;
+
+use super::request::Request;
+use super::response::{Response, ResponseBody, ResponseBuilder};
+use super::rewrite::Pattern;
+use super::status_code::StatusCode;
+
+/// Faults injected into a share of the requests, loaded from a file such as
+/// `{ "seed": 42, "faults": [{ "route": "/users/*", "methods": ["POST"], "rate": 0.2, "fault": "status", "status": [500, 503] }] }`.
+/// The faults of each request are drawn from the seed and the position of the
+/// request, so that a run can be reproduced as long as the requests arrive in
+/// the same order, whichever the order they are handled in
+pub struct Chaos {
+    rules: Vec<Rule>,
+    seed: u64,
+    /// Requests drawn so far
+    requests: AtomicU64
+}
+
+struct Rule {
+    /// Every path but the endpoints of the server itself when unset
+    route: Option<Pattern>,
+    /// Every method when empty
+    methods: Vec<String>,
+    /// Share of the matching requests the fault is injected into, from 0 to 1
+    rate: f64,
+    fault: FaultKind
+}
+
+enum FaultKind {
+    /// One of the status codes is picked at random
+    Status(Vec<StatusCode>),
+    Drop,
+    Truncate,
+    Malformed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Answered with the status code instead of being handled
+    Status(StatusCode),
+    /// Connection closed once the head and half of the body are sent
+    Drop,
+    /// Body cut in half, with a `Content-Length` of the cut body
+    Truncate,
+    /// Body turned into JSON which doesn't parse
+    Malformed
+}
+
+/// SplitMix64, small and good enough to draw the faults from the seed
+struct Rng(u64);
+
+impl Chaos {
+    /// Loads the faults of the file, the seed given takes precedence over the one of the file
+    pub fn load(path: &Path, seed: Option<u64>) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&content, seed)
+    }
+
+    fn parse(content: &str, seed: Option<u64>) -> Result<Self, String> {
+        let (json, _) = parse_json(content, 0).map_err(|err| err.0)?;
+        let obj = json
+            .unwrap_as_ref_object()
+            .map_err(|_| r#"Expect chaos to be a JSON object with the "faults" to inject"#.to_owned())?
+            .read()
+            .unwrap();
+
+        let seed = match (seed, obj.get("seed")) {
+            (Some(seed), _) => seed,
+            (None, Some(JsonField::Int(seed))) if *seed >= 0 => *seed as u64,
+            (None, Some(_)) => return Err(r#"Expect "seed" to be a non-negative integer"#.to_owned()),
+            (None, None) => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64)
+        };
+
+        let faults = match obj.get("faults") {
+            Some(JsonField::Array(faults)) => faults.read().unwrap(),
+            _ => return Err(r#"Expect "faults" to be an array"#.to_owned())
+        };
+        let rules = faults
+            .iter()
+            .enumerate()
+            .map(|(index, fault)| Rule::parse(fault).map_err(|err| format!("Fault #{}: {err}", index + 1)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { rules, seed, requests: AtomicU64::new(0) })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Descriptions of the faults, such as `20% of POST /users/*: status 500, 503`
+    pub fn rules(&self) -> impl Iterator<Item = String> + '_ {
+        self.rules.iter().map(|rule| rule.to_string())
+    }
+
+    /// Fault to inject into the request, if any. Every rule matching the
+    /// request is drawn in order and the first one drawn applies
+    pub fn pick(&self, request: &Request) -> Option<Fault> {
+        let path = request.url.to_str().unwrap_or_default();
+        let mut rng = Rng::for_request(self.seed, self.requests.fetch_add(1, Ordering::Relaxed));
+
+        for rule in self.rules.iter().filter(|rule| rule.is_match(request.method.as_str(), path)) {
+            if rng.next_f64() >= rule.rate {
+                continue;
+            }
+
+            let fault = match &rule.fault {
+                FaultKind::Status(status_codes) => Fault::Status(status_codes[rng.next() as usize % status_codes.len()]),
+                FaultKind::Drop => Fault::Drop,
+                FaultKind::Truncate => Fault::Truncate,
+                FaultKind::Malformed => Fault::Malformed
+            };
+            return Some(fault);
+        }
+        None
+    }
+}
+
+impl Rule {
+    fn parse(field: &JsonField) -> Result<Self, String> {
+        let obj = field
+            .unwrap_as_ref_object()
+            .map_err(|_| "Expect a JSON object".to_owned())?
+            .read()
+            .unwrap();
+
+        let route = match obj.get("route") {
+            Some(JsonField::String(route)) => Some(Pattern::new(route)),
+            Some(_) => return Err(r#"Expect "route" to be a path pattern such as "/users/*""#.to_owned()),
+            None => None
+        };
+
+        let methods = match obj.get("methods") {
+            Some(JsonField::Array(methods)) => methods
+                .read()
+                .unwrap()
+                .iter()
+                .map(|method| match method {
+                    JsonField::String(method) => Ok(method.to_ascii_uppercase()),
+                    _ => Err(r#"Expect "methods" to be an array of strings"#.to_owned())
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(r#"Expect "methods" to be an array of strings"#.to_owned()),
+            None => vec![]
+        };
+
+        let rate = match obj.get("rate") {
+            Some(JsonField::Int(rate)) => *rate as f64,
+            Some(JsonField::Float(rate)) => *rate,
+            _ => f64::NAN
+        };
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(r#"Expect "rate" to be a number from 0 to 1"#.to_owned());
+        }
+
+        let fault = match obj.get("fault") {
+            Some(JsonField::String(fault)) if fault == "status" => {
+                let status_codes = match obj.get("status") {
+                    Some(JsonField::Int(status_code)) => vec![*status_code],
+                    Some(JsonField::Array(status_codes)) => status_codes
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .map(|status_code| status_code.unwrap_as_ref_int().copied().unwrap_or(0))
+                        .collect(),
+                    _ => vec![]
+                };
+                let status_codes = status_codes
+                    .iter()
+                    .map(|status_code| StatusCode::from_value(*status_code as usize).ok_or(*status_code))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|status_code| format!("Unsupported status code {status_code}"))?;
+                // Faults are answered as errors, with a body which 204 and 304 can't carry
+                if let Some(status_code) = status_codes.iter().find(|status_code| status_code.get_value() < 400) {
+                    return Err(format!("Expect \"status\" to be of 4xx or 5xx errors, instead got: {}", status_code.get_value()));
+                }
+
+                if status_codes.is_empty() {
+                    return Err(r#"Expect "status" to be a status code or an array of them"#.to_owned());
+                }
+                FaultKind::Status(status_codes)
+            },
+            Some(JsonField::String(fault)) if fault == "drop" => FaultKind::Drop,
+            Some(JsonField::String(fault)) if fault == "truncate" => FaultKind::Truncate,
+            Some(JsonField::String(fault)) if fault == "malformed" => FaultKind::Malformed,
+            _ => return Err(r#"Expect "fault" to be one of "status", "drop", "truncate" or "malformed""#.to_owned())
+        };
+
+        Ok(Self { route, methods, rate, fault })
+    }
+
+    fn is_match(&self, method: &str, path: &str) -> bool {
+        let is_route_match = match &self.route {
+            Some(route) => route.is_match(path),
+            None => !path.trim_start_matches('/').starts_with(RESERVED_PREFIX)
+        };
+        is_route_match && (self.methods.is_empty() || self.methods.iter().any(|allowed| allowed == method))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let methods = match self.methods.is_empty() {
+            true => "any method".to_owned(),
+            false => self.methods.join(", ")
+        };
+        let route = self.route.as_ref().map_or("any route", |route| route.as_str());
+        write!(f, "{}% of {methods} {route}: ", self.rate * 100.0)?;
+
+        match &self.fault {
+            FaultKind::Status(status_codes) => {
+                let status_codes: Vec<String> = status_codes.iter().map(|status_code| status_code.get_value().to_string()).collect();
+                write!(f, "status {}", status_codes.join(", "))
+            },
+            FaultKind::Drop => write!(f, "drop"),
+            FaultKind::Truncate => write!(f, "truncate"),
+            FaultKind::Malformed => write!(f, "malformed")
+        }
+    }
+}
+
+impl Fault {
+    /// Response the request is answered with instead of being handled, if any
+    pub fn failure(&self, request: &Request) -> Option<Response> {
+        let status_code = match self {
+            Fault::Status(status_code) => *status_code,
+            _ => return None
+        };
+
+        let mut response = ResponseBuilder::build_error(request.version.clone(), status_code, status_code.get_desc());
+        if matches!(status_code, StatusCode::TooManyRequests | StatusCode::ServiceUnavailable) {
+            response.headers_mut().set("Retry-After", "1");
+        }
+        Some(response)
+    }
+
+    /// Damages the body of the response, empty bodies are left alone. The
+    /// validators go along with it, so that the damaged body isn't cached or
+    /// resumed as the content they stand for
+    pub fn apply(&self, response: &mut Response) {
+        if !matches!(self, Fault::Truncate | Fault::Malformed) {
+            return;
+        }
+
+        let headers = response.headers_mut();
+        for name in ["ETag", "Last-Modified", "Accept-Ranges"] {
+            headers.remove(name);
+        }
+
+        let mut body = match mem::replace(response.body_mut(), ResponseBody::Bytes(vec![])) {
+            ResponseBody::Content(content) => content.into_bytes(),
+            ResponseBody::Bytes(bytes) => bytes,
+            ResponseBody::Stream(write_body) => {
+                let mut bytes = vec![];
+                let _ = write_body(&mut bytes);
+                bytes
+            },
+            ResponseBody::File(file, len) => {
+                let mut bytes = vec![];
+                let _ = file.take(len).read_to_end(&mut bytes);
+                bytes
+            }
+        };
+
+        if !body.is_empty() {
+            match self {
+                Fault::Truncate => body.truncate(body.len() / 2),
+                _ => {
+                    // Dangling comma and mismatched bracket, whatever the body was
+                    body.pop();
+                    body.extend_from_slice(b",]}");
+                }
+            }
+        }
+        *response.body_mut() = ResponseBody::Bytes(body);
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Status(status_code) => write!(f, "status {}", status_code.get_value()),
+            Fault::Drop => write!(f, "drop"),
+            Fault::Truncate => write!(f, "truncate"),
+            Fault::Malformed => write!(f, "malformed")
+        }
+    }
+}
+
+impl Rng {
+    /// Generator of the draws of the request at the index, independent of
+    /// the draws of the other requests
+    fn for_request(seed: u64, index: u64) -> Self {
+        Rng(Rng(seed ^ Rng(index).next()).next())
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly within `[0, 1)`
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Lets the first bytes through and silently discards the rest, so that the
+/// connection is closed partway through the response
+pub struct CutOff<W: Write> {
+    inner: W,
+    remaining: usize
+}
+
+impl<W: Write> CutOff<W> {
+    pub fn new(inner: W, limit: usize) -> Self {
+        Self { inner, remaining: limit }
+    }
+}
+
+impl<W: Write> Write for CutOff<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(buf.len());
+        }
+
+        let len = self.inner.write(&buf[..buf.len().min(self.remaining)])?;
+        self.remaining -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    fn request(method: &str, path: &str) -> Request {
+        let message = format!("{method} {path} HTTP/1.1\r\n\r\n");
+        Request::new(&mut message.as_bytes(), Instant::now(), &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn it_draws_the_same_faults_from_the_same_seed() {
+        let content = r#"{ "faults": [
+            { "route": "/users/*", "methods": ["post"], "rate": 0.5, "fault": "status", "status": [500, 503] },
+            { "rate": 0.2, "fault": "drop" }
+        ] }"#;
+        let draw = |chaos: &Chaos| -> Vec<Option<Fault>> {
+            (0..50).map(|_| chaos.pick(&request("POST", "/users/1"))).collect()
+        };
+
+        let faults = draw(&Chaos::parse(content, Some(7)).unwrap());
+        assert_eq!(faults, draw(&Chaos::parse(content, Some(7)).unwrap()));
+        assert!(faults.contains(&Some(Fault::Status(StatusCode::ServiceUnavailable))));
+        assert!(faults.contains(&Some(Fault::Drop)));
+        assert!(faults.contains(&None));
+    }
+
+    #[test]
+    fn it_only_injects_faults_into_matching_requests() {
+        let chaos = Chaos::parse(r#"{ "seed": 1, "faults": [{ "methods": ["GET"], "rate": 1, "fault": "truncate" }] }"#, None).unwrap();
+
+        assert_eq!(chaos.seed(), 1);
+        assert_eq!(chaos.pick(&request("GET", "/users")), Some(Fault::Truncate));
+        assert_eq!(chaos.pick(&request("POST", "/users")), None);
+        assert_eq!(chaos.pick(&request("GET", "/__health")), None);
+        assert!(Chaos::parse(r#"{ "faults": [{ "rate": 2, "fault": "drop" }] }"#, None).is_err());
+        assert!(Chaos::parse(r#"{ "faults": [{ "rate": 1, "fault": "status", "status": 299 }] }"#, None).is_err());
+    }
+
+    #[test]
+    fn it_only_accepts_error_status_codes() {
+        let parse = |status: &str| Chaos::parse(&format!(r#"{{ "faults": [{{ "rate": 1, "fault": "status", "status": {status} }}] }}"#), None);
+
+        assert!(parse("[404, 500, 503]").is_ok());
+        for status in ["200", "204", "304", "[500, 304]"] {
+            let err = parse(status).err().unwrap();
+            assert!(err.contains(r#"Expect "status" to be of 4xx or 5xx errors"#), "{err}");
+        }
+        assert!(parse("[]").err().unwrap().ends_with(r#"Expect "status" to be a status code or an array of them"#));
+    }
+
+    #[test]
+    fn it_draws_each_request_independently_of_the_others() {
+        let content = r#"{ "faults": [
+            { "route": "/users/*", "rate": 0.5, "fault": "status", "status": [500, 502, 503] },
+            { "route": "/users/*", "rate": 0.5, "fault": "truncate" },
+            { "route": "/posts", "rate": 0.5, "fault": "drop" }
+        ] }"#;
+
+        // Whatever the earlier requests drew, the nth request draws the same
+        let draw = |first: &str| -> Vec<Option<Fault>> {
+            let chaos = Chaos::parse(content, Some(3)).unwrap();
+            chaos.pick(&request("GET", first));
+            (0..20).map(|_| chaos.pick(&request("GET", "/posts"))).collect()
+        };
+        assert_eq!(draw("/users/1"), draw("/comments"));
+    }
+
+    #[test]
+    fn it_drops_the_validators_of_damaged_bodies() {
+        let response = || ResponseBuilder::new()
+            .set_status_code(StatusCode::Ok)
+            .set_content(r#"[{"id":1}]"#.to_owned())
+            .set_header("ETag", "\"abc\"")
+            .set_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .set_header("Accept-Ranges", "bytes")
+            .build();
+
+        for fault in [Fault::Truncate, Fault::Malformed] {
+            let mut damaged = response();
+            fault.apply(&mut damaged);
+            for name in ["ETag", "Last-Modified", "Accept-Ranges"] {
+                assert!(!damaged.headers().contains(name), "{fault} {name}");
+            }
+        }
+
+        let mut truncated = response();
+        Fault::Truncate.apply(&mut truncated);
+        assert_eq!(truncated.content_length(), Some(5));
+
+        let mut dropped = response();
+        Fault::Drop.apply(&mut dropped);
+        assert!(dropped.headers().contains("ETag"));
+    }
+}
+
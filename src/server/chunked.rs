@@ -0,0 +1,261 @@
+use std::io::{self, prelude::*};
+
+use super::request::RequestInitializationError;
+
+/// Size of the data carried by each chunk when streaming a response body
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Longest chunk size or trailer line accepted while decoding
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+
+/// Most bytes a chunked body may take with its framing, so that a body sent
+/// in tiny chunks can't take much more room than the size limit
+pub fn max_encoded_size(max_size: usize) -> usize {
+    max_size + max_size / 8 + 2 * MAX_LINE_SIZE as usize
+}
+
+/// Decodes a `Transfer-Encoding: chunked` message body, the reader is expected
+/// to be positioned right after the blank line which terminates the headers
+pub fn decode<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, RequestInitializationError> {
+    let mut reader = reader.take(max_encoded_size(max_size) as u64);
+
+    match decode_chunks(&mut reader, max_size) {
+        // Running out of the allowance is what cut the body short
+        Err(_) if reader.limit() == 0 => Err(RequestInitializationError::BodyTooLarge),
+        decoded => decoded
+    }
+}
+
+fn decode_chunks<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, RequestInitializationError> {
+    use RequestInitializationError::*;
+
+    let mut body: Vec<u8> = Vec::new();
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        reader.by_ref().take(MAX_LINE_SIZE).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(Malformed("Unexpected end of chunked body".to_owned()));
+        }
+
+        // Chunk extensions (";name=value") are allowed but we have no use of them
+        let size = line.trim_end().split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| Malformed(format!(r#"Invalid chunk size: "{size}""#)))?;
+
+        if size == 0 { break; }
+        if size > max_size - body.len() {
+            return Err(BodyTooLarge);
+        }
+
+        // Grown as the data arrives rather than trusting the claimed size
+        let start = body.len();
+        reader.by_ref().take(size as u64).read_to_end(&mut body)?;
+        if body.len() - start < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(Malformed("Expect chunk data to be followed by CRLF".to_owned()));
+        }
+    }
+
+    // Skip trailer fields until the terminating blank line
+    loop {
+        line.clear();
+        reader.by_ref().take(MAX_LINE_SIZE).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(Malformed("Unexpected end of chunked body trailers".to_owned()));
+        }
+        if line.trim_end().is_empty() { break; }
+    }
+
+    Ok(body)
+}
+
+/// Finds the end of a chunked body as it arrives, resuming after the chunks
+/// found complete by the previous scans
+#[derive(Debug, Default)]
+pub struct Scanner {
+    /// Where the first chunk or trailer not yet complete starts
+    offset: usize,
+    decoded: usize,
+    is_last_chunk_received: bool
+}
+
+impl Scanner {
+    /// Length of the chunked body at the start of the buffer including the trailers,
+    /// `None` while the buffer doesn't hold all of it yet. A body which is malformed or
+    /// already known to exceed the size limit counts as complete so that decoding reports the error
+    pub fn scan(&mut self, buffer: &[u8], max_size: usize) -> Option<usize> {
+        loop {
+            if self.offset > max_encoded_size(max_size) {
+                return Some(buffer.len());
+            }
+
+            let rest = &buffer[self.offset..];
+            let line_len = match rest.iter().position(|&byte| byte == b'\n') {
+                Some(position) => position + 1,
+                None if rest.len() as u64 > MAX_LINE_SIZE => return Some(buffer.len()),
+                None => return None
+            };
+            let line = String::from_utf8_lossy(&rest[..line_len]);
+
+            if self.is_last_chunk_received {
+                self.offset += line_len;
+                if line.trim_end().is_empty() {
+                    return Some(self.offset);
+                }
+                continue;
+            }
+
+            let size = line.trim_end().split(';').next().unwrap().trim();
+            let size = match usize::from_str_radix(size, 16) {
+                Ok(size) => size,
+                Err(_) => return Some(buffer.len())
+            };
+
+            if size == 0 {
+                self.is_last_chunk_received = true;
+                self.offset += line_len;
+                continue;
+            }
+            if size > max_size - self.decoded {
+                return Some(buffer.len());
+            }
+
+            // Data is followed by CRLF, which decoding checks
+            let end = self.offset + line_len + size + 2;
+            if buffer.len() < end {
+                return None;
+            }
+            self.decoded += size;
+            self.offset = end;
+        }
+    }
+}
+
+/// Writer which frames everything written into it as HTTP chunks,
+/// `finish` must be called to send the last chunk
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, buffer: Vec::with_capacity(CHUNK_SIZE) }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() { return Ok(()); }
+
+        write!(self.inner, "{:X}\r\n", self.buffer.len())?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.write_all(b"\r\n")?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_decodes_chunked_body() {
+        let mut message = "4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\n\r\n".as_bytes();
+
+        assert_eq!(
+            decode(&mut message, 1024),
+            Ok("Wikipedia in \r\n\r\nchunks.".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn it_returns_err_when_chunk_size_is_invalid() {
+        let mut message = "zz\r\nhello\r\n0\r\n\r\n".as_bytes();
+
+        assert_eq!(
+            decode(&mut message, 1024),
+            Err(RequestInitializationError::Malformed(r#"Invalid chunk size: "zz""#.to_owned()))
+        );
+    }
+
+    #[test]
+    fn it_rejects_chunked_body_larger_than_max_size() {
+        let message = "4\r\nWiki\r\n7FFFFFFF\r\n".as_bytes();
+
+        assert_eq!(decode(&mut &message[..], 16), Err(RequestInitializationError::BodyTooLarge));
+        assert_eq!(Scanner::default().scan(message, 16), Some(message.len()));
+    }
+
+    #[test]
+    fn it_rejects_chunked_body_with_too_much_framing() {
+        let message = "1;padding\r\nx\r\n".repeat(4 * 1024);
+        let message = format!("{message}0\r\n\r\n");
+
+        assert_eq!(decode(&mut message.as_bytes(), 8 * 1024), Err(RequestInitializationError::BodyTooLarge));
+        assert_eq!(Scanner::default().scan(message.as_bytes(), 8 * 1024), Some(message.len()));
+    }
+
+    #[test]
+    fn it_measures_chunked_body_only_once_complete() {
+        let message = "4\r\nWiki\r\n0\r\n\r\nGET /next".as_bytes();
+
+        assert_eq!(Scanner::default().scan(message, 1024), Some(message.len() - "GET /next".len()));
+        assert_eq!(Scanner::default().scan(&message[..6], 1024), None);
+        assert_eq!(Scanner::default().scan(&message[..13], 1024), None);
+    }
+
+    #[test]
+    fn it_resumes_scanning_where_it_left_off() {
+        let message = "4\r\nWiki\r\n6;ext=1\r\npedia \r\n0\r\nExpires: never\r\n\r\n".as_bytes();
+
+        let mut scanner = Scanner::default();
+        for len in 0..message.len() {
+            assert_eq!(scanner.scan(&message[..len], 1024), None);
+        }
+        assert_eq!(scanner.scan(message, 1024), Some(message.len()));
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_back_the_same_content() {
+        let content = "x".repeat(CHUNK_SIZE * 2 + 10);
+
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(content.as_bytes()).unwrap();
+        let encoded = writer.finish().unwrap();
+
+        assert!(encoded.starts_with(format!("{:X}\r\n", CHUNK_SIZE).as_bytes()));
+        assert!(encoded.ends_with(b"A\r\nxxxxxxxxxx\r\n0\r\n\r\n"));
+        assert_eq!(decode(&mut encoded.as_slice(), content.len()), Ok(content.into_bytes()));
+    }
+}
@@ -0,0 +1,300 @@
+mod deflate;
+
+use std::io::{self, prelude::*};
+use std::mem;
+
+use self::deflate::DeflateEncoder;
+use super::conditional;
+use super::headers::Headers;
+use super::request::Request;
+use super::response::{Response, ResponseBody};
+use super::status_code::StatusCode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format (RFC 1950), which is what HTTP calls "deflate"
+    Deflate
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate"
+        }
+    }
+
+    /// Picks the encoding the client prefers according to `Accept-Encoding`,
+    /// gzip wins over deflate when both are equally preferred
+    pub fn negotiate(headers: &Headers) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+
+        for value in headers.get_list("Accept-Encoding") {
+            let mut params = value.split(';');
+            let coding = params.next().unwrap().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let encoding = match coding.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" | "*" => Self::Gzip,
+                "deflate" => Self::Deflate,
+                _ => continue
+            };
+
+            let is_better = match best {
+                Some((_, best_quality)) if quality == best_quality => encoding == Self::Gzip,
+                Some((_, best_quality)) => quality > best_quality,
+                None => true
+            };
+            if quality > 0.0 && is_better {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// From 0 to 9, 0 turns compression off
+    pub level: u32,
+    /// Bodies smaller than this many bytes are sent as is
+    pub threshold: usize
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self { level: 6, threshold: 1024 }
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap().trim().to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || ["application/json", "application/javascript", "application/xml", "image/svg+xml"].contains(&mime.as_str())
+}
+
+impl CompressionConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.level > 0
+    }
+
+    /// Compresses the response body with the encoding negotiated with the client,
+    /// streamed bodies are compressed on the fly as they are written
+    pub fn apply(&self, request: &Request, response: &mut Response) {
+        if !self.is_enabled() { return; }
+
+        let status_code = *response.status_code();
+        // Ranges are of the identity encoding, compressing them would void Content-Range
+        if matches!(status_code, StatusCode::NoContent | StatusCode::PartialContent)
+            || response.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+
+        let content_type = response.headers().get("Content-Type").unwrap_or("");
+        if !is_compressible(content_type) { return; }
+
+        response.headers_mut().append("Vary", "Accept-Encoding");
+
+        let encoding = match Encoding::negotiate(&request.headers) {
+            Some(encoding) => encoding,
+            None => return
+        };
+
+        if !self.is_worth_compressing(request, response) { return; }
+
+        let headers = response.headers_mut();
+        if let Some(etag) = headers.get("ETag").map(conditional::weaken) {
+            headers.set("ETag", &etag);
+        }
+        // Nothing is sent, but the validators are those of the response which would have been
+        if status_code == StatusCode::NotModified { return; }
+        headers.set("Content-Encoding", encoding.as_str());
+
+        let level = self.level;
+        let body = match mem::replace(response.body_mut(), ResponseBody::Content(String::new())) {
+            ResponseBody::Content(content) => ResponseBody::Bytes(compress(encoding, level, content.as_bytes())),
+            ResponseBody::Bytes(bytes) => ResponseBody::Bytes(compress(encoding, level, &bytes)),
+            ResponseBody::Stream(write_body) => ResponseBody::Stream(Box::new(move |writer| {
+                let mut encoder = Encoder::new(writer, encoding, level)?;
+                write_body(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            })),
+            ResponseBody::File(file, len) => ResponseBody::Stream(Box::new(move |writer| {
+                let mut encoder = Encoder::new(writer, encoding, level)?;
+                io::copy(&mut file.take(len), &mut encoder)?;
+                encoder.finish().map(|_| ())
+            }))
+        };
+        *response.body_mut() = body;
+    }
+
+    /// Bodies smaller than the threshold are sent as is, and so are files for
+    /// HTTP/1.0 as they are compressed while streamed, which it can't frame
+    fn is_worth_compressing(&self, request: &Request, response: &Response) -> bool {
+        match response.body() {
+            ResponseBody::File(..) if request.version != "HTTP/1.1" => false,
+            _ => response.content_length().is_none_or(|len| len >= self.threshold)
+        }
+    }
+}
+
+pub fn compress(encoding: Encoding, level: u32, data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new(), encoding, level).unwrap();
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Writer which compresses everything written into it in the gzip (RFC 1952)
+/// or zlib (RFC 1950) format, `finish` must be called to write the trailer
+pub struct Encoder<W: Write> {
+    deflate: DeflateEncoder<W>,
+    encoding: Encoding,
+    crc: u32,
+    adler: Adler32,
+    size: u32
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(mut inner: W, encoding: Encoding, level: u32) -> io::Result<Self> {
+        match encoding {
+            // No file name nor modification time, OS unknown
+            Encoding::Gzip => inner.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?,
+            // 32K window with default compression level
+            Encoding::Deflate => inner.write_all(&[0x78, 0x9c])?
+        }
+
+        Ok(Self {
+            deflate: DeflateEncoder::new(inner, level),
+            encoding,
+            crc: 0,
+            adler: Adler32::new(),
+            size: 0
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = self.deflate.finish()?;
+
+        match self.encoding {
+            Encoding::Gzip => {
+                inner.write_all(&self.crc.to_le_bytes())?;
+                inner.write_all(&self.size.to_le_bytes())?;
+            },
+            Encoding::Deflate => inner.write_all(&self.adler.value().to_be_bytes())?
+        }
+
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.deflate.write(buf)?;
+
+        match self.encoding {
+            Encoding::Gzip => {
+                self.crc = crc32(self.crc, &buf[..len]);
+                self.size = self.size.wrapping_add(len as u32);
+            },
+            Encoding::Deflate => self.adler.update(&buf[..len])
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate.flush()
+    }
+}
+
+/// Continues CRC-32 (IEEE) checksum of previous data with more data
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+struct Adler32 {
+    a: u32,
+    b: u32
+}
+
+impl Adler32 {
+    const MODULO: u32 = 65521;
+
+    fn new() -> Self {
+        Self { a: 1, b: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        // Sums stay below overflow for this many bytes before taking the modulo
+        for chunk in data.chunks(5552) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= Self::MODULO;
+            self.b %= Self::MODULO;
+        }
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_computes_checksums() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), 0xCBF43926);
+
+        let mut adler = Adler32::new();
+        adler.update(b"Wikipedia");
+        assert_eq!(adler.value(), 0x11E60398);
+    }
+
+    #[test]
+    fn it_negotiates_encoding_by_quality() {
+        let mut headers = Headers::new();
+        assert_eq!(Encoding::negotiate(&headers), None);
+
+        headers.set("Accept-Encoding", "deflate, gzip");
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Gzip));
+
+        headers.set("Accept-Encoding", "gzip;q=0.5, deflate;q=0.8, br");
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Deflate));
+
+        headers.set("Accept-Encoding", "gzip;q=0, identity");
+        assert_eq!(Encoding::negotiate(&headers), None);
+    }
+
+    #[test]
+    fn it_wraps_compressed_data_in_gzip_format() {
+        let data = r#"[{"id":1,"name":"Alice"},{"id":2,"name":"Alice"}]"#.repeat(100);
+        let compressed = compress(Encoding::Gzip, 6, data.as_bytes());
+
+        assert_eq!(&compressed[..3], &[0x1f, 0x8b, 8]);
+        assert_eq!(&compressed[compressed.len() - 8..compressed.len() - 4], &crc32(0, data.as_bytes()).to_le_bytes());
+        assert_eq!(&compressed[compressed.len() - 4..], &(data.len() as u32).to_le_bytes());
+        assert!(compressed.len() < data.len() / 10);
+    }
+}
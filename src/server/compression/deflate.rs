@@ -0,0 +1,381 @@
+use std::io::{self, prelude::*};
+
+/// Input is compressed in blocks of this size, matches never cross blocks
+const BLOCK_SIZE: usize = 64 * 1024;
+const MAX_STORED_BLOCK_SIZE: usize = 65535;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+/// Writes values of arbitrary bit length least significant bit first, as DEFLATE expects
+struct BitWriter<W: Write> {
+    inner: W,
+    bits: u64,
+    bit_count: u32
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, bits: 0, bit_count: 0 }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) -> io::Result<()> {
+        self.bits |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+
+        while self.bit_count >= 8 {
+            self.inner.write_all(&[self.bits as u8])?;
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+        Ok(())
+    }
+
+    /// Huffman codes are defined most significant bit first
+    fn write_code(&mut self, code: u32, length: u32) -> io::Result<()> {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.write_bits(reversed, length)
+    }
+
+    fn align_to_byte(&mut self) -> io::Result<()> {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count)?;
+        }
+        Ok(())
+    }
+
+    /// Writes literal or length symbol with the fixed Huffman code
+    fn write_literal(&mut self, symbol: u16) -> io::Result<()> {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8)
+        }
+    }
+
+    fn write_length(&mut self, length: u16) -> io::Result<()> {
+        let code = LENGTH_BASE.iter().rposition(|&base| base <= length).unwrap();
+        self.write_literal(257 + code as u16)?;
+
+        let extra_bits = LENGTH_EXTRA_BITS[code] as u32;
+        if extra_bits > 0 {
+            self.write_bits((length - LENGTH_BASE[code]) as u32, extra_bits)?;
+        }
+        Ok(())
+    }
+
+    fn write_distance(&mut self, distance: u16) -> io::Result<()> {
+        let code = DISTANCE_BASE.iter().rposition(|&base| base <= distance).unwrap();
+        self.write_code(code as u32, 5)?;
+
+        let extra_bits = DISTANCE_EXTRA_BITS[code] as u32;
+        if extra_bits > 0 {
+            self.write_bits((distance - DISTANCE_BASE[code]) as u32, extra_bits)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streaming raw DEFLATE encoder (RFC 1951), level 0 emits stored blocks and
+/// levels 1 to 9 search for matches longer the higher the level is
+pub struct DeflateEncoder<W: Write> {
+    writer: BitWriter<W>,
+    buffer: Vec<u8>,
+    level: u32
+}
+
+impl<W: Write> DeflateEncoder<W> {
+    pub fn new(inner: W, level: u32) -> Self {
+        Self {
+            writer: BitWriter::new(inner),
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            level: level.min(9)
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() { return Ok(()); }
+
+        let block = std::mem::take(&mut self.buffer);
+        if self.level == 0 {
+            self.write_stored(&block)?;
+        } else {
+            // Compressed aside, data with few repetitions such as already compressed
+            // content comes out larger with the fixed codes than stored as is
+            let mut compressed = BitWriter {
+                inner: Vec::new(),
+                bits: self.writer.bits,
+                bit_count: self.writer.bit_count
+            };
+            write_fixed(&mut compressed, &block, self.level)?;
+
+            let compressed_bits = compressed.inner.len() * 8 + compressed.bit_count as usize;
+            if compressed_bits < self.stored_bits(block.len()) {
+                self.writer.inner.write_all(&compressed.inner)?;
+                self.writer.bits = compressed.bits;
+                self.writer.bit_count = compressed.bit_count;
+            } else {
+                self.write_stored(&block)?;
+            }
+        }
+
+        self.buffer = block;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn write_stored(&mut self, data: &[u8]) -> io::Result<()> {
+        for block in data.chunks(MAX_STORED_BLOCK_SIZE) {
+            // BFINAL = 0, BTYPE = 00
+            self.writer.write_bits(0, 3)?;
+            self.writer.align_to_byte()?;
+
+            let len = block.len() as u16;
+            self.writer.inner.write_all(&len.to_le_bytes())?;
+            self.writer.inner.write_all(&(!len).to_le_bytes())?;
+            self.writer.inner.write_all(block)?;
+        }
+        Ok(())
+    }
+
+    /// Most bits the data takes as stored blocks, including the bits pending in the writer
+    fn stored_bits(&self, len: usize) -> usize {
+        // Block header, padding to the byte boundary, then LEN and NLEN
+        let overhead = 3 + 7 + 32;
+        self.writer.bit_count as usize + len.div_ceil(MAX_STORED_BLOCK_SIZE) * overhead + len * 8
+    }
+
+    /// Compresses what is left and terminates the stream with an empty final block
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+
+        // BFINAL = 1, BTYPE = 01, followed by end of block
+        self.writer.write_bits(0b011, 3)?;
+        self.writer.write_literal(END_OF_BLOCK)?;
+        self.writer.align_to_byte()?;
+
+        Ok(self.writer.inner)
+    }
+}
+
+/// Compresses the data as one block with the fixed Huffman codes, searching
+/// for matches longer the higher the level is
+fn write_fixed<W: Write>(writer: &mut BitWriter<W>, data: &[u8], level: u32) -> io::Result<()> {
+    // BFINAL = 0, BTYPE = 01
+    writer.write_bits(0b010, 3)?;
+
+    let max_chain = 4 << level;
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let hash = |pos: usize| -> usize {
+        let value = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+        (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(pos)];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < max_chain {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len == max_len { break; }
+                }
+
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        let step = if best_len >= MIN_MATCH {
+            writer.write_length(best_len as u16)?;
+            writer.write_distance(best_dist as u16)?;
+            best_len
+        } else {
+            writer.write_literal(data[pos] as u16)?;
+            1
+        };
+
+        for (index, link) in prev.iter_mut().enumerate().skip(pos).take(step) {
+            if index + MIN_MATCH <= data.len() {
+                let key = hash(index);
+                *link = head[key];
+                head[key] = index;
+            }
+        }
+        pos += step;
+    }
+
+    writer.write_literal(END_OF_BLOCK)
+}
+
+impl<W: Write> Write for DeflateEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Reads bits least significant first, as the encoder writes them
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: usize) -> u32 {
+            (0..count).fold(0, |value, index| {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                self.pos += 1;
+                value | (bit as u32) << index
+            })
+        }
+
+        /// Huffman codes are read most significant bit first
+        fn code(&mut self, length: usize) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn literal(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0b0010111 { return 256 + code as u16; }
+
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xBF => (code - 0x30) as u16,
+                0xC0..=0xC7 => (280 + code - 0xC0) as u16,
+                _ => (144 + (code << 1 | self.bits(1)) - 0x190) as u16
+            }
+        }
+    }
+
+    /// Decodes the stored and fixed Huffman blocks the encoder produces
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, pos: 0 };
+        let mut output = Vec::new();
+
+        loop {
+            let is_final = reader.bits(1) == 1;
+            match reader.bits(2) {
+                0b00 => {
+                    reader.pos = reader.pos.div_ceil(8) * 8;
+                    let len = reader.bits(16) as usize;
+                    assert_eq!(reader.bits(16) as usize, !len & 0xFFFF);
+                    let start = reader.pos / 8;
+                    output.extend_from_slice(&data[start..start + len]);
+                    reader.pos += len * 8;
+                },
+                0b01 => loop {
+                    let symbol = reader.literal();
+                    if symbol < 256 {
+                        output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == END_OF_BLOCK { break; }
+
+                    let code = (symbol - 257) as usize;
+                    let length = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA_BITS[code] as usize) as usize;
+                    let code = reader.code(5) as usize;
+                    let distance = DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA_BITS[code] as usize) as usize;
+                    for _ in 0..length {
+                        output.push(output[output.len() - distance]);
+                    }
+                },
+                btype => panic!("Unexpected block type {btype}")
+            }
+            if is_final { return output; }
+        }
+    }
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Bytes without repetitions a match could use, as in already compressed content
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9E3779B9;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_decodes_back_what_it_encoded_at_every_level() {
+        let json = r#"[{"id":1,"name":"Alice"},{"id":2,"name":"Bob"}]"#.repeat(3000);
+        let mut mixed = json.clone().into_bytes();
+        mixed.extend(noise(BLOCK_SIZE + 100));
+
+        for level in 0..=9 {
+            assert_eq!(inflate(&deflate(json.as_bytes(), level)), json.as_bytes(), "level {level}");
+            assert_eq!(inflate(&deflate(&mixed, level)), mixed, "level {level}");
+        }
+        assert_eq!(inflate(&deflate(b"", 6)), b"");
+    }
+
+    #[test]
+    fn it_stores_blocks_which_dont_compress() {
+        let data = noise(BLOCK_SIZE * 2);
+        let compressed = deflate(&data, 9);
+
+        // Each block takes two stored blocks of 5 bytes of header, then the final empty block
+        assert!(compressed.len() <= data.len() + 4 * 5 + 2, "{} bytes", compressed.len());
+        assert_eq!(inflate(&compressed), data);
+    }
+}
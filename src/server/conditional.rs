@@ -0,0 +1,123 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::http_date;
+use super::request::{Request, RequestMethod};
+
+/// Validators of the representation a response carries, which clients send
+/// back in conditional requests to learn whether it has changed since
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: SystemTime
+}
+
+impl Validators {
+    pub fn new(checksum: u64, last_modified: SystemTime) -> Self {
+        Self { etag: etag(checksum), last_modified }
+    }
+
+    pub fn last_modified_date(&self) -> String {
+        http_date::format(self.last_modified)
+    }
+
+    /// Whether a GET or HEAD request can be answered with `304 Not Modified`.
+    /// `If-None-Match` takes precedence, `If-Modified-Since` is only looked
+    /// at when the client didn't send any entity tag
+    pub fn is_not_modified(&self, request: &Request) -> bool {
+        if !matches!(request.method, RequestMethod::GET | RequestMethod::HEAD) {
+            return false;
+        }
+
+        let if_none_match = request.headers.get_list("If-None-Match");
+        if !if_none_match.is_empty() {
+            return if_none_match
+                .iter()
+                .any(|etag| *etag == "*" || is_weak_match(etag, &self.etag));
+        }
+
+        match request.headers.get("If-Modified-Since").and_then(http_date::parse) {
+            // Dates only have the precision of seconds
+            Some(since) => seconds(self.last_modified) <= seconds(since),
+            None => false
+        }
+    }
+
+    /// Whether the `Range` of the request still applies according to `If-Range`,
+    /// which holds either an entity tag compared strongly or a date
+    pub fn is_range_current(&self, request: &Request) -> bool {
+        let if_range = match request.headers.get("If-Range") {
+            Some(if_range) => if_range,
+            None => return true
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            return if_range == self.etag;
+        }
+        http_date::parse(if_range).is_some_and(|date| seconds(date) == seconds(self.last_modified))
+    }
+}
+
+/// Strong entity tag of the content with the checksum
+pub fn etag(checksum: u64) -> String {
+    format!(r#""{checksum:016x}""#)
+}
+
+/// Whether a write may go ahead according to `If-Match`, which only lets it
+/// through when the current entity tag is listed. The server only weakens the
+/// tags of the responses it compresses, whose content is still the one the tag
+/// was computed from, so the opaque tags are compared regardless of `W/`.
+/// Requests without the header pass
+pub fn is_match(request: &Request, etag: &str) -> bool {
+    let if_match = request.headers.get_list("If-Match");
+    if_match.is_empty() || if_match.iter().any(|tag| *tag == "*" || is_weak_match(tag, etag))
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Weak comparison, entity tags match regardless of either being weak
+fn is_weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// Marks the entity tag as weak, e.g. after the body got compressed and
+/// isn't byte-for-byte the representation the tag was computed from
+pub fn weaken(etag: &str) -> String {
+    if etag.starts_with("W/") { etag.to_owned() } else { format!("W/{etag}") }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn request(headers: &str) -> Request {
+        let message = format!("GET /users HTTP/1.1\r\n{headers}\r\n");
+        Request::new(&mut message.as_bytes(), Instant::now(), &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn it_matches_if_none_match_before_if_modified_since() {
+        let validators = Validators::new(0xabc, UNIX_EPOCH + Duration::from_secs(784111777));
+
+        assert!(validators.is_not_modified(&request("If-None-Match: \"1\", W/\"0000000000000abc\"\r\n")));
+        assert!(validators.is_not_modified(&request("If-None-Match: *\r\n")));
+        assert!(!validators.is_not_modified(&request(
+            "If-None-Match: \"1\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"
+        )));
+        assert!(validators.is_not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n")));
+        assert!(!validators.is_not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n")));
+    }
+
+    #[test]
+    fn it_compares_the_opaque_tags_for_if_match() {
+        let etag = etag(0xabc);
+
+        assert!(is_match(&request(""), &etag));
+        assert!(is_match(&request("If-Match: *\r\n"), &etag));
+        assert!(is_match(&request("If-Match: \"1\", \"0000000000000abc\"\r\n"), &etag));
+        assert!(is_match(&request("If-Match: W/\"0000000000000abc\"\r\n"), &etag));
+        assert!(!is_match(&request("If-Match: \"1\"\r\n"), &etag));
+        assert!(!is_match(&request("If-Match: W/\"1\"\r\n"), &etag));
+    }
+}
@@ -1,31 +1,89 @@
 use std::path::PathBuf;
+use std::time::Duration;
+
+use super::access_log::{AccessLogConfig, LogFormat};
+use super::compression::CompressionConfig;
+use super::cors::CorsConfig;
+use super::delay::{Delay, DelayConfig};
+use super::request::RequestLimits;
+use super::rewrite::Pattern;
+
+/// Server core which accepts connections and drives the request handling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// Every connection is handled by one thread of the pool from start to end
+    ThreadPool,
+    /// Connections are multiplexed onto few threads with epoll (Linux only)
+    EventLoop
+}
 
 #[derive(Debug)]
 pub struct Config {
-    pub jsondb_dir: PathBuf,
+    pub jsondb_path: PathBuf,
     pub pool_capacity: Option<usize>,
-    pub port: Option<usize>,
+    pub engine: Engine,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Path of Unix domain socket to listen on instead of TCP
+    pub socket: Option<PathBuf>,
     pub verbose: bool,
-    pub dry_run: bool
+    pub dry_run: bool,
+    /// Reject every request which would change the data, nothing is written
+    pub read_only: bool,
+    /// Maintain a `_version` field in every record, bumped on each write
+    pub versioned: bool,
+    /// Reject PUT, PATCH and DELETE without `If-Match` with 428
+    pub require_if_match: bool,
+    /// Create the collection on a POST to an unknown path instead of answering 404
+    pub auto_create: bool,
+    /// File of the rules rewriting request paths before they are routed
+    pub routes: Option<PathBuf>,
+    /// Directory of files served for the paths which don't match any collection
+    pub static_dir: Option<PathBuf>,
+    pub delay: DelayConfig,
+    /// File of the faults injected into a share of the requests
+    pub chaos: Option<PathBuf>,
+    /// Seed the faults are drawn from, overriding the one of the file
+    pub chaos_seed: Option<u64>,
+    pub cors: CorsConfig,
+    pub limits: RequestLimits,
+    pub compression: CompressionConfig,
+    pub access_log: AccessLogConfig
 }
 
 impl Config {
     pub fn from(args: Vec<String>) -> Result<Self, String> {
         let len = args.len();
         if len < 2 {
-            return Err("Please specify the entry folder for all json db files".to_owned());
+            return Err("Please specify the folder of the json db files or a single json db file".to_owned());
         }
 
         let mut config = Self {
-            jsondb_dir: PathBuf::from(args[1].to_owned()),
+            jsondb_path: PathBuf::from(args[1].to_owned()),
             pool_capacity: None,
+            engine: Engine::ThreadPool,
+            host: None,
             port: None,
+            socket: None,
             verbose: false,
-            dry_run: false
+            dry_run: false,
+            read_only: false,
+            versioned: false,
+            require_if_match: false,
+            auto_create: false,
+            routes: None,
+            static_dir: None,
+            delay: DelayConfig::default(),
+            chaos: None,
+            chaos_seed: None,
+            cors: CorsConfig::default(),
+            limits: RequestLimits::default(),
+            compression: CompressionConfig::default(),
+            access_log: AccessLogConfig::default()
         };
 
-        for i in 2..len {
-            config.parse_option(args[i].clone())?;
+        for arg in args.iter().skip(2) {
+            config.parse_option(arg.clone())?;
         }
 
         Ok(config)
@@ -46,15 +104,44 @@ impl Config {
                 self.pool_capacity = Some(value.parse::<usize>().unwrap());
                 Ok(())
             },
+            "--engine" => {
+                self.engine = match value {
+                    "threads" => Engine::ThreadPool,
+                    "evloop" if cfg!(target_os = "linux") => Engine::EventLoop,
+                    "evloop" => return Err(r#"The "evloop" engine is only supported on Linux"#.to_owned()),
+                    _ => return Err(r#"The option "--engine" only accepts "threads" or "evloop" value"#.to_owned())
+                };
+                Ok(())
+            },
+            "--host" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--host" expects a hostname or IP address such as "0.0.0.0" or "::""#.to_owned());
+                }
+
+                self.host = Some(value.to_owned());
+                Ok(())
+            },
             "--port" => {
-                self.port = Some(value.parse::<usize>().unwrap());
+                let value = value.parse::<u16>().map_err(|_| {
+                    format!(r#"The option "--port" expects a number between 0 and 65535, instead got: "{value}""#)
+                })?;
+
+                self.port = Some(value);
+                Ok(())
+            },
+            "--socket" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--socket" expects a file path such as "/tmp/rjs.sock""#.to_owned());
+                }
+
+                self.socket = Some(PathBuf::from(value));
                 Ok(())
             },
             "--verbose" => {
                 let value = match value {
                     "true" => true,
                     "false" => false,
-                    _ => return Err(format!(r#"The option "--verbose" only accepts "true" or "false" value"#))
+                    _ => return Err(r#"The option "--verbose" only accepts "true" or "false" value"#.to_owned())
                 };
 
                 self.verbose = value;
@@ -64,15 +151,282 @@ impl Config {
                 let value = match value {
                     "true" => true,
                     "false" => false,
-                    _ => return Err(format!(r#"The option "--dry-run" only accepts "true" or "false" value"#))
+                    _ => return Err(r#"The option "--dry-run" only accepts "true" or "false" value"#.to_owned())
                 };
 
                 self.dry_run = value;
                 Ok(())
             },
+            "--read-only" => {
+                let value = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(r#"The option "--read-only" only accepts "true" or "false" value"#.to_owned())
+                };
+
+                self.read_only = value;
+                Ok(())
+            },
+            "--versioned" => {
+                let value = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(r#"The option "--versioned" only accepts "true" or "false" value"#.to_owned())
+                };
+
+                self.versioned = value;
+                Ok(())
+            },
+            "--require-if-match" => {
+                let value = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(r#"The option "--require-if-match" only accepts "true" or "false" value"#.to_owned())
+                };
+
+                self.require_if_match = value;
+                Ok(())
+            },
+            "--auto-create" => {
+                let value = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(r#"The option "--auto-create" only accepts "true" or "false" value"#.to_owned())
+                };
+
+                self.auto_create = value;
+                Ok(())
+            },
+            "--header-timeout" => {
+                self.limits.header_timeout = parse_duration(value)
+                    .ok_or_else(|| invalid_duration("--header-timeout", value))?;
+                Ok(())
+            },
+            "--body-timeout" => {
+                self.limits.body_timeout = parse_duration(value)
+                    .ok_or_else(|| invalid_duration("--body-timeout", value))?;
+                Ok(())
+            },
+            "--write-timeout" => {
+                self.limits.write_timeout = parse_duration(value)
+                    .ok_or_else(|| invalid_duration("--write-timeout", value))?;
+                Ok(())
+            },
+            "--max-headers" => {
+                self.limits.max_headers = value.parse::<usize>().map_err(|_| {
+                    format!(r#"The option "--max-headers" expects a number, instead got: "{value}""#)
+                })?;
+                Ok(())
+            },
+            "--max-header-size" => {
+                self.limits.max_header_size = parse_size(value)
+                    .ok_or_else(|| invalid_size("--max-header-size", value))?;
+                Ok(())
+            },
+            "--max-body-size" => {
+                self.limits.max_body_size = parse_size(value)
+                    .ok_or_else(|| invalid_size("--max-body-size", value))?;
+                Ok(())
+            },
+            "--compression-level" => {
+                self.compression.level = match value.parse::<u32>() {
+                    Ok(level) if level <= 9 => level,
+                    _ => return Err(format!(r#"The option "--compression-level" expects a number from 0 to 9, instead got: "{value}""#))
+                };
+                Ok(())
+            },
+            "--compression-threshold" => {
+                self.compression.threshold = parse_size(value)
+                    .ok_or_else(|| invalid_size("--compression-threshold", value))?;
+                Ok(())
+            },
+            "--log-format" => {
+                self.access_log.format = match value {
+                    "common" => LogFormat::Common,
+                    "combined" => LogFormat::Combined,
+                    "json" => LogFormat::Json,
+                    _ => return Err(r#"The option "--log-format" only accepts "common", "combined" or "json" value"#.to_owned())
+                };
+                Ok(())
+            },
+            "--routes" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--routes" expects a file path such as "routes.json""#.to_owned());
+                }
+
+                self.routes = Some(PathBuf::from(value));
+                Ok(())
+            },
+            "--static" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--static" expects a directory path such as "./public""#.to_owned());
+                }
+
+                self.static_dir = Some(PathBuf::from(value));
+                Ok(())
+            },
+            "--chaos" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--chaos" expects a file path such as "chaos.json""#.to_owned());
+                }
+
+                self.chaos = Some(PathBuf::from(value));
+                Ok(())
+            },
+            "--chaos-seed" => {
+                self.chaos_seed = Some(value.parse::<u64>().map_err(|_| {
+                    format!(r#"The option "--chaos-seed" expects a non-negative integer, instead got: "{value}""#)
+                })?);
+                Ok(())
+            },
+            "--delay" => {
+                self.delay.global = Some(Delay::parse(value).ok_or_else(|| invalid_delay("--delay", value))?);
+                Ok(())
+            },
+            "--route-delay" => {
+                let (pattern, delay) = value.rsplit_once('=').ok_or_else(|| {
+                    r#"The option "--route-delay" expects a path pattern and a delay such as "/users/*=800ms""#.to_owned()
+                })?;
+                let delay = Delay::parse(delay).ok_or_else(|| invalid_delay("--route-delay", delay))?;
+
+                self.delay.routes.push((Pattern::new(pattern), delay));
+                Ok(())
+            },
+            "--log-file" => {
+                if value.is_empty() {
+                    return Err(r#"The option "--log-file" expects a file path such as "access.log""#.to_owned());
+                }
+
+                self.access_log.file = Some(PathBuf::from(value));
+                Ok(())
+            },
+            "--log-max-size" => {
+                self.access_log.max_size = parse_size(value)
+                    .ok_or_else(|| invalid_size("--log-max-size", value))?;
+                Ok(())
+            },
+            "--log-max-files" => {
+                self.access_log.max_files = value.parse::<usize>().map_err(|_| {
+                    format!(r#"The option "--log-max-files" expects a number of files, instead got: "{value}""#)
+                })?;
+                Ok(())
+            },
+            "--cors-origins" => {
+                self.cors.allowed_origins = parse_list(value);
+                Ok(())
+            },
+            "--cors-methods" => {
+                self.cors.allowed_methods = parse_list(value)
+                    .into_iter()
+                    .map(|method| method.to_ascii_uppercase())
+                    .collect();
+                Ok(())
+            },
+            "--cors-headers" => {
+                self.cors.allowed_headers = Some(parse_list(value));
+                Ok(())
+            },
+            "--cors-credentials" => {
+                let value = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(r#"The option "--cors-credentials" only accepts "true" or "false" value"#.to_owned())
+                };
+
+                self.cors.allow_credentials = value;
+                Ok(())
+            },
+            "--cors-max-age" => {
+                let value = value.parse::<usize>().map_err(|_| {
+                    format!(r#"The option "--cors-max-age" expects number of seconds, instead got: "{value}""#)
+                })?;
+
+                self.cors.max_age = Some(value);
+                Ok(())
+            },
             _ => {
                 Err(format!("Unrecognized option: {key}"))
             }
         }
     }
 }
+
+/// Parses comma-separated option value such as `--cors-origins=http://a.com,http://b.com`
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+/// Parses duration such as `800ms`, `10s` or `2m`, plain numbers are milliseconds
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "ms")
+    };
+    let number = number.parse::<u64>().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number * 60)),
+        _ => None
+    }
+}
+
+fn invalid_duration(option: &str, value: &str) -> String {
+    format!(r#"The option "{option}" expects a duration such as "800ms" or "10s", instead got: "{value}""#)
+}
+
+fn invalid_delay(option: &str, value: &str) -> String {
+    format!(r#"The option "{option}" expects a delay such as "800ms" or "200..1500ms", instead got: "{value}""#)
+}
+
+/// Parses size such as `512`, `8KB` or `10MB` into bytes
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "B")
+    };
+    let number = number.parse::<usize>().ok()?;
+
+    match unit.to_ascii_uppercase().as_str() {
+        "B" => Some(number),
+        "KB" => number.checked_mul(1024),
+        "MB" => number.checked_mul(1024 * 1024),
+        "GB" => number.checked_mul(1024 * 1024 * 1024),
+        _ => None
+    }
+}
+
+fn invalid_size(option: &str, value: &str) -> String {
+    format!(r#"The option "{option}" expects a size such as "8KB" or "10MB", instead got: "{value}""#)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_durations_with_units() {
+        assert_eq!(parse_duration("800ms"), Some(Duration::from_millis(800)));
+        assert_eq!(parse_duration("10s"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("250"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("10h"), None);
+        assert_eq!(parse_duration("fast"), None);
+    }
+
+    #[test]
+    fn it_parses_sizes_with_units() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("8KB"), Some(8 * 1024));
+        assert_eq!(parse_size("10mb"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1TB"), None);
+    }
+}
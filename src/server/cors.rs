@@ -0,0 +1,230 @@
+use super::request::{Request, RequestMethod};
+use super::response::{Response, ResponseBuilder};
+use super::status_code::StatusCode;
+
+const DEFAULT_ALLOWED_METHODS: [&str; 6] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+/// Cross-Origin Resource Sharing policy, disabled unless any origin is allowed
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the server, `*` allows any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in the actual request. When unset, whatever headers the
+    /// preflight asks for are allowed, since a mock server shouldn't be the
+    /// reason a client request fails
+    pub allowed_headers: Option<Vec<String>>,
+    pub allow_credentials: bool,
+    /// Seconds for browsers to cache the preflight result
+    pub max_age: Option<usize>
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: DEFAULT_ALLOWED_METHODS.iter().map(|method| method.to_string()).collect(),
+            allowed_headers: None,
+            allow_credentials: false,
+            max_age: None
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, `None` if the origin isn't allowed
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        let allows_any = self.allowed_origins.iter().any(|allowed| allowed == "*");
+
+        // Browsers reject the wildcard for credentialed requests, so echo the origin instead
+        if allows_any && !self.allow_credentials {
+            return Some("*".to_owned());
+        }
+
+        if allows_any || self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            return Some(origin.to_owned());
+        }
+
+        None
+    }
+
+    pub fn is_preflight(&self, request: &Request) -> bool {
+        self.is_enabled()
+            && matches!(request.method, RequestMethod::OPTIONS)
+            && request.headers.contains("Origin")
+            && request.headers.contains("Access-Control-Request-Method")
+    }
+
+    /// Stops allowing the methods which would change the data, for a read-only server
+    pub fn allow_reads_only(&mut self) {
+        self.allowed_methods.retain(|method| !RequestMethod::from(method.as_str()).is_write());
+    }
+
+    /// Answers the `OPTIONS` preflight request sent by browsers ahead of the actual request
+    pub fn preflight(&self, request: &Request) -> Response {
+        let origin = request.headers.get("Origin").unwrap_or("");
+        let requested_method = request.headers.get("Access-Control-Request-Method").unwrap_or("");
+
+        let allow_origin = self.allow_origin(origin);
+        let allows_method = self.allowed_methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(requested_method));
+
+        let builder = ResponseBuilder::new()
+            .set_protocol(request.version.clone())
+            .set_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+
+        let allow_origin = match allow_origin {
+            Some(allow_origin) if allows_method => allow_origin,
+            _ => return builder
+                .set_status_code(StatusCode::Forbidden)
+                .set_content(r#"{ "message": "CORS preflight rejected" }"#.to_owned())
+                .set_content_type("application/json".to_owned())
+                .build()
+        };
+
+        let allowed_headers = match &self.allowed_headers {
+            Some(headers) => headers.join(", "),
+            None => request.headers.get_list("Access-Control-Request-Headers").join(", ")
+        };
+
+        let mut builder = builder
+            .set_status_code(StatusCode::NoContent)
+            .set_header("Access-Control-Allow-Origin", &allow_origin)
+            .set_header("Access-Control-Allow-Methods", &self.allowed_methods.join(", "));
+
+        if !allowed_headers.is_empty() {
+            builder = builder.set_header("Access-Control-Allow-Headers", &allowed_headers);
+        }
+        if self.allow_credentials {
+            builder = builder.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = self.max_age {
+            builder = builder.set_header("Access-Control-Max-Age", &max_age.to_string());
+        }
+
+        builder.build()
+    }
+
+    /// Adds the CORS headers to the response of an actual (non-preflight) request
+    pub fn apply(&self, request: &Request, response: &mut Response) {
+        if !self.is_enabled() { return; }
+
+        let headers = response.headers_mut();
+        headers.append("Vary", "Origin");
+
+        let origin = match request.headers.get("Origin") {
+            Some(origin) => origin,
+            None => return
+        };
+
+        if let Some(allow_origin) = self.allow_origin(origin) {
+            headers.set("Access-Control-Allow-Origin", &allow_origin);
+            if self.allow_credentials {
+                headers.set("Access-Control-Allow-Credentials", "true");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let message = format!("{method} /users HTTP/1.1\r\n{headers}\r\n");
+        Request::new(&mut message.as_bytes(), Instant::now(), &Default::default()).unwrap()
+    }
+
+    fn config(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_accepts_and_rejects_preflights() {
+        let cors = config(&["http://app.test"]);
+
+        let accepted = request("OPTIONS", "Origin: http://app.test\r\nAccess-Control-Request-Method: PATCH\r\n");
+        assert!(cors.is_preflight(&accepted));
+        let response = cors.preflight(&accepted);
+        assert_eq!(*response.status_code(), StatusCode::NoContent);
+        assert_eq!(response.headers().get("Access-Control-Allow-Origin"), Some("http://app.test"));
+        assert_eq!(response.headers().get("Access-Control-Allow-Methods"), Some("GET, HEAD, POST, PUT, PATCH, DELETE"));
+
+        let other_origin = request("OPTIONS", "Origin: http://evil.test\r\nAccess-Control-Request-Method: GET\r\n");
+        assert_eq!(*cors.preflight(&other_origin).status_code(), StatusCode::Forbidden);
+
+        let other_method = request("OPTIONS", "Origin: http://app.test\r\nAccess-Control-Request-Method: TRACE\r\n");
+        assert_eq!(*cors.preflight(&other_method).status_code(), StatusCode::Forbidden);
+
+        assert!(!cors.is_preflight(&request("OPTIONS", "Origin: http://app.test\r\n")));
+        assert!(!config(&[]).is_preflight(&accepted));
+    }
+
+    #[test]
+    fn it_echoes_the_origin_for_wildcard_with_credentials() {
+        let mut cors = config(&["*"]);
+        let preflight = request("OPTIONS", "Origin: http://app.test\r\nAccess-Control-Request-Method: GET\r\n");
+        assert_eq!(cors.preflight(&preflight).headers().get("Access-Control-Allow-Origin"), Some("*"));
+
+        cors.allow_credentials = true;
+        let response = cors.preflight(&preflight);
+        assert_eq!(response.headers().get("Access-Control-Allow-Origin"), Some("http://app.test"));
+        assert_eq!(response.headers().get("Access-Control-Allow-Credentials"), Some("true"));
+    }
+
+    #[test]
+    fn it_reflects_requested_headers_unless_configured() {
+        let mut cors = config(&["*"]);
+        let preflight = request(
+            "OPTIONS",
+            "Origin: http://app.test\r\nAccess-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: X-Token, Content-Type\r\n"
+        );
+        assert_eq!(cors.preflight(&preflight).headers().get("Access-Control-Allow-Headers"), Some("X-Token, Content-Type"));
+
+        cors.allowed_headers = Some(vec!["Content-Type".to_owned()]);
+        assert_eq!(cors.preflight(&preflight).headers().get("Access-Control-Allow-Headers"), Some("Content-Type"));
+    }
+
+    #[test]
+    fn it_applies_headers_to_allowed_origins_only() {
+        let cors = config(&["http://app.test"]);
+
+        let mut response = ResponseBuilder::new().build();
+        cors.apply(&request("GET", "Origin: http://app.test\r\n"), &mut response);
+        assert_eq!(response.headers().get("Access-Control-Allow-Origin"), Some("http://app.test"));
+        assert_eq!(response.headers().get("Vary"), Some("Origin"));
+
+        let mut response = ResponseBuilder::new().build();
+        cors.apply(&request("GET", "Origin: http://evil.test\r\n"), &mut response);
+        assert_eq!(response.headers().get("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers().get("Vary"), Some("Origin"));
+
+        let mut response = ResponseBuilder::new().build();
+        config(&[]).apply(&request("GET", "Origin: http://app.test\r\n"), &mut response);
+        assert_eq!(response.headers().get("Vary"), None);
+    }
+
+    #[test]
+    fn it_only_allows_reads_for_a_read_only_server() {
+        let mut cors = config(&["http://app.test"]);
+        cors.allow_reads_only();
+
+        let read = request("OPTIONS", "Origin: http://app.test\r\nAccess-Control-Request-Method: GET\r\n");
+        let response = cors.preflight(&read);
+        assert_eq!(*response.status_code(), StatusCode::NoContent);
+        assert_eq!(response.headers().get("Access-Control-Allow-Methods"), Some("GET, HEAD"));
+
+        let write = request("OPTIONS", "Origin: http://app.test\r\nAccess-Control-Request-Method: DELETE\r\n");
+        assert_eq!(*cors.preflight(&write).status_code(), StatusCode::Forbidden);
+    }
+}
+
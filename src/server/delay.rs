@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::BinaryHeap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::db::RESERVED_PREFIX;
+
+use super::config::parse_duration;
+use super::request::Request;
+use super::rewrite::Pattern;
+
+/// Longest delay a single request can ask for with `?_delay=`
+const MAX_REQUEST_DELAY: Duration = Duration::from_secs(60);
+
+/// Artificial latency added to the responses, to exercise loading states
+/// and timeouts of the clients
+#[derive(Debug, Default)]
+pub struct DelayConfig {
+    /// Applied to every request without a more specific delay
+    pub global: Option<Delay>,
+    /// Delays of the paths matching the pattern, the first match applies
+    pub routes: Vec<(Pattern, Delay)>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delay {
+    Fixed(Duration),
+    /// Picked at random between the bounds, inclusive
+    Jitter(Duration, Duration)
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Holds jobs back until they are due and hands them over to be run, so
+/// that delayed responses don't keep the workers of the pool waiting
+pub struct Timer {
+    sender: mpsc::Sender<Scheduled>
+}
+
+struct Scheduled {
+    due: Instant,
+    job: Job
+}
+
+impl Delay {
+    /// Parses a duration such as `800ms`, or a range such as `200..1500ms`
+    /// where a lower bound without a unit takes the one of the upper bound
+    pub fn parse(value: &str) -> Option<Self> {
+        let (min, max) = match value.split_once("..") {
+            Some(bounds) => bounds,
+            None => return parse_duration(value).map(Delay::Fixed)
+        };
+
+        let (min, max) = (min.trim(), max.trim());
+        let min = if min.bytes().all(|byte| byte.is_ascii_digit()) {
+            let unit = max.trim_start_matches(|c: char| c.is_ascii_digit());
+            parse_duration(&format!("{min}{unit}"))?
+        } else {
+            parse_duration(min)?
+        };
+        let max = parse_duration(max)?;
+
+        (min <= max).then_some(Delay::Jitter(min, max))
+    }
+
+    pub fn sample(&self) -> Duration {
+        match *self {
+            Delay::Fixed(delay) => delay,
+            Delay::Jitter(min, max) => {
+                let span = (max - min).as_millis() as u64;
+                min + Duration::from_millis(random() % (span + 1))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Delay {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Delay::Fixed(delay) => write!(f, "{delay:?}"),
+            Delay::Jitter(min, max) => write!(f, "{min:?}..{max:?}")
+        }
+    }
+}
+
+impl DelayConfig {
+    /// Delay of the response to the request. The `_delay` query parameter takes
+    /// precedence over the delay of the route, which does over the global one.
+    /// Endpoints of the server itself, such as `/__health`, are left out of the global one
+    pub fn delay_for(&self, request: &Request) -> Duration {
+        if let Some(delay) = request.query_param("_delay").and_then(Delay::parse) {
+            return delay.sample().min(MAX_REQUEST_DELAY);
+        }
+
+        let path = request.url.to_str().unwrap_or_default();
+        let global = self.global.as_ref().filter(|_| !path.trim_start_matches('/').starts_with(RESERVED_PREFIX));
+        self.routes
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(path))
+            .map(|(_, delay)| delay)
+            .or(global)
+            .map_or(Duration::ZERO, Delay::sample)
+    }
+}
+
+impl Timer {
+    /// Spawns the thread keeping the jobs, which are given to `dispatch` once due
+    pub fn new<F>(dispatch: F) -> Self
+    where F: Fn(Job) + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel::<Scheduled>();
+
+        thread::spawn(move || {
+            let mut queue = BinaryHeap::new();
+            loop {
+                let message = match queue.peek() {
+                    Some(Scheduled { due, .. }) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                };
+
+                match message {
+                    Ok(scheduled) => queue.push(scheduled),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+
+                let now = Instant::now();
+                while queue.peek().is_some_and(|scheduled| scheduled.due <= now) {
+                    dispatch(queue.pop().unwrap().job);
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    pub fn schedule<F>(&self, due: Instant, job: F)
+    where F: FnOnce() + Send + 'static
+    {
+        let _ = self.sender.send(Scheduled { due, job: Box::new(job) });
+    }
+}
+
+// Ordered by due time, reversed so that the heap pops the earliest first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.due.cmp(&self.due)
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due
+    }
+}
+
+impl Eq for Scheduled {}
+
+/// Random enough to spread the delays, the hasher is keyed at random each time
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_fixed_and_jittered_delays() {
+        assert_eq!(Delay::parse("800ms"), Some(Delay::Fixed(Duration::from_millis(800))));
+        assert_eq!(Delay::parse("200..1500ms"), Some(Delay::Jitter(Duration::from_millis(200), Duration::from_millis(1500))));
+        assert_eq!(Delay::parse("1..2s"), Some(Delay::Jitter(Duration::from_secs(1), Duration::from_secs(2))));
+        assert_eq!(Delay::parse("500ms..1s"), Some(Delay::Jitter(Duration::from_millis(500), Duration::from_secs(1))));
+        assert_eq!(Delay::parse("2s..1s"), None);
+        assert_eq!(Delay::parse("soon"), None);
+    }
+
+    #[test]
+    fn it_samples_jittered_delays_within_the_bounds() {
+        let delay = Delay::Jitter(Duration::from_millis(200), Duration::from_millis(210));
+        for _ in 0..100 {
+            let sample = delay.sample();
+            assert!(sample >= Duration::from_millis(200) && sample <= Duration::from_millis(210));
+        }
+    }
+
+    fn delay_for(config: &DelayConfig, target: &str) -> Duration {
+        let message = format!("GET {target} HTTP/1.1\r\n\r\n");
+        let request = Request::new(&mut message.as_bytes(), Instant::now(), &Default::default()).unwrap();
+        config.delay_for(&request)
+    }
+
+    #[test]
+    fn it_picks_the_most_specific_delay() {
+        let config = DelayConfig {
+            global: Some(Delay::Fixed(Duration::from_millis(100))),
+            routes: vec![
+                (Pattern::new("/slow/*"), Delay::Fixed(Duration::from_millis(300))),
+                (Pattern::new("/slow/fast"), Delay::Fixed(Duration::from_millis(1))),
+                (Pattern::new("/__ready"), Delay::Fixed(Duration::from_millis(50)))
+            ]
+        };
+
+        assert_eq!(delay_for(&config, "/users"), Duration::from_millis(100));
+        // The first matching route applies
+        assert_eq!(delay_for(&config, "/slow/fast"), Duration::from_millis(300));
+        assert_eq!(delay_for(&config, "/slow/users?_delay=5ms"), Duration::from_millis(5));
+        assert_eq!(delay_for(&config, "/users?_delay=0ms"), Duration::ZERO);
+        assert_eq!(delay_for(&config, "/users?_delay=soon"), Duration::from_millis(100));
+        assert_eq!(delay_for(&config, "/users?_delay=5m"), MAX_REQUEST_DELAY);
+
+        // Endpoints of the server are only delayed when asked for specifically
+        assert_eq!(delay_for(&config, "/__health"), Duration::ZERO);
+        assert_eq!(delay_for(&config, "/__ready"), Duration::from_millis(50));
+        assert_eq!(delay_for(&config, "/__health?_delay=5ms"), Duration::from_millis(5));
+
+        assert_eq!(delay_for(&DelayConfig::default(), "/users"), Duration::ZERO);
+    }
+
+    #[test]
+    fn it_runs_jobs_in_the_order_they_are_due() {
+        let timer = Timer::new(|job| job());
+        let (sender, receiver) = mpsc::channel();
+        let now = Instant::now();
+
+        for (name, due) in [("third", 90), ("first", 30), ("second", 60), ("overdue", 0)] {
+            let sender = sender.clone();
+            timer.schedule(now + Duration::from_millis(due), move || sender.send((name, Instant::now())).unwrap());
+        }
+
+        let ran: Vec<_> = (0..4).map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(ran.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["overdue", "first", "second", "third"]);
+        assert!(ran[3].1 >= now + Duration::from_millis(90));
+    }
+}
+
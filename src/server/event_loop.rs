@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, prelude::*, ErrorKind};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::request::{MessageScanner, RequestLimits, RequestReader, TimedOut};
+use super::stream::{Listener, Stream};
+use super::thread_pool::ThreadPool;
+
+/// Reads the request off the raw message and writes the response into the buffer,
+/// run by the workers of the pool. Given when handling started and the client address,
+/// returns how long the response is held back before it is written
+pub type Handler = dyn Fn(&mut dyn RequestReader, Instant, &str, &mut Vec<u8>) -> io::Result<Duration> + Send + Sync;
+
+const MAX_EVENTS: usize = 256;
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+const LISTENER_TOKEN: u64 = u64::MAX;
+const WAKER_TOKEN: u64 = u64::MAX - 1;
+
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const EPOLL_CLOEXEC: c_int = 0o2000000;
+    pub const EPOLL_CTL_ADD: c_int = 1;
+    pub const EPOLL_CTL_DEL: c_int = 2;
+    pub const EPOLL_CTL_MOD: c_int = 3;
+
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EPOLLEXCLUSIVE: u32 = 1 << 28;
+
+    pub const EFD_CLOEXEC: c_int = 0o2000000;
+    pub const EFD_NONBLOCK: c_int = 0o4000;
+
+    // The kernel declares the struct packed on x86_64 only
+    #[derive(Clone, Copy, Default)]
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64
+    }
+
+    extern "C" {
+        pub fn epoll_create1(flags: c_int) -> c_int;
+        pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        pub fn epoll_wait(epfd: c_int, events: *mut EpollEvent, maxevents: c_int, timeout: c_int) -> c_int;
+        pub fn eventfd(initval: u32, flags: c_int) -> c_int;
+        pub fn close(fd: c_int) -> c_int;
+    }
+}
+
+use sys::EpollEvent;
+
+/// Safe wrapper of an epoll instance, file descriptors are registered with a
+/// token which is handed back when they become ready
+struct Poller {
+    epfd: RawFd
+}
+
+impl Poller {
+    fn new() -> io::Result<Self> {
+        let epfd = unsafe { sys::epoll_create1(sys::EPOLL_CLOEXEC) };
+        if epfd < 0 { return Err(io::Error::last_os_error()); }
+        Ok(Self { epfd })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = EpollEvent { events, data: token };
+        let result = unsafe { sys::epoll_ctl(self.epfd, op, fd, &mut event) };
+        if result < 0 { return Err(io::Error::last_os_error()); }
+        Ok(())
+    }
+
+    fn add(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    fn modify(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Waits for readiness events, a negative timeout waits indefinitely
+    fn wait(&self, events: &mut [EpollEvent], timeout_ms: i32) -> io::Result<usize> {
+        let count = unsafe {
+            sys::epoll_wait(self.epfd, events.as_mut_ptr(), events.len() as i32, timeout_ms)
+        };
+        if count < 0 { return Err(io::Error::last_os_error()); }
+        Ok(count as usize)
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe { sys::close(self.epfd); }
+    }
+}
+
+/// Wakes the event loop thread up from another thread, through an eventfd
+/// the poller listens to
+struct Waker {
+    file: File
+}
+
+impl Waker {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { sys::eventfd(0, sys::EFD_CLOEXEC | sys::EFD_NONBLOCK) };
+        if fd < 0 { return Err(io::Error::last_os_error()); }
+        Ok(Self { file: unsafe { File::from_raw_fd(fd) } })
+    }
+
+    fn wake(&self) {
+        let _ = (&self.file).write(&1u64.to_ne_bytes());
+    }
+
+    /// Resets the counter so that the poller stops reporting it
+    fn reset(&self) {
+        let _ = (&self.file).read(&mut [0; 8]);
+    }
+}
+
+/// Response produced by a worker of the pool, sent back to the event loop
+/// thread the client belongs to
+struct Handled {
+    token: u64,
+    response: Vec<u8>,
+    result: io::Result<Duration>
+}
+
+/// Hands the requests over to the pool, so that handling one doesn't hold up
+/// the other connections of the event loop thread
+struct Dispatcher<'a> {
+    pool: &'a ThreadPool,
+    handler: &'a Arc<Handler>,
+    sender: mpsc::Sender<Handled>,
+    waker: Arc<Waker>
+}
+
+impl Dispatcher<'_> {
+    /// Handles the message on the pool, as timed out once the message runs out
+    /// when `is_timed_out` is set
+    fn dispatch(&self, token: u64, message: Vec<u8>, is_timed_out: bool, start_time: Instant, remote_addr: String) {
+        let handler = Arc::clone(self.handler);
+        let sender = self.sender.clone();
+        let waker = Arc::clone(&self.waker);
+
+        self.pool.execute(move || {
+            let mut response = Vec::new();
+            let mut reader = message.as_slice();
+            // A panicking handler closes the client rather than leaving it handled forever
+            let result = panic::catch_unwind(AssertUnwindSafe(|| match is_timed_out {
+                true => handler(&mut reader.chain(TimedOut), start_time, &remote_addr, &mut response),
+                false => handler(&mut reader, start_time, &remote_addr, &mut response)
+            })).unwrap_or_else(|_| Err(io::Error::other("the request handler panicked")));
+
+            if sender.send(Handled { token, response, result }).is_ok() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+enum ClientState {
+    Reading,
+    /// Request is being handled by the pool, nothing is listened to meanwhile
+    Handling,
+    /// Response is buffered and held back until the deadline, see `--delay`
+    Delayed,
+    Writing { written: usize }
+}
+
+/// Connection multiplexed by the event loop, the request is buffered until
+/// complete and the response is buffered until the socket accepts all of it
+struct Client {
+    stream: Stream,
+    remote_addr: String,
+    state: ClientState,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    start_time: Option<Instant>,
+    /// When the headers, the body or the response has to be done by,
+    /// or when the delayed response is due
+    deadline: Instant,
+    scanner: MessageScanner,
+    is_head_received: bool
+}
+
+impl Client {
+    fn new(stream: Stream, limits: &RequestLimits) -> Self {
+        Self {
+            remote_addr: stream.remote_addr(),
+            stream,
+            state: ClientState::Reading,
+            read_buffer: vec![],
+            write_buffer: vec![],
+            start_time: None,
+            deadline: Instant::now() + limits.header_timeout,
+            scanner: MessageScanner::default(),
+            is_head_received: false
+        }
+    }
+
+    /// Makes as much progress as the socket allows, returns `true` once the
+    /// connection is finished and should be closed
+    fn on_ready(
+        &mut self,
+        poller: &Poller,
+        token: u64,
+        limits: &RequestLimits,
+        dispatcher: &Dispatcher
+    ) -> io::Result<bool> {
+        if let ClientState::Reading = self.state {
+            // Reads no further than the end of the message, which the limits bound
+            let mut is_eof = false;
+            let mut chunk = [0; READ_BUFFER_SIZE];
+            let len = loop {
+                if let Some(len) = self.scanner.scan(&self.read_buffer, limits) {
+                    break Some(len);
+                }
+
+                match self.stream.read(&mut chunk) {
+                    // Client is done sending, possibly only half closing the connection
+                    Ok(0) => {
+                        is_eof = true;
+                        break None;
+                    },
+                    Ok(size) => {
+                        self.start_time.get_or_insert_with(Instant::now);
+                        self.read_buffer.extend_from_slice(&chunk[..size]);
+                    },
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break None,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err)
+                }
+            };
+
+            if !self.is_head_received && self.scanner.is_head_complete() {
+                self.is_head_received = true;
+                self.deadline = Instant::now() + limits.body_timeout;
+            }
+
+            let len = match len {
+                Some(len) => len,
+                None if is_eof && self.read_buffer.is_empty() => return Ok(true),
+                // Answered all the same, parsing the incomplete request reports the error
+                None if is_eof => self.read_buffer.len(),
+                None => return Ok(false)
+            };
+
+            let mut buffer = mem::take(&mut self.read_buffer);
+            buffer.truncate(len);
+            self.dispatch(buffer, false, poller, token, dispatcher)?;
+            return Ok(false);
+        }
+
+        if let ClientState::Writing { written } = &mut self.state {
+            while *written < self.write_buffer.len() {
+                match self.stream.write(&self.write_buffer[*written..]) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(size) => *written += size,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err)
+                }
+            }
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Answers the client which didn't send the complete request in time,
+    /// parsing what was received so far ends up with a timeout
+    fn on_timeout(
+        &mut self,
+        poller: &Poller,
+        token: u64,
+        limits: &RequestLimits,
+        dispatcher: &Dispatcher
+    ) -> io::Result<bool> {
+        match self.state {
+            ClientState::Reading => {
+                let buffer = mem::take(&mut self.read_buffer);
+                self.dispatch(buffer, true, poller, token, dispatcher)?;
+                Ok(false)
+            },
+            ClientState::Delayed => {
+                self.write(poller, token, limits)?;
+                self.on_ready(poller, token, limits, dispatcher)
+            },
+            // Not given a deadline while handled
+            ClientState::Handling => Ok(false),
+            // Client isn't taking the response in time
+            ClientState::Writing { .. } => Ok(true)
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        message: Vec<u8>,
+        is_timed_out: bool,
+        poller: &Poller,
+        token: u64,
+        dispatcher: &Dispatcher
+    ) -> io::Result<()> {
+        let start_time = self.start_time.unwrap_or_else(Instant::now);
+        self.state = ClientState::Handling;
+
+        // Not listening to anything, only a hang up is reported meanwhile
+        poller.modify(self.stream.as_raw_fd(), 0, token)?;
+        dispatcher.dispatch(token, message, is_timed_out, start_time, self.remote_addr.clone());
+        Ok(())
+    }
+
+    /// Takes the response of the pool, streamed bodies are buffered as well
+    /// as the socket may not take them at once
+    fn on_handled(
+        &mut self,
+        handled: Handled,
+        poller: &Poller,
+        token: u64,
+        limits: &RequestLimits
+    ) -> io::Result<()> {
+        let delay = handled.result?;
+        self.write_buffer = handled.response;
+        if delay.is_zero() {
+            return self.write(poller, token, limits);
+        }
+
+        // Not listening to anything, only a hang up is reported meanwhile
+        self.state = ClientState::Delayed;
+        self.deadline = Instant::now() + delay;
+        poller.modify(self.stream.as_raw_fd(), 0, token)
+    }
+
+    /// When the client has to be done by, `None` while its request is handled
+    fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            ClientState::Handling => None,
+            _ => Some(self.deadline)
+        }
+    }
+
+    fn write(&mut self, poller: &Poller, token: u64, limits: &RequestLimits) -> io::Result<()> {
+        self.state = ClientState::Writing { written: 0 };
+        self.deadline = Instant::now() + limits.write_timeout;
+
+        // Stop listening to reads, the rest of the request is of no interest
+        poller.modify(self.stream.as_raw_fd(), sys::EPOLLOUT, token)
+    }
+}
+
+/// Serves the listener with one epoll instance per thread, each thread
+/// multiplexes every connection it accepted instead of blocking on one
+/// while the requests are handled by the pool
+pub fn run(
+    listener: &Listener,
+    threads: usize,
+    limits: &RequestLimits,
+    pool: &ThreadPool,
+    handler: &Arc<Handler>
+) -> io::Result<()> {
+    assert!(threads > 0);
+    listener.set_nonblocking(true)?;
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| scope.spawn(|| serve(listener, limits, pool, handler)))
+            .collect();
+
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+        Ok(())
+    })
+}
+
+fn serve(listener: &Listener, limits: &RequestLimits, pool: &ThreadPool, handler: &Arc<Handler>) -> io::Result<()> {
+    let poller = Poller::new()?;
+    // Only wake up one of the threads for each incoming connection
+    poller.add(listener.as_raw_fd(), sys::EPOLLIN | sys::EPOLLEXCLUSIVE, LISTENER_TOKEN)?;
+
+    let waker = Arc::new(Waker::new()?);
+    poller.add(waker.file.as_raw_fd(), sys::EPOLLIN, WAKER_TOKEN)?;
+    let (sender, receiver) = mpsc::channel();
+    let dispatcher = Dispatcher { pool, handler, sender, waker: Arc::clone(&waker) };
+
+    let mut clients: HashMap<u64, Client> = HashMap::new();
+    // Tokens are never reused, a response handled for a client which went
+    // away can't be mistaken for the one of a newer client
+    let mut next_token = 0;
+    let mut events = vec![EpollEvent::default(); MAX_EVENTS];
+
+    loop {
+        // Wake up in time for the earliest deadline
+        let timeout_ms = clients
+            .values()
+            .filter_map(|client| client.deadline())
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .min()
+            .map(|timeout| (timeout + Duration::from_millis(1)).as_millis().min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+
+        let count = match poller.wait(&mut events, timeout_ms) {
+            Ok(count) => count,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
+
+        for event in events[..count].iter() {
+            let token = event.data;
+
+            if token == LISTENER_TOKEN {
+                accept(listener, &poller, limits, &mut clients, &mut next_token);
+                continue;
+            }
+
+            if token == WAKER_TOKEN {
+                waker.reset();
+                for handled in receiver.try_iter() {
+                    let token = handled.token;
+                    let client = match clients.get_mut(&token) {
+                        Some(client) => client,
+                        None => continue
+                    };
+
+                    if let Err(err) = client.on_handled(handled, &poller, token, limits) {
+                        eprintln!("Failed to handle request: {err}");
+                        close(&poller, &mut clients, token);
+                    }
+                }
+                continue;
+            }
+
+            let client = match clients.get_mut(&token) {
+                Some(client) => client,
+                None => continue
+            };
+
+            // Client went away while its request was handled or its response held back
+            let is_hung_up = event.events & (sys::EPOLLERR | sys::EPOLLHUP) != 0;
+            if let ClientState::Handling | ClientState::Delayed = client.state {
+                if is_hung_up { close(&poller, &mut clients, token); }
+                continue;
+            }
+
+            let is_finished = client.on_ready(&poller, token, limits, &dispatcher).unwrap_or_else(|err| {
+                eprintln!("Failed to serve connection: {err}");
+                true
+            });
+
+            if is_finished {
+                close(&poller, &mut clients, token);
+            }
+        }
+
+        let now = Instant::now();
+        let expired: Vec<u64> = clients
+            .iter()
+            .filter(|(_, client)| client.deadline().is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            let client = clients.get_mut(&token).unwrap();
+            let is_finished = client.on_timeout(&poller, token, limits, &dispatcher).unwrap_or_else(|err| {
+                eprintln!("Failed to time out connection: {err}");
+                true
+            });
+
+            if is_finished {
+                close(&poller, &mut clients, token);
+            }
+        }
+    }
+}
+
+fn close(poller: &Poller, clients: &mut HashMap<u64, Client>, token: u64) {
+    if let Some(client) = clients.remove(&token) {
+        let _ = poller.delete(client.stream.as_raw_fd());
+    }
+}
+
+fn accept(
+    listener: &Listener,
+    poller: &Poller,
+    limits: &RequestLimits,
+    clients: &mut HashMap<u64, Client>,
+    next_token: &mut u64
+) {
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {err}");
+                return;
+            }
+        };
+
+        let fd = stream.as_raw_fd();
+        let token = *next_token;
+        *next_token += 1;
+        let registered = stream
+            .set_nonblocking(true)
+            .and_then(|_| poller.add(fd, sys::EPOLLIN | sys::EPOLLRDHUP, token));
+
+        match registered {
+            Ok(_) => { clients.insert(token, Client::new(stream, limits)); },
+            Err(err) => eprintln!("Failed to register connection: {err}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::request::Request;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    /// Serves with a single event loop thread, answering with the path of the
+    /// request after a second for `/slow` and with 400 when it can't be read,
+    /// panicking for `/panic`
+    fn serve() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let handler: Arc<Handler> = Arc::new(|message: &mut dyn RequestReader, _: Instant, _: &str, writer: &mut Vec<u8>| {
+                let content = match Request::new(message, Instant::now(), &RequestLimits::default()) {
+                    Ok(request) => request.url.to_string_lossy().into_owned(),
+                    Err(_) => return write!(writer, "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").map(|_| Duration::ZERO)
+                };
+                match content.as_str() {
+                    "/slow" => thread::sleep(Duration::from_secs(1)),
+                    "/panic" => panic!("handler failed"),
+                    _ => {}
+                }
+                write!(writer, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{content}", content.len()).map(|_| Duration::ZERO)
+            });
+
+            let pool = ThreadPool::new(2);
+            run(&Listener::Tcp(listener), 1, &RequestLimits::default(), &pool, &handler).unwrap();
+        });
+
+        addr
+    }
+
+    fn exchange(addr: std::net::SocketAddr, message: &str, half_close: bool) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(message.as_bytes()).unwrap();
+        if half_close {
+            stream.shutdown(Shutdown::Write).unwrap();
+        }
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn it_answers_requests_sent_before_half_closing() {
+        let addr = serve();
+
+        assert!(exchange(addr, "GET /users HTTP/1.1\r\n\r\n", true).ends_with("\r\n\r\n/users"));
+        assert!(exchange(addr, "POST /users HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}", true).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn it_keeps_serving_other_connections_while_handling_one() {
+        let addr = serve();
+
+        let slow = thread::spawn(move || exchange(addr, "GET /slow HTTP/1.1\r\n\r\n", false));
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(exchange(addr, "GET /fast HTTP/1.1\r\n\r\n", false).ends_with("/fast"));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(slow.join().unwrap().ends_with("/slow"));
+    }
+
+    #[test]
+    fn it_closes_connections_whose_handler_panicked() {
+        let addr = serve();
+
+        assert_eq!(exchange(addr, "GET /panic HTTP/1.1\r\n\r\n", false), "");
+        assert!(exchange(addr, "GET /users HTTP/1.1\r\n\r\n", true).ends_with("/users"));
+    }
+}
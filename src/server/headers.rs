@@ -0,0 +1,109 @@
+/// HTTP header fields shared by requests and responses, names are matched
+/// case-insensitively and each name may carry multiple values
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    entries: Vec<(String, String)>
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self { entries: vec![] }
+    }
+
+    /// Returns the first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every value of the header in the order they were received
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Returns the values of a comma-separated list header such as `Accept-Encoding`,
+    /// combining repeated header lines into one list
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value to the header, keeping the existing values
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_owned(), value.to_owned()));
+    }
+
+    /// Replaces all existing values of the header with the given value
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_looks_up_headers_case_insensitively() {
+        let mut headers = Headers::new();
+        headers.append("Content-Length", "12");
+
+        assert_eq!(headers.get("content-length"), Some("12"));
+        assert_eq!(headers.get("CONTENT-LENGTH"), Some("12"));
+        assert!(headers.contains("Content-length"));
+        assert_eq!(headers.get("Content-Type"), None);
+    }
+
+    #[test]
+    fn it_keeps_multiple_values_per_name() {
+        let mut headers = Headers::new();
+        headers.append("Accept-Encoding", "gzip");
+        headers.append("accept-encoding", "deflate, br");
+
+        assert_eq!(headers.get("Accept-Encoding"), Some("gzip"));
+        assert_eq!(headers.get_all("Accept-Encoding"), vec!["gzip", "deflate, br"]);
+        assert_eq!(headers.get_list("Accept-Encoding"), vec!["gzip", "deflate", "br"]);
+    }
+
+    #[test]
+    fn it_replaces_all_values_when_set() {
+        let mut headers = Headers::new();
+        headers.append("Vary", "Origin");
+        headers.append("vary", "Accept-Encoding");
+        headers.set("VARY", "*");
+
+        assert_eq!(headers.get_all("Vary"), vec!["*"]);
+        assert_eq!(headers.len(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days since 1970-01-01 of the civil date, months start from 1
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = (year - era * 400) as u64;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era as i64 - 719468
+}
+
+/// Civil date of the days since 1970-01-01, as (year, month, day)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Breaks the time down into days since 1970-01-01 and seconds into that day
+fn split(time: SystemTime) -> (u64, u64) {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY)
+}
+
+/// Formats the time as IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn format(time: SystemTime) -> String {
+    let (days, time_of_day) = split(time);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// Formats the time the way Common Log Format does, e.g. "06/Nov/1994:08:49:37 +0000"
+pub fn format_common_log(time: SystemTime) -> String {
+    let (days, time_of_day) = split(time);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// Formats the time as RFC 3339 in UTC with milliseconds, e.g. "1994-11-06T08:49:37.000Z"
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let (days, time_of_day) = split(time);
+    let (year, month, day) = civil_from_days(days);
+    let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_millis();
+
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        millis
+    )
+}
+
+/// Parses any of the three date formats HTTP recipients have to accept:
+/// IMF-fixdate, the obsolete RFC 850 format and asctime
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let tokens: Vec<&str> = value
+        .split([' ', ',', '-'])
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    let month_of = |token: &str| MONTHS.iter().position(|month| *month == token).map(|index| index as u64 + 1);
+
+    // The weekday is redundant, only the rest is looked at
+    let (day, month, year, time) = match tokens.as_slice() {
+        [_, month, day, time, year] => (*day, month_of(month)?, *year, *time),
+        [_, day, month, year, time, "GMT"] => (*day, month_of(month)?, *year, *time),
+        _ => return None
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let mut year: i64 = year.parse().ok()?;
+    // RFC 850 only has two digits for the year
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+
+    let time: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (hour, minute, second) = match time.as_slice() {
+        [hour, minute, second] if *hour < 24 && *minute < 60 && *second <= 60 => (*hour, *minute, *second),
+        _ => return None
+    };
+    if !(1..=31).contains(&day) { return None; }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = u64::try_from(days).ok()? * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_formats_time_as_imf_fixdate() {
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(UNIX_EPOCH + Duration::from_secs(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH + Duration::from_secs(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn it_formats_time_for_access_logs() {
+        let time = UNIX_EPOCH + Duration::from_millis(784111777042);
+
+        assert_eq!(format_common_log(time), "06/Nov/1994:08:49:37 +0000");
+        assert_eq!(format_rfc3339(time), "1994-11-06T08:49:37.042Z");
+    }
+
+    #[test]
+    fn it_parses_all_http_date_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(parse("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse("yesterday"), None);
+    }
+}
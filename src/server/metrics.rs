@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::db::JsonDb;
+
+use super::access_log::Entry;
+use super::request::RequestMethod;
+use super::thread_pool::PoolStats;
+
+/// Upper bounds in seconds of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations in each bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Request and server figures exposed at `/__metrics` in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by method, route and status code
+    requests: Mutex<BTreeMap<(String, String, usize), u64>>,
+    /// Request latencies by method and route
+    latencies: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// Only set when serving with the thread pool
+    pool: OnceLock<Arc<PoolStats>>
+}
+
+impl Metrics {
+    pub fn set_pool(&self, stats: Arc<PoolStats>) {
+        let _ = self.pool.set(stats);
+    }
+
+    /// Records the request once its response is sent
+    pub fn observe(&self, entry: &Entry) {
+        // Clients can send any method, those unknown share a label rather than growing the series
+        let method = match entry.request.as_ref().map(|request| &request.method) {
+            Some(RequestMethod::Other(_)) => "OTHER",
+            Some(method) => method.as_str(),
+            None => "UNKNOWN"
+        }.to_owned();
+        let route = entry.route.clone().unwrap_or_else(|| "unmatched".to_owned());
+        let latency = entry.duration().as_secs_f64();
+
+        *self.requests
+            .lock()
+            .unwrap()
+            .entry((method.clone(), route.clone(), entry.status_code.get_value()))
+            .or_default() += 1;
+
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry((method, route))
+            .or_default()
+            .observe(latency);
+    }
+
+    pub fn render(&self, jsondb: &JsonDb) -> String {
+        let mut output = String::new();
+
+        write_header(&mut output, "rjs_http_requests_total", "counter", "Requests answered by method, route and status code");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                r#"rjs_http_requests_total{{method="{}",route="{}",status="{status}"}} {count}"#,
+                escape(method),
+                escape(route)
+            );
+        }
+
+        write_header(&mut output, "rjs_http_request_duration_seconds", "histogram", "Time from reading the request until the response is sent");
+        for ((method, route), histogram) in self.latencies.lock().unwrap().iter() {
+            let labels = format!(r#"method="{}",route="{}""#, escape(method), escape(route));
+
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(output, r#"rjs_http_request_duration_seconds_bucket{{{labels},le="{bound}"}} {cumulative}"#);
+            }
+            let _ = writeln!(output, r#"rjs_http_request_duration_seconds_bucket{{{labels},le="+Inf"}} {}"#, histogram.count);
+            let _ = writeln!(output, "rjs_http_request_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(output, "rjs_http_request_duration_seconds_count{{{labels}}} {}", histogram.count);
+        }
+
+        if let Some(pool) = self.pool.get() {
+            write_header(&mut output, "rjs_thread_pool_workers", "gauge", "Worker threads in the pool");
+            let _ = writeln!(output, "rjs_thread_pool_workers {}", pool.capacity);
+            write_header(&mut output, "rjs_thread_pool_busy_workers", "gauge", "Worker threads handling a connection");
+            let _ = writeln!(output, "rjs_thread_pool_busy_workers {}", pool.busy.load(Ordering::Relaxed));
+            write_header(&mut output, "rjs_thread_pool_queue_depth", "gauge", "Connections waiting for a worker thread");
+            let _ = writeln!(output, "rjs_thread_pool_queue_depth {}", pool.queued.load(Ordering::Relaxed));
+        }
+
+        let collections = jsondb.collections();
+
+        write_header(&mut output, "rjs_collection_records", "gauge", "Records in the collection");
+        for (name, connection) in collections.iter() {
+            let _ = writeln!(output, r#"rjs_collection_records{{collection="{}"}} {}"#, escape(&name.to_string_lossy()), connection.len());
+        }
+
+        write_header(&mut output, "rjs_persist_duration_seconds", "summary", "Time spent writing the collection to its file");
+        for (name, connection) in collections.iter() {
+            let stats = connection.persist_stats();
+            let name = escape(&name.to_string_lossy());
+            let seconds = stats.duration_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+            let _ = writeln!(output, r#"rjs_persist_duration_seconds_sum{{collection="{name}"}} {seconds}"#);
+            let _ = writeln!(output, r#"rjs_persist_duration_seconds_count{{collection="{name}"}} {}"#, stats.writes.load(Ordering::Relaxed));
+        }
+
+        write_header(&mut output, "rjs_persist_errors_total", "counter", "Failed writes of the collection to its file");
+        for (name, connection) in collections.iter() {
+            let errors = connection.persist_stats().errors.load(Ordering::Relaxed);
+            let _ = writeln!(output, r#"rjs_persist_errors_total{{collection="{}"}} {errors}"#, escape(&name.to_string_lossy()));
+        }
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {metric_type}");
+}
+
+/// Escapes label value as the text format requires
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::request::Request;
+
+    #[test]
+    fn it_counts_observations_into_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.0002);
+        histogram.observe(0.003);
+        histogram.observe(10.0);
+
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[3], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(histogram.count, 3);
+    }
+
+    #[test]
+    fn it_labels_unknown_methods_alike() {
+        let metrics = Metrics::default();
+        for message in ["GET / HTTP/1.1\r\n\r\n", "BREW / HTTP/1.1\r\n\r\n", "PURGE / HTTP/1.1\r\n\r\n"] {
+            let start_time = std::time::Instant::now();
+            let mut entry = Entry::new("127.0.0.1", start_time);
+            entry.request = Some(Request::new(&mut message.as_bytes(), start_time, &Default::default()).unwrap());
+            metrics.observe(&entry);
+        }
+
+        let requests = metrics.requests.lock().unwrap();
+        let methods: Vec<_> = requests.iter().map(|((method, _, _), count)| (method.as_str(), *count)).collect();
+        assert_eq!(methods, [("GET", 1), ("OTHER", 2)]);
+    }
+}
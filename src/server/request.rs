@@ -1,30 +1,58 @@
 use std::{
-    collections::HashMap,
-    net::TcpStream,
-    io::{prelude::*, BufReader},
+    fmt,
+    io::{self, prelude::*, BufReader, ErrorKind},
     path::PathBuf,
-    convert::From, time::Instant
+    convert::From,
+    time::{Duration, Instant}
 };
 
-#[derive(Debug)]
+use super::chunked;
+use super::headers::Headers;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum RequestMethod {
     GET,
+    HEAD,
     POST,
     PUT,
     PATCH,
-    DELETE
+    DELETE,
+    OPTIONS,
+    /// Any other verb, kept so that it can be answered with 405 Method Not Allowed
+    Other(String)
+}
+
+impl RequestMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::GET => "GET",
+            Self::HEAD => "HEAD",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::PATCH => "PATCH",
+            Self::DELETE => "DELETE",
+            Self::OPTIONS => "OPTIONS",
+            Self::Other(method) => method
+        }
+    }
+
+    /// Whether the method is meant to change the data
+    pub fn is_write(&self) -> bool {
+        matches!(self, Self::POST | Self::PUT | Self::PATCH | Self::DELETE)
+    }
 }
 
 impl From<&str> for RequestMethod {
     fn from(value: &str) -> Self {
         match value.to_ascii_uppercase().as_str() {
             "GET" => Self::GET,
+            "HEAD" => Self::HEAD,
             "POST" => Self::POST,
             "PUT" => Self::PUT,
             "PATCH" => Self::PATCH,
             "DELETE" => Self::DELETE,
-            _ => Self::GET
-            // _ => panic!("{}", format!("Failed to initialize RequstMethod from \"{}\"", value))
+            "OPTIONS" => Self::OPTIONS,
+            method => Self::Other(method.to_owned())
         }
     }
 }
@@ -32,65 +60,223 @@ impl From<&str> for RequestMethod {
 pub struct Request {
     pub start_time: Instant,
     pub method: RequestMethod,
+    /// Path of the request target, without the query
     pub url: PathBuf,
+    pub query: Option<String>,
     url_string: String,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Option<String>
 }
 
-pub struct RequestInitializationError(String);
+#[derive(Debug, PartialEq)]
+pub enum RequestInitializationError {
+    Malformed(String),
+    /// Client didn't send the headers or the body within the timeout
+    Timeout,
+    HeadersTooLarge,
+    BodyTooLarge
+}
 
-impl Request {
-    pub fn new(mut stream: &TcpStream, start_time: Instant) -> Result<Self, String> {        
-        let mut buf_reader = BufReader::new(&mut stream);
-        let mut request_info = String::new();
-        buf_reader.read_line(&mut request_info).unwrap();
+impl fmt::Display for RequestInitializationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(reason) => write!(f, "Malformed request: {reason}"),
+            Self::Timeout => write!(f, "Request timed out"),
+            Self::HeadersTooLarge => write!(f, "Request headers too large"),
+            Self::BodyTooLarge => write!(f, "Request body too large")
+        }
+    }
+}
 
-        // TODO: Figure out a way to handle errors!
-        let mut request_info = request_info.trim_end().split(" ");
-        let method = request_info.next();
-        if method.is_none() {
-            return Err("Empty Request Method".to_owned());
+impl From<io::Error> for RequestInitializationError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            // Sockets with read timeout report WouldBlock on Unix
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Self::Timeout,
+            ErrorKind::UnexpectedEof => Self::Malformed("Unexpected end of request".to_owned()),
+            _ => Self::Malformed(err.to_string())
         }
-        let method = RequestMethod::from(method.unwrap());
+    }
+}
+
+/// Bounds on what a client may send, so that slow or oversized requests
+/// can't hold on to the server or make it allocate on their behalf
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_headers: usize,
+    /// Bytes of the request line and all headers combined
+    pub max_header_size: usize,
+    pub max_body_size: usize
+}
 
-        let url_str = request_info.next();
-        if url_str.is_none() {
-            return Err("Empty Request URL".to_owned());
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_headers: 100,
+            max_header_size: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024
         }
-        let url_str = url_str.unwrap();
+    }
+}
+
+/// Source of the request message, notified when reading moves on from the
+/// headers to the body so that it can switch to the body timeout
+pub trait RequestReader: Read {
+    fn start_body(&mut self) {}
+}
+
+impl RequestReader for &[u8] {}
+
+impl<R: RequestReader> RequestReader for io::Chain<R, TimedOut> {
+    fn start_body(&mut self) {
+        self.get_mut().0.start_body();
+    }
+}
+
+/// Reader which keeps a copy of the first bytes read through it, so that a
+/// request which couldn't be read can still be logged as it was received
+pub struct Recorder<'a, R: RequestReader + ?Sized> {
+    reader: &'a mut R,
+    recorded: Vec<u8>,
+    limit: usize
+}
+
+impl<'a, R: RequestReader + ?Sized> Recorder<'a, R> {
+    pub fn new(reader: &'a mut R, limit: usize) -> Self {
+        Self { reader, recorded: Vec::new(), limit }
+    }
+
+    /// Request line and headers received, as much of them as was read
+    pub fn head(&self) -> String {
+        let end = self.recorded
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap_or(self.recorded.len());
+        String::from_utf8_lossy(&self.recorded[..end]).into_owned()
+    }
+}
 
-        let url = PathBuf::from(url_str);
+impl<R: RequestReader + ?Sized> Read for Recorder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        let recorded = size.min(self.limit - self.recorded.len());
+        self.recorded.extend_from_slice(&buf[..recorded]);
+        Ok(size)
+    }
+}
+
+impl<R: RequestReader + ?Sized> RequestReader for Recorder<'_, R> {
+    fn start_body(&mut self) {
+        self.reader.start_body();
+    }
+}
+
+/// Reader which only ever times out, chained after the buffered part of a
+/// request which didn't complete in time
+pub struct TimedOut;
+
+impl Read for TimedOut {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(ErrorKind::TimedOut.into())
+    }
+}
+
+impl Request {
+    pub fn new<R: RequestReader + ?Sized>(
+        stream: &mut R,
+        start_time: Instant,
+        limits: &RequestLimits
+    ) -> Result<Self, RequestInitializationError> {
+        use RequestInitializationError::*;
+
+        let mut buf_reader = BufReader::new(stream);
+        let mut header_bytes_left = limits.max_header_size;
+
+        let mut request_info = String::new();
+        read_header_line(&mut buf_reader, &mut request_info, &mut header_bytes_left)?;
+
+        let mut request_info = request_info.trim_end().split(' ');
+        let method = match request_info.next() {
+            Some(method) if !method.is_empty() => RequestMethod::from(method),
+            _ => return Err(Malformed("Empty Request Method".to_owned()))
+        };
+
+        let url_str = match request_info.next() {
+            Some(url_str) => url_str,
+            None => return Err(Malformed("Empty Request URL".to_owned()))
+        };
+
+        let (url, query) = split_target(url_str);
         let url_string = url_str.to_owned();
-        let version = request_info.next().unwrap().trim_end().to_owned();
+        let version = match request_info.next() {
+            Some(version) => version.trim_end().to_owned(),
+            None => return Err(Malformed("Empty Request Protocol Version".to_owned()))
+        };
 
-        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut headers = Headers::new();
         let mut message = String::new();
         loop {
-            let size = buf_reader.read_line(&mut message).unwrap();
+            let size = read_header_line(&mut buf_reader, &mut message, &mut header_bytes_left)?;
             if size < 3 { break; }
 
-            let header = message.clone();
-            let mut header = header.splitn(2, ": ");
-            let header_key = header.next().unwrap();
-            let header_value = header.next().unwrap().trim_end();
-            headers.insert(header_key.to_owned(), header_value.to_owned());
+            if headers.len() == limits.max_headers {
+                return Err(HeadersTooLarge);
+            }
+
+            let mut header = message.splitn(2, ':');
+            let header_key = header.next().unwrap().trim();
+            let header_value = match header.next() {
+                Some(value) => value.trim(),
+                None => return Err(Malformed(format!("Malformed header line: {:?}", message.trim_end())))
+            };
+            headers.append(header_key, header_value);
 
             message.clear();
         }
 
+        let is_chunked = headers
+            .get_list("Transfer-Encoding")
+            .iter()
+            .any(|coding| coding.eq_ignore_ascii_case("chunked"));
+
+        let content_len = match headers.get("Content-Length") {
+            Some(content_len) => Some(content_len
+                .parse::<usize>()
+                .map_err(|_| Malformed(format!("Invalid Content-Length: {content_len:?}")))?),
+            None => None
+        };
+
         let mut body: Option<String> = None;
-        if let Some(content_len) = headers.get("Content-Length") {
-            let content_len = content_len.parse::<usize>().unwrap();
-            let mut buffer = vec![0; content_len];
-            buf_reader.read_exact(&mut buffer).unwrap();
-            body = Some(String::from_utf8(buffer).unwrap());
+        if is_chunked {
+            buf_reader.get_mut().start_body();
+            let buffer = chunked::decode(&mut buf_reader, limits.max_body_size)?;
+            body = Some(String::from_utf8(buffer).map_err(|err| Malformed(err.to_string()))?);
+        } else if let Some(content_len) = content_len {
+            if content_len > limits.max_body_size {
+                return Err(BodyTooLarge);
+            }
+
+            // Never trust the claimed length for allocation, the buffer grows as data arrives
+            buf_reader.get_mut().start_body();
+            let mut buffer = Vec::new();
+            buf_reader.by_ref().take(content_len as u64).read_to_end(&mut buffer)?;
+            if buffer.len() < content_len {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            body = Some(String::from_utf8(buffer).map_err(|err| Malformed(err.to_string()))?);
         }
 
         Ok(Self {
             method,
             url,
+            query,
             url_string,
             version,
             headers,
@@ -99,14 +285,220 @@ impl Request {
         })
     }
 
-    pub fn log(&self, verbose: bool) {
-        let duration = Instant::now() - self.start_time;
-        println!("{:?} :: {} {:?}", self.method, self.url_string, duration);
+    /// Request target exactly as it appears in the request line, even once rewritten
+    pub fn target(&self) -> &str {
+        &self.url_string
+    }
 
-        if verbose {
-            for (key, value) in self.headers.iter() {
-                println!("{key}: {value}");
+    /// Raw value of the first query parameter of the name, as it appears in the target
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .find_map(|pair| match pair.split_once('=') {
+                Some((key, value)) => (key == name).then_some(value),
+                None => (pair == name).then_some("")
+            })
+    }
+
+    /// Points the request at another target, such as with the rewrite rules
+    pub fn set_target(&mut self, target: &str) {
+        (self.url, self.query) = split_target(target);
+    }
+}
+
+/// Finds where the request message at the start of the buffer ends as the
+/// buffer fills up, keeping what it found out between calls so that the
+/// message isn't parsed all over again on each read
+#[derive(Debug, Default)]
+pub struct MessageScanner {
+    /// Bytes already searched for the blank line which ends the head
+    searched: usize,
+    /// Set once the head is complete
+    body: Option<BodyScan>
+}
+
+#[derive(Debug)]
+enum BodyScan {
+    /// Message is complete once the buffer holds this many bytes
+    Length(usize),
+    Chunked { head_len: usize, scanner: chunked::Scanner },
+    /// Head is malformed or exceeds the limits, the message ends with it
+    Rejected(usize)
+}
+
+impl MessageScanner {
+    /// Length of the request message, `None` while the buffer doesn't hold the
+    /// complete message yet. Messages which are malformed or already exceed the
+    /// limits count as complete so that parsing reports the error, which keeps
+    /// the buffer from growing any larger than the limits allow
+    pub fn scan(&mut self, buffer: &[u8], limits: &RequestLimits) -> Option<usize> {
+        if self.body.is_none() {
+            // The blank line may straddle what was searched and what is new
+            let start = self.searched.saturating_sub(3);
+            let head_len = match buffer[start..].windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(position) => start + position + 4,
+                None => {
+                    self.searched = buffer.len();
+                    return (buffer.len() > limits.max_header_size).then_some(buffer.len());
+                }
+            };
+            self.body = Some(Self::scan_head(&buffer[..head_len], limits));
+        }
+
+        match self.body.as_mut()? {
+            BodyScan::Length(len) => (buffer.len() >= *len).then_some(*len),
+            BodyScan::Chunked { head_len, scanner } => scanner
+                .scan(&buffer[*head_len..], limits.max_body_size)
+                .map(|len| *head_len + len),
+            BodyScan::Rejected(len) => Some(*len)
+        }
+    }
+
+    pub fn is_head_complete(&self) -> bool {
+        self.body.is_some()
+    }
+
+    fn scan_head(head: &[u8], limits: &RequestLimits) -> BodyScan {
+        let head_len = head.len();
+        if head_len > limits.max_header_size { return BodyScan::Rejected(head_len); }
+
+        let head = String::from_utf8_lossy(head);
+        let mut headers = Headers::new();
+        for line in head.split("\r\n").skip(1) {
+            if let Some((key, value)) = line.split_once(':') {
+                headers.append(key.trim(), value.trim());
             }
         }
+
+        let is_chunked = headers
+            .get_list("Transfer-Encoding")
+            .iter()
+            .any(|coding| coding.eq_ignore_ascii_case("chunked"));
+
+        if is_chunked {
+            return BodyScan::Chunked { head_len, scanner: chunked::Scanner::default() };
+        }
+
+        match headers.get("Content-Length").map(str::parse::<usize>) {
+            Some(Ok(content_len)) if content_len <= limits.max_body_size => BodyScan::Length(head_len + content_len),
+            Some(_) => BodyScan::Rejected(head_len),
+            None => BodyScan::Length(head_len)
+        }
+    }
+}
+
+fn split_target(target: &str) -> (PathBuf, Option<String>) {
+    match target.split_once('?') {
+        Some((path, query)) => (PathBuf::from(path), Some(query.to_owned())),
+        None => (PathBuf::from(target), None)
     }
 }
+
+/// Reads one line of the request line or headers, failing once the line
+/// would exceed what is left of the header size limit
+fn read_header_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    bytes_left: &mut usize
+) -> Result<usize, RequestInitializationError> {
+    let size = reader.by_ref().take(*bytes_left as u64).read_line(line)?;
+    *bytes_left -= size;
+
+    if !line.ends_with('\n') {
+        return Err(match *bytes_left {
+            0 => RequestInitializationError::HeadersTooLarge,
+            _ => RequestInitializationError::Malformed("Unexpected end of request headers".to_owned())
+        });
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(message: &str, limits: &RequestLimits) -> Result<Request, RequestInitializationError> {
+        Request::new(&mut message.as_bytes(), Instant::now(), limits)
+    }
+
+    #[test]
+    fn it_rejects_bodies_over_the_limit() {
+        let limits = RequestLimits { max_body_size: 4, ..Default::default() };
+
+        assert!(parse("POST /users HTTP/1.1\r\nContent-Length: 4\r\n\r\nWiki", &limits).is_ok());
+        assert_eq!(
+            parse("POST /users HTTP/1.1\r\nContent-Length: 5\r\n\r\nWikis", &limits).err(),
+            Some(RequestInitializationError::BodyTooLarge)
+        );
+        assert_eq!(
+            parse("POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nWikis\r\n0\r\n\r\n", &limits).err(),
+            Some(RequestInitializationError::BodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn it_rejects_headers_over_the_limits() {
+        let limits = RequestLimits { max_headers: 2, max_header_size: 64, ..Default::default() };
+
+        assert!(parse("GET /users HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n", &limits).is_ok());
+        assert_eq!(
+            parse("GET /users HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", &limits).err(),
+            Some(RequestInitializationError::HeadersTooLarge)
+        );
+        assert_eq!(
+            parse(&format!("GET /users HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(64)), &limits).err(),
+            Some(RequestInitializationError::HeadersTooLarge)
+        );
+    }
+
+    #[test]
+    fn it_times_out_when_the_message_stops_short() {
+        let limits = RequestLimits::default();
+        let timed_out = |message: &str| Request::new(&mut message.as_bytes().chain(TimedOut), Instant::now(), &limits).err();
+
+        assert_eq!(timed_out("GET /users HTTP/1.1\r\nHost: loc"), Some(RequestInitializationError::Timeout));
+        assert_eq!(
+            timed_out("POST /users HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"),
+            Some(RequestInitializationError::Timeout)
+        );
+    }
+
+    #[test]
+    fn it_scans_messages_as_they_arrive() {
+        let limits = RequestLimits::default();
+        let message = "POST /users HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}GET /next";
+        let len = message.len() - "GET /next".len();
+
+        let mut scanner = MessageScanner::default();
+        for end in 0..len {
+            assert_eq!(scanner.scan(&message.as_bytes()[..end], &limits), None);
+        }
+        assert_eq!(scanner.scan(message.as_bytes(), &limits), Some(len));
+
+        let message = "POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n";
+        assert_eq!(MessageScanner::default().scan(message.as_bytes(), &limits), Some(message.len()));
+    }
+
+    #[test]
+    fn it_ends_messages_over_the_limits_early() {
+        let limits = RequestLimits { max_header_size: 64, max_body_size: 4, ..Default::default() };
+
+        let head = "POST /users HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(MessageScanner::default().scan(head.as_bytes(), &limits), Some(head.len()));
+
+        let head = format!("GET /users HTTP/1.1\r\nA: {}", "x".repeat(64));
+        assert_eq!(MessageScanner::default().scan(head.as_bytes(), &limits), Some(head.len()));
+    }
+
+    #[test]
+    fn it_tells_methods_which_change_the_data() {
+        for method in ["POST", "put", "PATCH", "DELETE"] {
+            assert!(RequestMethod::from(method).is_write(), "{method}");
+        }
+        for method in ["GET", "HEAD", "OPTIONS", "TRACE", "PROPFIND"] {
+            assert!(!RequestMethod::from(method).is_write(), "{method}");
+        }
+    }
+}
+
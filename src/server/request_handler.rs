@@ -1,80 +1,677 @@
+use std::ffi::OsStr;
 use std::sync::Arc;
-use std::net::TcpStream;
-use std::io::prelude::*;
+use std::sync::atomic::Ordering;
 
-use crate::db::connection::{Connection, DbQueryError};
-use crate::json::field::JsonField;
+use crate::db::checksum::Checksum;
+use crate::db::{DbCollectionError, DbSnapshotError, JsonDb};
+use crate::db::connection::{Connection, DbQueryError, DbWriteError};
+use crate::json::field::{JsonField, JsonFieldType};
+use crate::json::parser::parse_json;
 use crate::server::{
     StatusCode,
-    response::ResponseBuilder,
-    request::Request
+    access_log::escape_json,
+    conditional::{self, Validators},
+    metrics::Metrics,
+    response::{Response, ResponseBuilder},
+    request::{Request, RequestMethod}
 };
 
-use super::response::Response;
+/// Collections with more records than this are streamed as chunks
+/// instead of being serialized into one response string
+const STREAMING_RECORDS_THRESHOLD: usize = 1000;
 
 pub fn get(
-    request: Request,
-    mut stream: TcpStream,
+    request: &Request,
     connection: Arc<Connection>
-) {
-    let content = connection.read();
+) -> Response {
+    let validators = Validators::new(connection.checksum(), connection.modified());
 
-    let response = ResponseBuilder::new()
-        .set_status_code(StatusCode::Ok)
+    let builder = ResponseBuilder::new()
+        .set_status_code(status_code(request, &validators))
         .set_protocol(request.version.clone())
-        .set_content(content)
         .set_content_type("application/json".to_owned())
-        .build();
+        .set_header("ETag", &validators.etag)
+        .set_header("Last-Modified", &validators.last_modified_date());
+
+    // Chunked transfer-encoding only exists since HTTP/1.1, and HEAD requests
+    // need the actual Content-Length of the body
+    let is_streaming = request.method == RequestMethod::GET
+        && request.version == "HTTP/1.1"
+        && connection.len() > STREAMING_RECORDS_THRESHOLD;
 
-    request.log(false);
-    stream.write_all(response.format().as_bytes()).unwrap();
+    let builder = if is_streaming {
+        builder.set_stream(Box::new(move |writer| connection.read_into(writer)))
+    } else {
+        builder.set_content(connection.read())
+    };
+
+    builder.build()
 }
 
 pub fn post(
-    request: Request,
-    mut stream: TcpStream,
+    request: &Request,
     connection: Arc<Connection>
-) {
-    // Use this to get the request body
-    let body = request.body.as_ref().unwrap();
-    let json = JsonField::from(body.as_str());
+) -> Response {
+    let field = match parse_object(request) {
+        Some(field) => field,
+        None => return ResponseBuilder::build_error(
+            request.version.clone(),
+            StatusCode::BadRequest,
+            "Expect request body to be a JSON object"
+        )
+    };
 
-    let response_body = connection.insert(json);
+    let response_body = connection.insert(field);
 
-    let response = ResponseBuilder::new()
+    ResponseBuilder::new()
         .set_status_code(StatusCode::Ok)
         .set_protocol(request.version.clone())
         .set_content(response_body)
         .set_content_type("application/json".to_owned())
-        .build();
-
-    request.log(false);
-    stream.write_all(response.format().as_bytes()).unwrap();
+        .build()
 }
 
 pub fn get_id(
-    request: Request,
-    mut stream: TcpStream,
+    request: &Request,
     connection: Arc<Connection>,
     id: i32
-) {
-    let content;
-    match connection.get(id) {
-        Ok(value) => { content = value; },
-        Err(DbQueryError(_)) => return Response::not_found(
-            request.version,
-            request.start_time,
-            stream
-        )
+) -> Option<Response> {
+    let content = match connection.get(id) {
+        Ok(value) => value,
+        Err(DbQueryError(_)) => return None
+    };
+
+    // Records aren't tracked individually, so they share the collection write time
+    let validators = Validators::new(Checksum::of(content.as_bytes()), connection.modified());
+
+    let response = ResponseBuilder::new()
+        .set_status_code(status_code(request, &validators))
+        .set_protocol(request.version.clone())
+        .set_content(content)
+        .set_content_type("application/json".to_owned())
+        .set_header("ETag", &validators.etag)
+        .set_header("Last-Modified", &validators.last_modified_date())
+        .build();
+
+    Some(response)
+}
+
+#[derive(Clone, Copy)]
+enum WriteKind {
+    Replace,
+    Merge
+}
+
+/// Replaces the record with the body on PUT and merges the body into it on PATCH,
+/// the id is `None` for singular resources
+pub fn put(
+    request: &Request,
+    connection: Arc<Connection>,
+    id: Option<i32>,
+    require_if_match: bool
+) -> Option<Response> {
+    write(request, connection, id, require_if_match, WriteKind::Replace)
+}
+
+pub fn patch(
+    request: &Request,
+    connection: Arc<Connection>,
+    id: Option<i32>,
+    require_if_match: bool
+) -> Option<Response> {
+    write(request, connection, id, require_if_match, WriteKind::Merge)
+}
+
+fn write(
+    request: &Request,
+    connection: Arc<Connection>,
+    id: Option<i32>,
+    require_if_match: bool,
+    kind: WriteKind
+) -> Option<Response> {
+    if let Some(response) = check_if_match_required(request, require_if_match) {
+        return Some(response);
     }
 
+    let field = match parse_object(request) {
+        Some(field) => field,
+        None => {
+            return Some(ResponseBuilder::build_error(
+                request.version.clone(),
+                StatusCode::BadRequest,
+                "Expect request body to be a JSON object"
+            ));
+        }
+    };
+
+    let precondition = |record: &str| conditional::is_match(request, &conditional::etag(Checksum::of(record.as_bytes())));
+    let result = match (kind, id) {
+        (WriteKind::Replace, Some(id)) => connection.replace(id, field, precondition),
+        (WriteKind::Merge, Some(id)) => connection.merge(id, field, precondition),
+        (WriteKind::Replace, None) => connection.replace_singular(field, precondition),
+        (WriteKind::Merge, None) => connection.merge_singular(field, precondition)
+    };
+
+    let content = match result {
+        Ok(content) => content,
+        Err(DbWriteError::NotFound) => return None,
+        Err(DbWriteError::PreconditionFailed) => return Some(precondition_failed(request))
+    };
+
     let response = ResponseBuilder::new()
         .set_status_code(StatusCode::Ok)
         .set_protocol(request.version.clone())
+        .set_header("ETag", &conditional::etag(Checksum::of(content.as_bytes())))
         .set_content(content)
         .set_content_type("application/json".to_owned())
         .build();
 
-    request.log(false);
-    stream.write_all(response.format().as_bytes()).unwrap();
+    Some(response)
+}
+
+pub fn delete(
+    request: &Request,
+    connection: Arc<Connection>,
+    id: i32,
+    require_if_match: bool
+) -> Option<Response> {
+    if let Some(response) = check_if_match_required(request, require_if_match) {
+        return Some(response);
+    }
+
+    let precondition = |record: &str| conditional::is_match(request, &conditional::etag(Checksum::of(record.as_bytes())));
+    match connection.delete(id, precondition) {
+        Ok(_) => {},
+        Err(DbWriteError::NotFound) => return None,
+        Err(DbWriteError::PreconditionFailed) => return Some(precondition_failed(request))
+    }
+
+    let response = ResponseBuilder::new()
+        .set_status_code(StatusCode::NoContent)
+        .set_protocol(request.version.clone())
+        .build();
+
+    Some(response)
+}
+
+fn parse_object(request: &Request) -> Option<JsonField> {
+    let body = request.body.as_deref()?.trim();
+    if body.is_empty() { return None; }
+
+    let (field, _) = parse_json(body, 0).ok()?;
+    if field.is(JsonFieldType::Object) { Some(field) } else { None }
+}
+
+/// Answers with `428 Precondition Required` when writes have to be
+/// conditional and the request has no `If-Match`
+fn check_if_match_required(request: &Request, require_if_match: bool) -> Option<Response> {
+    if !require_if_match || request.headers.contains("If-Match") {
+        return None;
+    }
+
+    Some(ResponseBuilder::build_error(
+        request.version.clone(),
+        StatusCode::PreconditionRequired,
+        "428 Precondition Required, send If-Match with the ETag of the record"
+    ))
+}
+
+fn precondition_failed(request: &Request) -> Response {
+    ResponseBuilder::build_error(
+        request.version.clone(),
+        StatusCode::PreconditionFailed,
+        "412 Precondition Failed, the record has been modified since"
+    )
+}
+
+/// `304 Not Modified` when the client has the representation already. Its
+/// body is kept though never sent, compression looks at it to tell which
+/// validators the `200 OK` would have carried
+fn status_code(request: &Request, validators: &Validators) -> StatusCode {
+    match validators.is_not_modified(request) {
+        true => StatusCode::NotModified,
+        false => StatusCode::Ok
+    }
+}
+
+fn format_allow(allowed_methods: &[RequestMethod]) -> String {
+    allowed_methods
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+pub fn options(
+    request: &Request,
+    allowed_methods: &[RequestMethod]
+) -> Response {
+    ResponseBuilder::new()
+        .set_status_code(StatusCode::NoContent)
+        .set_protocol(request.version.clone())
+        .set_header("Allow", &format_allow(allowed_methods))
+        .build()
+}
+
+pub fn method_not_allowed(
+    request: &Request,
+    allowed_methods: &[RequestMethod]
+) -> Response {
+    ResponseBuilder::new()
+        .set_status_code(StatusCode::MethodNotAllowed)
+        .set_protocol(request.version.clone())
+        .set_header("Allow", &format_allow(allowed_methods))
+        .set_content(r#"{ "message": "405 Method Not Allowed" }"#.to_owned())
+        .set_content_type("application/json".to_owned())
+        .build()
+}
+
+pub fn metrics(
+    request: &Request,
+    metrics: &Metrics,
+    jsondb: &JsonDb
+) -> Response {
+    let allowed_methods = [RequestMethod::GET, RequestMethod::HEAD];
+    if !allowed_methods.contains(&request.method) {
+        return method_not_allowed(request, &allowed_methods);
+    }
+
+    ResponseBuilder::new()
+        .set_status_code(StatusCode::Ok)
+        .set_protocol(request.version.clone())
+        .set_content(metrics.render(jsondb))
+        .set_content_type("text/plain; version=0.0.4".to_owned())
+        .build()
+}
+
+/// Liveness, answered as long as the server accepts connections
+pub fn health(request: &Request) -> Response {
+    let allowed_methods = [RequestMethod::GET, RequestMethod::HEAD];
+    if !allowed_methods.contains(&request.method) {
+        return method_not_allowed(request, &allowed_methods);
+    }
+
+    ResponseBuilder::new()
+        .set_status_code(StatusCode::Ok)
+        .set_protocol(request.version.clone())
+        .set_content(r#"{ "status": "ok" }"#.to_owned())
+        .set_content_type("application/json".to_owned())
+        .build()
+}
+
+/// Readiness, `503 Service Unavailable` while the collections are loading,
+/// when one of them failed to load or when its latest write failed
+pub fn ready(
+    request: &Request,
+    jsondb: Option<&JsonDb>
+) -> Response {
+    let allowed_methods = [RequestMethod::GET, RequestMethod::HEAD];
+    if !allowed_methods.contains(&request.method) {
+        return method_not_allowed(request, &allowed_methods);
+    }
+
+    let builder = ResponseBuilder::new()
+        .set_protocol(request.version.clone())
+        .set_content_type("application/json".to_owned());
+
+    let builder = match jsondb {
+        Some(jsondb) => {
+            let (is_ready, content) = readiness(jsondb);
+            let status_code = if is_ready { StatusCode::Ok } else { StatusCode::ServiceUnavailable };
+            builder.set_status_code(status_code).set_content(content)
+        },
+        None => builder
+            .set_status_code(StatusCode::ServiceUnavailable)
+            .set_header("Retry-After", "1")
+            .set_content(r#"{ "status": "loading", "loaded": false, "persistence_healthy": true, "collections": 0, "load_failures": [], "persistence_failures": [] }"#.to_owned())
+    };
+
+    builder.build()
+}
+
+fn readiness(jsondb: &JsonDb) -> (bool, String) {
+    let load_failures: Vec<String> = jsondb
+        .failures()
+        .iter()
+        .map(|failure| format!(
+            r#"{{ "file": "{}", "reason": "{}" }}"#,
+            escape_json(&failure.file),
+            escape_json(&failure.reason)
+        ))
+        .collect();
+
+    let persistence_failures: Vec<String> = jsondb
+        .collections()
+        .iter()
+        .filter(|(_, connection)| connection.persist_stats().failing.load(Ordering::Relaxed))
+        .map(|(name, _)| format!(r#""{}""#, escape_json(&name.to_string_lossy())))
+        .collect();
+
+    let loaded = load_failures.is_empty();
+    let persistence_healthy = persistence_failures.is_empty();
+    let is_ready = loaded && persistence_healthy;
+    let status = if is_ready { "ready" } else { "unavailable" };
+
+    let content = format!(
+        r#"{{ "status": "{status}", "loaded": {loaded}, "persistence_healthy": {persistence_healthy}, "collections": {}, "load_failures": [{}], "persistence_failures": [{}] }}"#,
+        jsondb.len(),
+        load_failures.join(", "),
+        persistence_failures.join(", ")
+    );
+
+    (is_ready, content)
+}
+
+/// Answer to the requests which would change the data of a read-only server
+pub fn read_only(request: &Request) -> Response {
+    let allowed_methods = [RequestMethod::GET, RequestMethod::HEAD, RequestMethod::OPTIONS];
+    let message = format!(
+        "405 Method Not Allowed, the server is read-only and rejects {} requests",
+        request.method.as_str()
+    );
+
+    let mut response = ResponseBuilder::build_error(request.version.clone(), StatusCode::MethodNotAllowed, &message);
+    response.headers_mut().set("Allow", &format_allow(&allowed_methods));
+    response
+}
+
+/// Leaves the methods which would change the data out of `Allow`, as a
+/// read-only server rejects them whatever the resource
+pub fn allow_reads_only(response: &mut Response) {
+    let allow = match response.headers().get("Allow") {
+        Some(allow) => allow
+            .split(',')
+            .map(str::trim)
+            .filter(|method| !RequestMethod::from(*method).is_write())
+            .collect::<Vec<_>>()
+            .join(", "),
+        None => return
+    };
+    response.headers_mut().set("Allow", &allow);
+}
+
+/// Answer to the requests which need the collections while they are still loading
+pub fn loading(request: &Request) -> Response {
+    let mut response = ResponseBuilder::build_error(
+        request.version.clone(),
+        StatusCode::ServiceUnavailable,
+        "503 Service Unavailable, the collections are still loading"
+    );
+    response.headers_mut().set("Retry-After", "1");
+    response
+}
+
+/// Creates the empty collection named in the body, as in `{ "name": "posts" }`
+pub fn create_collection(
+    request: &Request,
+    jsondb: &JsonDb
+) -> Response {
+    let allowed_methods = [RequestMethod::POST, RequestMethod::OPTIONS];
+    match request.method {
+        RequestMethod::POST => {},
+        RequestMethod::OPTIONS => return options(request, &allowed_methods),
+        _ => return method_not_allowed(request, &allowed_methods)
+    }
+
+    let name = parse_object(request).and_then(|field| {
+        match field.unwrap_as_ref_object().unwrap().read().unwrap().get("name") {
+            Some(JsonField::String(name)) => Some(name.clone()),
+            _ => None
+        }
+    });
+    let name = match name {
+        Some(name) => name,
+        None => {
+            return ResponseBuilder::build_error(
+                request.version.clone(),
+                StatusCode::BadRequest,
+                "Expect request body to be a JSON object with the name of the collection"
+            );
+        }
+    };
+
+    match jsondb.create(&name) {
+        Ok(_) => ResponseBuilder::new()
+            .set_status_code(StatusCode::Created)
+            .set_protocol(request.version.clone())
+            .set_header("Location", &format!("/{name}"))
+            .set_content(format!(r#"{{ "name": "{name}" }}"#))
+            .set_content_type("application/json".to_owned())
+            .build(),
+        Err(err) => collection_error(request, err)
+    }
+}
+
+/// Drops the collection, only once the request confirms it by naming the
+/// collection in `X-Confirm-Delete`
+pub fn drop_collection(
+    request: &Request,
+    jsondb: &JsonDb,
+    name: &str
+) -> Option<Response> {
+    let allowed_methods = [RequestMethod::DELETE, RequestMethod::OPTIONS];
+    match request.method {
+        RequestMethod::DELETE => {},
+        RequestMethod::OPTIONS => return Some(options(request, &allowed_methods)),
+        _ => return Some(method_not_allowed(request, &allowed_methods))
+    }
+
+    // Unknown collections are 404 whether confirmed or not
+    jsondb.get(OsStr::new(name))?;
+    if request.headers.get("X-Confirm-Delete") != Some(name) {
+        return Some(ResponseBuilder::build_error(
+            request.version.clone(),
+            StatusCode::PreconditionRequired,
+            "428 Precondition Required, send X-Confirm-Delete with the name of the collection"
+        ));
+    }
+
+    match jsondb.remove(name) {
+        Ok(_) => Some(ResponseBuilder::new()
+            .set_status_code(StatusCode::NoContent)
+            .set_protocol(request.version.clone())
+            .build()),
+        Err(DbCollectionError::NotFound) => None,
+        Err(err) => Some(collection_error(request, err))
+    }
+}
+
+/// Answer to a collection which couldn't be created or dropped
+pub fn collection_error(request: &Request, err: DbCollectionError) -> Response {
+    let version = request.version.clone();
+    match err {
+        DbCollectionError::InvalidName => ResponseBuilder::build_error(
+            version,
+            StatusCode::BadRequest,
+            "Collection names may only contain letters, digits, - and _, namespaced by /, and can't start with __"
+        ),
+        DbCollectionError::AlreadyExists => ResponseBuilder::build_error(
+            version,
+            StatusCode::Conflict,
+            "409 Conflict, the collection already exists"
+        ),
+        DbCollectionError::NotFound => ResponseBuilder::build_404(version),
+        DbCollectionError::Io(err) => {
+            eprintln!("Failed to create or drop collection: {err}");
+            ResponseBuilder::build_error(version, StatusCode::InternalServerError, "500 Internal Server Error")
+        }
+    }
+}
+
+/// Restores the collections to their content as loaded, or to the snapshot
+/// named in the body as in `{ "snapshot": "seeded", "collections": ["users"] }`.
+/// Every collection is restored when none are named
+pub fn reset(
+    request: &Request,
+    jsondb: &JsonDb
+) -> Response {
+    let allowed_methods = [RequestMethod::POST, RequestMethod::OPTIONS];
+    match request.method {
+        RequestMethod::POST => {},
+        RequestMethod::OPTIONS => return options(request, &allowed_methods),
+        _ => return method_not_allowed(request, &allowed_methods)
+    }
+
+    let invalid_body = || ResponseBuilder::build_error(
+        request.version.clone(),
+        StatusCode::BadRequest,
+        "Expect request body to be empty, or a JSON object with the snapshot and the array of collections to reset"
+    );
+
+    let (mut snapshot, mut names) = (None, None);
+    if request.body.as_deref().is_some_and(|body| !body.trim().is_empty()) {
+        let field = match parse_object(request) {
+            Some(field) => field,
+            None => return invalid_body()
+        };
+        let obj = field.unwrap_as_ref_object().unwrap().read().unwrap();
+
+        snapshot = match obj.get("snapshot") {
+            Some(JsonField::String(snapshot)) => Some(snapshot.clone()),
+            Some(JsonField::Null) | None => None,
+            Some(_) => return invalid_body()
+        };
+        names = match obj.get("collections") {
+            Some(JsonField::Array(collections)) => {
+                let collections = collections.read().unwrap();
+                let names: Option<Vec<String>> = collections
+                    .iter()
+                    .map(|name| match name {
+                        JsonField::String(name) => Some(name.clone()),
+                        _ => None
+                    })
+                    .collect();
+                match names {
+                    Some(names) => Some(names),
+                    None => return invalid_body()
+                }
+            },
+            Some(JsonField::Null) | None => None,
+            Some(_) => return invalid_body()
+        };
+    }
+
+    match jsondb.reset(names.as_deref(), snapshot.as_deref()) {
+        Ok(names) => {
+            let names: Vec<String> = names.iter().map(|name| format!(r#""{}""#, escape_json(name))).collect();
+            let snapshot = match &snapshot {
+                Some(snapshot) => format!(r#""{}""#, escape_json(snapshot)),
+                None => "null".to_owned()
+            };
+
+            ResponseBuilder::new()
+                .set_status_code(StatusCode::Ok)
+                .set_protocol(request.version.clone())
+                .set_content(format!(r#"{{ "reset": [{}], "snapshot": {snapshot} }}"#, names.join(", ")))
+                .set_content_type("application/json".to_owned())
+                .build()
+        },
+        Err(err) => snapshot_error(request, err)
+    }
+}
+
+/// Lists the snapshots, or takes one of every collection under the name
+/// given in the body, as in `{ "name": "seeded" }`
+pub fn snapshots(
+    request: &Request,
+    jsondb: &JsonDb
+) -> Response {
+    let allowed_methods = [RequestMethod::GET, RequestMethod::HEAD, RequestMethod::POST, RequestMethod::OPTIONS];
+    match request.method {
+        RequestMethod::GET | RequestMethod::HEAD => {
+            let names: Vec<String> = jsondb
+                .snapshots()
+                .iter()
+                .map(|name| format!(r#""{name}""#))
+                .collect();
+
+            return ResponseBuilder::new()
+                .set_status_code(StatusCode::Ok)
+                .set_protocol(request.version.clone())
+                .set_content(format!(r#"{{ "snapshots": [{}] }}"#, names.join(", ")))
+                .set_content_type("application/json".to_owned())
+                .build();
+        },
+        RequestMethod::POST => {},
+        RequestMethod::OPTIONS => return options(request, &allowed_methods),
+        _ => return method_not_allowed(request, &allowed_methods)
+    }
+
+    let name = parse_object(request).and_then(|field| {
+        match field.unwrap_as_ref_object().unwrap().read().unwrap().get("name") {
+            Some(JsonField::String(name)) => Some(name.clone()),
+            _ => None
+        }
+    });
+    let name = match name {
+        Some(name) => name,
+        None => {
+            return ResponseBuilder::build_error(
+                request.version.clone(),
+                StatusCode::BadRequest,
+                "Expect request body to be a JSON object with the name of the snapshot"
+            );
+        }
+    };
+
+    match jsondb.snapshot(&name) {
+        Ok(_) => ResponseBuilder::new()
+            .set_status_code(StatusCode::Created)
+            .set_protocol(request.version.clone())
+            .set_header("Location", &format!("/__snapshots/{name}"))
+            .set_content(format!(r#"{{ "name": "{name}" }}"#))
+            .set_content_type("application/json".to_owned())
+            .build(),
+        Err(err) => snapshot_error(request, err)
+    }
+}
+
+pub fn drop_snapshot(
+    request: &Request,
+    jsondb: &JsonDb,
+    name: &str
+) -> Option<Response> {
+    let allowed_methods = [RequestMethod::DELETE, RequestMethod::OPTIONS];
+    match request.method {
+        RequestMethod::DELETE => {},
+        RequestMethod::OPTIONS => return Some(options(request, &allowed_methods)),
+        _ => return Some(method_not_allowed(request, &allowed_methods))
+    }
+
+    jsondb.remove_snapshot(name).ok()?;
+    Some(ResponseBuilder::new()
+        .set_status_code(StatusCode::NoContent)
+        .set_protocol(request.version.clone())
+        .build())
+}
+
+fn snapshot_error(request: &Request, err: DbSnapshotError) -> Response {
+    let version = request.version.clone();
+    match err {
+        DbSnapshotError::InvalidName => ResponseBuilder::build_error(
+            version,
+            StatusCode::BadRequest,
+            "Snapshot names may only contain letters, digits, - and _"
+        ),
+        DbSnapshotError::AlreadyExists => ResponseBuilder::build_error(
+            version,
+            StatusCode::Conflict,
+            "409 Conflict, the snapshot already exists"
+        ),
+        DbSnapshotError::NotFound => ResponseBuilder::build_error(
+            version,
+            StatusCode::NotFound,
+            "404 Not Found, there is no such snapshot"
+        ),
+        DbSnapshotError::CollectionNotFound(name) => ResponseBuilder::build_error(
+            version,
+            StatusCode::NotFound,
+            &escape_json(&format!("404 Not Found, the collection {name:?} doesn't exist or isn't part of the snapshot"))
+        ),
+        DbSnapshotError::Mismatch(name) => ResponseBuilder::build_error(
+            version,
+            StatusCode::Conflict,
+            &escape_json(&format!("409 Conflict, the collection {name:?} no longer has the shape it had in the snapshot"))
+        )
+    }
 }
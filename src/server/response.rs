@@ -1,72 +1,240 @@
-use std::net::TcpStream;
-use std::io::prelude::*;
-use std::time::Instant;
+use std::fs::File;
+use std::io::{self, prelude::*};
 
+use super::chunked::ChunkedWriter;
+use super::headers::Headers;
 use super::status_code::StatusCode;
 
+/// Body which is serialized straight into the connection instead of being
+/// built up front, sent with `Transfer-Encoding: chunked`
+pub type StreamBody = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+/// Headers describing the body framing, always derived from the body itself
+const FRAMING_HEADERS: [&str; 2] = ["Content-Length", "Transfer-Encoding"];
+
+pub enum ResponseBody {
+    Content(String),
+    Bytes(Vec<u8>),
+    Stream(StreamBody),
+    /// Bytes of the file from its current position on, only read as it is sent
+    File(File, u64)
+}
 
 pub struct Response {
-    content_length: usize,
-    content_type: Option<String>,
-    content: String,
+    headers: Headers,
+    body: ResponseBody,
+    omit_body: bool,
     protocol: String,
     status_code: StatusCode
 }
 
 impl Response {
-    pub fn format(&self) -> String {
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &ResponseBody {
+        &self.body
+    }
+
+        pub fn body_mut(&mut self) -> &mut ResponseBody {
+        &mut self.body
+    }
+
+    pub fn status_code(&self) -> &StatusCode {
+        &self.status_code
+    }
+
+    /// Length of the body, `None` when it is streamed
+    pub fn content_length(&self) -> Option<usize> {
+        match &self.body {
+            ResponseBody::Content(content) => Some(content.len()),
+            ResponseBody::Bytes(bytes) => Some(bytes.len()),
+            ResponseBody::Stream(_) => None,
+            ResponseBody::File(_, len) => Some(*len as usize)
+        }
+    }
+
+    /// Keeps the headers describing the body but doesn't send the body itself,
+    /// which is how HEAD requests are answered
+    pub fn omit_body(&mut self) {
+        self.omit_body = true;
+    }
+
+    /// Start of the body as text for logging, at most `limit` bytes of it.
+    /// `None` when no body is sent
+    pub fn body_preview(&self, limit: usize) -> Option<String> {
+        if self.omit_body || matches!(self.status_code, StatusCode::NoContent | StatusCode::NotModified) {
+            return None;
+        }
+
+        let content = match &self.body {
+            ResponseBody::Content(content) => content.as_bytes(),
+            ResponseBody::Bytes(bytes) => match self.headers.get("Content-Encoding") {
+                Some(encoding) => return Some(format!("({} bytes of {encoding} encoded content)", bytes.len())),
+                None => bytes
+            },
+            ResponseBody::Stream(_) => return Some("(streamed content)".to_owned()),
+            ResponseBody::File(_, len) => return Some(format!("({len} bytes of file content)"))
+        };
+
+        if content.len() <= limit {
+            return Some(String::from_utf8_lossy(content).into_owned());
+        }
+        Some(format!("{}... ({} bytes in total)", String::from_utf8_lossy(&content[..limit]), content.len()))
+    }
+
+    pub fn format_head(&self) -> String {
         let new_line: String = String::from("\r\n");
 
         let mut response = format!("{} {}", self.protocol, self.status_code.get_desc());
         response.push_str(&new_line);
-        response.push_str(&format!("Content-Length: {}", self.content_length));
-        response.push_str(&new_line);
-        if self.content_type.is_some() {
-            response.push_str(&format!("Content-Type: {}", self.content_type.as_ref().unwrap()));
+        match &self.body {
+            // 204 and 304 responses must not carry any framing header
+            _ if matches!(self.status_code, StatusCode::NoContent | StatusCode::NotModified) => {},
+            ResponseBody::Content(content) => {
+                response.push_str(&format!("Content-Length: {}", content.len()));
+                response.push_str(&new_line);
+            },
+            ResponseBody::Bytes(bytes) => {
+                response.push_str(&format!("Content-Length: {}", bytes.len()));
+                response.push_str(&new_line);
+            },
+            ResponseBody::Stream(_) => {
+                response.push_str("Transfer-Encoding: chunked");
+                response.push_str(&new_line);
+            },
+            ResponseBody::File(_, len) => {
+                response.push_str(&format!("Content-Length: {len}"));
+                response.push_str(&new_line);
+            }
+        }
+        for (key, value) in self.headers.iter() {
+            if FRAMING_HEADERS.iter().any(|name| key.eq_ignore_ascii_case(name)) { continue; }
+            // The body of a 304 is only there to describe the representation, not sent
+            if self.status_code == StatusCode::NotModified && key.eq_ignore_ascii_case("Content-Type") { continue; }
+            response.push_str(&format!("{key}: {value}"));
             response.push_str(&new_line);
         }
         response.push_str(&new_line);
-        response.push_str(&self.content);
 
         response
     }
 
-    pub fn not_found(version: String, start_time: Instant, mut stream: TcpStream) {
-        let response = ResponseBuilder::build_404(version);
-        match stream.write_all(response.format().as_bytes()) {
-            Ok(_) => println!("404: {:?}", Instant::now() - start_time),
-            Err(err) => println!("{err:?}")
+    /// Writes the response, returns the number of bytes of the body sent
+    /// (not counting the chunked framing of streamed bodies)
+    pub fn send<W: Write + ?Sized>(self, stream: &mut W) -> io::Result<usize> {
+        stream.write_all(self.format_head().as_bytes())?;
+        if self.omit_body || self.status_code == StatusCode::NotModified { return Ok(0); }
+
+        match self.body {
+            ResponseBody::Content(content) => {
+                stream.write_all(content.as_bytes())?;
+                Ok(content.len())
+            },
+            ResponseBody::Bytes(bytes) => {
+                stream.write_all(&bytes)?;
+                Ok(bytes.len())
+            },
+            ResponseBody::Stream(write_body) => {
+                let mut writer = ByteCounter { inner: ChunkedWriter::new(stream), count: 0 };
+                write_body(&mut writer)?;
+                writer.inner.finish()?;
+                Ok(writer.count)
+            },
+            ResponseBody::File(file, len) => {
+                let sent = io::copy(&mut file.take(len), stream)?;
+                // The length is announced already, a file which shrank meanwhile can't make up for it
+                if sent < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(sent as usize)
+            }
         }
     }
 }
 
+/// Counts the bytes written through it
+struct ByteCounter<W: Write> {
+    inner: W,
+    count: usize
+}
+
+impl<W: Write> Write for ByteCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub struct ResponseBuilder {
-    content_length: usize,
-    content: String,
+    body: ResponseBody,
     protocol: String,
     status_code: StatusCode,
-    content_type: Option<String>
+    headers: Headers
+}
+
+impl Default for ResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ResponseBuilder {
     pub fn new() -> Self {
         Self {
-            content: "".to_owned(),
-            content_length: 0,
-            content_type: None,
+            body: ResponseBody::Content("".to_owned()),
+            headers: Headers::new(),
             protocol: "".to_owned(),
             status_code: StatusCode::Ok
         }
     }
 
-    pub fn set_content(mut self, content: String ) -> Self {
-        self.content_length = content.len();
-        self.content = content;
+    pub fn set_content(mut self, content: String) -> Self {
+        self.body = ResponseBody::Content(content);
+        self
+    }
+
+    pub fn set_bytes(mut self, bytes: Vec<u8>) -> Self {
+        self.body = ResponseBody::Bytes(bytes);
+        self
+    }
+
+    /// Sends `len` bytes of the file from its current position
+    pub fn set_file(mut self, file: File, len: u64) -> Self {
+        self.body = ResponseBody::File(file, len);
+        self
+    }
+
+    pub fn set_stream(mut self, write_body: StreamBody) -> Self {
+        self.body = ResponseBody::Stream(write_body);
         self
     }
 
     pub fn set_content_type(mut self, content_type: String) -> Self {
-        self.content_type = Some(content_type);
+        self.headers.set("Content-Type", &content_type);
+        self
+    }
+
+    /// Sets the header, replacing any value set before. `Content-Length` and
+    /// `Transfer-Encoding` are derived from the body and ignored if set here
+    pub fn set_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.set(name, value);
+        self
+    }
+
+    /// Adds another value to the header, keeping the values set before
+    pub fn append_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
         self
     }
 
@@ -82,14 +250,25 @@ impl ResponseBuilder {
 
     pub fn build(self) -> Response {
         Response {
-            content: self.content,
-            content_length: self.content_length,
-            content_type: self.content_type,
+            body: self.body,
+            omit_body: false,
+            headers: self.headers,
             status_code: self.status_code,
             protocol: self.protocol
         }
     }
 
+    /// Builds response with JSON body of `{ "message": ... }`, the message is
+    /// expected to be a fixed string which needs no escaping
+    pub fn build_error(version: String, status_code: StatusCode, message: &str) -> Response {
+        Self::new()
+            .set_status_code(status_code)
+            .set_protocol(version)
+            .set_content(format!(r#"{{ "message": "{message}" }}"#))
+            .set_content_type("application/json".to_owned())
+            .build()
+    }
+
     pub fn build_404(version: String) -> Response {
         Self::new()
             .set_status_code(StatusCode::NotFound)
@@ -0,0 +1,257 @@
+use std::fs;
+use std::path::Path;
+
+use crate::json::field::JsonField;
+use crate::json::parser::{object_keys, parse_json};
+
+/// Rewrite rules loaded from a `routes.json` such as
+/// `{ "/api/v2/*": "/$1", "/blog/:slug": "/posts?slug=:slug" }`,
+/// the first rule in the file matching the path is applied
+#[derive(Debug, Default)]
+pub struct Rewriter {
+    rules: Vec<Rule>
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    tokens: Vec<Token>,
+    target: String
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    /// `:name`, matches one segment of the path
+    Param(String),
+    /// `*`, matches anything including `/`
+    Wildcard
+}
+
+/// Path pattern such as `/users/:id` or `/api/*`, in the syntax of the rewrite rules
+#[derive(Debug)]
+pub struct Pattern {
+    pattern: String,
+    tokens: Vec<Token>
+}
+
+/// Values captured by a rule in order, `$1` is the first one, with the name
+/// of the parameter which captured it
+type Captures<'a> = Vec<(Option<&'a str>, &'a str)>;
+
+impl Rewriter {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let (json, _) = parse_json(content, 0).map_err(|err| err.0)?;
+        let obj = json
+            .unwrap_as_ref_object()
+            .map_err(|_| "Expect routes to be a JSON object of patterns and their targets".to_owned())?
+            .read()
+            .unwrap();
+
+        // Objects don't keep the order of their keys, which decides what rule applies first
+        let mut rules: Vec<Rule> = Vec::new();
+        for pattern in object_keys(content).map_err(|err| err.0)? {
+            // A pattern given twice keeps its last target, at the position of its first
+            if rules.iter().any(|rule| rule.pattern == pattern) { continue; }
+
+            let target = match obj.get(&pattern) {
+                Some(JsonField::String(target)) => target.clone(),
+                _ => return Err(format!("Expect the target of {pattern:?} to be a string"))
+            };
+            rules.push(Rule { tokens: tokenize(&pattern), pattern, target });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules.iter().map(|rule| (rule.pattern.as_str(), rule.target.as_str()))
+    }
+
+    /// Target the path is rewritten to, with the query of the request added
+    /// to the one of the target. `None` when no rule matches
+    pub fn rewrite(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let path = trim_trailing_slash(path);
+
+        self.rules.iter().find_map(|rule| {
+            let mut captures = Vec::new();
+            if !matches(&rule.tokens, path, &mut captures) {
+                return None;
+            }
+
+            let mut target = substitute(&rule.target, &captures);
+            if let Some(query) = query {
+                target.push(if target.contains('?') { '&' } else { '?' });
+                target.push_str(query);
+            }
+            Some(target)
+        })
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Self { pattern: pattern.to_owned(), tokens: tokenize(pattern) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        matches(&self.tokens, trim_trailing_slash(path), &mut Vec::new())
+    }
+}
+
+fn trim_trailing_slash(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '*' => Token::Wildcard,
+            ':' if chars.peek().is_some_and(|c| is_name_char(*c)) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| is_name_char(*c)) {
+                    name.push(c);
+                }
+                Token::Param(name)
+            },
+            c => {
+                literal.push(c);
+                continue;
+            }
+        };
+
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(token);
+    }
+
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    tokens
+}
+
+/// Matches the whole of the path, backtracking over what parameters and wildcards capture
+fn matches<'a>(tokens: &'a [Token], path: &'a str, captures: &mut Captures<'a>) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return path.is_empty()
+    };
+
+    let (name, max_len) = match token {
+        Token::Literal(literal) => {
+            return path.strip_prefix(literal.as_str()).is_some_and(|path| matches(rest, path, captures));
+        },
+        Token::Param(name) => (Some(name.as_str()), path.find('/').unwrap_or(path.len())),
+        Token::Wildcard => (None, path.len())
+    };
+    let min_len = if name.is_some() { 1 } else { 0 };
+
+    for len in (min_len..=max_len).rev().filter(|len| path.is_char_boundary(*len)) {
+        captures.push((name, &path[..len]));
+        if matches(rest, &path[len..], captures) {
+            return true;
+        }
+        captures.pop();
+    }
+    false
+}
+
+/// Replaces `$1`, `$2`, ... and `:name` in the target with the captured values
+fn substitute(target: &str, captures: &Captures) -> String {
+    let mut result = String::new();
+    let mut chars = target.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '$' if chars.peek().is_some_and(char::is_ascii_digit) => {
+                let mut index = String::new();
+                while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                    index.push(c);
+                }
+                let capture = index.parse::<usize>().ok().and_then(|index| captures.get(index.checked_sub(1)?));
+                match capture {
+                    Some((_, value)) => result.push_str(value),
+                    None => { result.push('$'); result.push_str(&index); }
+                }
+            },
+            ':' if chars.peek().is_some_and(|c| is_name_char(*c)) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| is_name_char(*c)) {
+                    name.push(c);
+                }
+                match captures.iter().find(|(param, _)| *param == Some(name.as_str())) {
+                    Some((_, value)) => result.push_str(value),
+                    None => { result.push(':'); result.push_str(&name); }
+                }
+            },
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_rewrites_wildcards_and_params() {
+        let rewriter = Rewriter::parse(r#"{
+            "/api/v2/*": "/$1",
+            "/blog/:slug": "/posts?slug=:slug",
+            "/:resource/:id/show": "/:resource/:id"
+        }"#).unwrap();
+
+        assert_eq!(rewriter.rewrite("/api/v2/users/1", None).as_deref(), Some("/users/1"));
+        assert_eq!(rewriter.rewrite("/blog/hello", Some("page=2")).as_deref(), Some("/posts?slug=hello&page=2"));
+        assert_eq!(rewriter.rewrite("/posts/3/show/", None).as_deref(), Some("/posts/3"));
+        assert_eq!(rewriter.rewrite("/blog/a/b", None), None);
+    }
+
+    #[test]
+    fn it_applies_the_first_matching_rule_in_the_file() {
+        let rewriter = Rewriter::parse(r#"{ "/a/*": "/first", "/a/b": "/second", "/b": "/a/b" }"#).unwrap();
+
+        assert_eq!(rewriter.rewrite("/a/b", None).as_deref(), Some("/first"));
+        assert_eq!(rewriter.rules().map(|(pattern, _)| pattern).collect::<Vec<_>>(), ["/a/*", "/a/b", "/b"]);
+    }
+
+    #[test]
+    fn it_keeps_the_order_of_rules_whatever_their_text() {
+        let rewriter = Rewriter::parse(r#"{
+            "/z/*": "/:a:",
+            "/y/\\*": "/1",
+            "/x": "/y/\\*",
+            "/w": "/2",
+            "/z/*": "/3"
+        }"#).unwrap();
+
+        assert_eq!(
+            rewriter.rules().collect::<Vec<_>>(),
+            [("/z/*", "/3"), ("/y/\\\\*", "/1"), ("/x", "/y/\\\\*"), ("/w", "/2")]
+        );
+    }
+}
+
@@ -0,0 +1,407 @@
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::db::checksum::Checksum;
+
+use super::conditional::Validators;
+use super::request::{Request, RequestMethod};
+use super::response::{Response, ResponseBuilder};
+use super::status_code::StatusCode;
+
+/// Files of a public directory, such as a built single page app, served
+/// for the GET and HEAD requests which don't match any collection
+#[derive(Debug)]
+pub struct StaticFiles {
+    /// Canonical path, files have to resolve within it
+    root: PathBuf
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one which isn't supported such as multiple ranges
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable
+}
+
+impl StaticFiles {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+        }
+
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Response with the file the request points at, `None` when there's no such file.
+    /// Paths without an extension fall back to `index.html` when `is_app_route` is set
+    pub fn serve(&self, request: &Request, is_app_route: bool) -> Option<Response> {
+        if !matches!(request.method, RequestMethod::GET | RequestMethod::HEAD) {
+            return None;
+        }
+
+        let path = percent_decode(request.url.to_str()?)?;
+        // Refused outright, rather than falling back to the app
+        if !is_safe(&path) {
+            return None;
+        }
+
+        let file_path = match self.resolve(&path) {
+            Some(file_path) => file_path,
+            // Paths without an extension are routes of the app rather than missing files
+            None if is_app_route && !path.rsplit('/').next().unwrap_or("").contains('.') => self.resolve("/index.html")?,
+            None => return None
+        };
+
+        let mut file = File::open(&file_path).ok()?;
+        let metadata = file.metadata().ok()?;
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+
+        let mut checksum = Checksum::new();
+        checksum.update(&metadata.len().to_le_bytes());
+        checksum.update(&modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_le_bytes());
+        let validators = Validators::new(checksum.value(), modified);
+
+        let content_type = content_type(&file_path);
+        // Pages have to be revalidated so that they pick up newly built assets
+        let cache_control = if content_type.starts_with("text/html") { "no-cache" } else { "public, max-age=3600" };
+
+        let builder = ResponseBuilder::new()
+            .set_protocol(request.version.clone())
+            .set_header("ETag", &validators.etag)
+            .set_header("Last-Modified", &validators.last_modified_date())
+            .set_header("Cache-Control", cache_control);
+
+        // Only the part of the file sent is read, as it is sent
+        let len = metadata.len();
+        let builder = builder.set_content_type(content_type.to_owned());
+
+        // The file is kept though never sent, see `request_handler::status_code`
+        if validators.is_not_modified(request) {
+            return Some(builder.set_status_code(StatusCode::NotModified).set_file(file, len).build());
+        }
+        let builder = builder.set_header("Accept-Ranges", "bytes");
+
+        let range = match request.headers.get("Range") {
+            Some(range) if validators.is_range_current(request) => parse_range(range, len),
+            _ => ByteRange::Full
+        };
+
+        let response = match range {
+            ByteRange::Full => builder
+                .set_status_code(StatusCode::Ok)
+                .set_file(file, len),
+            ByteRange::Partial(first, last) => {
+                file.seek(SeekFrom::Start(first)).ok()?;
+                builder
+                    .set_status_code(StatusCode::PartialContent)
+                    .set_header("Content-Range", &format!("bytes {first}-{last}/{len}"))
+                    .set_file(file, last - first + 1)
+            },
+            ByteRange::Unsatisfiable => builder
+                .set_status_code(StatusCode::RangeNotSatisfiable)
+                .set_header("Content-Range", &format!("bytes */{len}"))
+        };
+
+        Some(response.build())
+    }
+
+    /// File the path points at within the root, the `index.html` of directories.
+    /// Symlinks leading out of the root are refused
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        file.extend(path.split('/').filter(|segment| !segment.is_empty()));
+
+        if file.is_dir() {
+            file.push("index.html");
+        }
+
+        let file = file.canonicalize().ok()?;
+        (file.starts_with(&self.root) && file.is_file()).then_some(file)
+    }
+}
+
+/// Hidden files and `..` are never served
+fn is_safe(path: &str) -> bool {
+    path.split('/').all(|segment| !segment.starts_with('.') && !segment.contains('\\') && !segment.contains('\0'))
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream"
+    }
+}
+
+/// Parses a single `bytes=first-last`, `bytes=first-` or `bytes=-suffix` range
+fn parse_range(value: &str, len: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full
+    };
+
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => (len.saturating_sub(suffix), len.saturating_sub(1)),
+        _ => return ByteRange::Full
+    };
+
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last)
+}
+
+/// Decodes the `%XX` escapes of the path, `None` when it isn't valid UTF-8 once decoded
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match escaped {
+            Some(byte) => { decoded.push(byte); index += 3; },
+            None => { decoded.push(bytes[index]); index += 1; }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use crate::test_dir::TestDir;
+    use super::super::compression::CompressionConfig;
+    use super::super::response::ResponseBody;
+
+    fn public() -> (TestDir, StaticFiles) {
+        let dir = TestDir::new();
+        dir.write("public/index.html", "<h1>App</h1>");
+        dir.write("public/app.js", "0123456789");
+        dir.write("public/docs/index.html", "<h1>Docs</h1>");
+        dir.write("public/.env", "SECRET=1");
+        dir.write("secret.txt", "SECRET=1");
+        let files = StaticFiles::new(&dir.path().join("public")).unwrap();
+        (dir, files)
+    }
+
+    fn request(message: &str) -> Request {
+        Request::new(&mut message.as_bytes(), Instant::now(), &Default::default()).unwrap()
+    }
+
+    fn send(response: Response) -> String {
+        let mut sent = Vec::new();
+        response.send(&mut sent).unwrap();
+        String::from_utf8_lossy(&sent).into_owned()
+    }
+
+    fn get(files: &StaticFiles, target: &str, headers: &str) -> Option<String> {
+        files.serve(&request(&format!("GET {target} HTTP/1.1\r\n{headers}\r\n")), true).map(send)
+    }
+
+    fn etag(response: &str) -> &str {
+        response.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap()
+    }
+
+    #[test]
+    fn it_parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=50-500", 100), ByteRange::Partial(50, 99));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn it_decodes_percent_escapes() {
+        assert_eq!(percent_decode("/my%20file.txt").as_deref(), Some("/my file.txt"));
+        assert_eq!(percent_decode("/%2e%2e/secret").as_deref(), Some("/../secret"));
+        assert_eq!(percent_decode("/100%").as_deref(), Some("/100%"));
+    }
+
+    #[test]
+    fn it_resolves_files_and_directory_indexes() {
+        let (dir, files) = public();
+        let root = dir.path().join("public").canonicalize().unwrap();
+
+        assert_eq!(files.resolve("/app.js"), Some(root.join("app.js")));
+        assert_eq!(files.resolve("//app.js/"), Some(root.join("app.js")));
+        assert_eq!(files.resolve("/docs"), Some(root.join("docs/index.html")));
+        assert_eq!(files.resolve("/"), Some(root.join("index.html")));
+        assert_eq!(files.resolve("/missing.js"), None);
+    }
+
+    #[test]
+    fn it_refuses_hidden_files_and_parent_directories() {
+        assert!(is_safe("/docs/index.html"));
+        assert!(is_safe("/a..b/c"));
+        for path in ["/.env", "/docs/../.env", "/..", "/a\\b", "/a\0b"] {
+            assert!(!is_safe(path), "{path:?}");
+        }
+
+        let (_dir, files) = public();
+        for target in ["/.env", "/../secret.txt", "/%2e%2e/secret.txt", "/docs/%2E%2E/%2E%2E/secret.txt", "/docs/%2E%2E/.env"] {
+            assert_eq!(get(&files, target, ""), None, "{target}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_refuses_symlinks_leading_out_of_the_root() {
+        let (dir, files) = public();
+        let public_dir = dir.path().join("public");
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), public_dir.join("secret.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), public_dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(public_dir.join("app.js"), public_dir.join("linked.js")).unwrap();
+
+        assert_eq!(get(&files, "/secret.txt", ""), None);
+        assert_eq!(get(&files, "/outside/secret.txt", ""), None);
+        assert!(get(&files, "/linked.js", "").unwrap().ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[test]
+    fn it_falls_back_to_the_index_for_app_routes() {
+        let (_dir, files) = public();
+
+        let route = get(&files, "/users/1", "").unwrap();
+        assert!(route.starts_with("HTTP/1.1 200 OK\r\n"), "{route}");
+        assert!(route.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(route.ends_with("<h1>App</h1>"));
+        // Looks like a file, which is missing
+        assert_eq!(get(&files, "/missing.js", ""), None);
+        assert!(files.serve(&request("POST /app.js HTTP/1.1\r\n\r\n"), true).is_none());
+    }
+
+    #[test]
+    fn it_serves_ranges_of_files() {
+        let (_dir, files) = public();
+
+        let partial = get(&files, "/app.js", "Range: bytes=2-4\r\n").unwrap();
+        assert!(partial.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{partial}");
+        assert!(partial.contains("Content-Length: 3\r\n"));
+        assert!(partial.contains("Content-Range: bytes 2-4/10\r\n"));
+        assert!(partial.ends_with("\r\n\r\n234"));
+
+        let suffix = get(&files, "/app.js", "Range: bytes=-3\r\n").unwrap();
+        assert!(suffix.ends_with("\r\n\r\n789"));
+
+        let unsatisfiable = get(&files, "/app.js", "Range: bytes=10-\r\n").unwrap();
+        assert!(unsatisfiable.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"), "{unsatisfiable}");
+        assert!(unsatisfiable.contains("Content-Range: bytes */10\r\n"));
+    }
+
+    #[test]
+    fn it_only_serves_ranges_of_the_same_version_with_if_range() {
+        let (_dir, files) = public();
+        let full = get(&files, "/app.js", "").unwrap();
+        let etag = etag(&full);
+
+        let current = get(&files, "/app.js", &format!("Range: bytes=0-1\r\nIf-Range: {etag}\r\n")).unwrap();
+        assert!(current.ends_with("\r\n\r\n01"), "{current}");
+
+        for if_range in ["\"0000000000000000\"", &format!("W/{etag}"), "Sun, 06 Nov 1994 08:49:37 GMT"] {
+            let stale = get(&files, "/app.js", &format!("Range: bytes=0-1\r\nIf-Range: {if_range}\r\n")).unwrap();
+            assert!(stale.starts_with("HTTP/1.1 200 OK\r\n"), "{stale}");
+            assert!(stale.ends_with("\r\n\r\n0123456789"));
+        }
+
+        let not_modified = get(&files, "/app.js", &format!("If-None-Match: {etag}\r\n")).unwrap();
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{not_modified}");
+    }
+
+    #[test]
+    fn it_announces_the_length_of_files_without_reading_them_for_head() {
+        let (_dir, files) = public();
+
+        let mut response = files.serve(&request("HEAD /app.js HTTP/1.1\r\n\r\n"), true).unwrap();
+        assert!(matches!(response.body_mut(), ResponseBody::File(_, 10)));
+        response.omit_body();
+        let head = send(response);
+        assert!(head.contains("Content-Length: 10\r\n"), "{head}");
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn it_compresses_large_files_as_they_are_sent() {
+        let (dir, files) = public();
+        dir.write("public/large.js", &"console.log(1);\n".repeat(1000));
+        let large = request("GET /large.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+
+        let mut response = files.serve(&large, true).unwrap();
+        CompressionConfig::default().apply(&large, &mut response);
+        let sent = send(response);
+        assert!(sent.contains("Transfer-Encoding: chunked\r\n"), "{sent}");
+        assert!(sent.contains("Content-Encoding: gzip\r\n"));
+        assert!(sent.len() < 16000);
+
+        // Small ones are sent as they are
+        let small = request("GET /app.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let mut response = files.serve(&small, true).unwrap();
+        CompressionConfig::default().apply(&small, &mut response);
+        let sent = send(response);
+        assert!(sent.contains("Content-Length: 10\r\n"), "{sent}");
+        assert!(!sent.contains("Content-Encoding"));
+        assert!(sent.ends_with("\r\n\r\n0123456789"));
+
+        // HTTP/1.0 can't frame the compressed stream
+        let large = request("GET /large.js HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n");
+        let mut response = files.serve(&large, true).unwrap();
+        CompressionConfig::default().apply(&large, &mut response);
+        let sent = send(response);
+        assert!(sent.contains("Content-Length: 16000\r\n"), "{sent}");
+        assert!(!sent.contains("Transfer-Encoding"));
+        assert!(!sent.contains("Content-Encoding"));
+        assert!(!sent.contains("ETag: W/"));
+    }
+}
+
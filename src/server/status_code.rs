@@ -1,22 +1,103 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StatusCode {
     Ok,
-    NotFound
+    Created,
+    NoContent,
+    PartialContent,
+    NotModified,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    PreconditionFailed,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    PreconditionRequired,
+    TooManyRequests,
+    InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout
 }
 
 impl StatusCode {
     pub fn get_value(&self) -> usize {
         match self {
             Self::Ok => 200,
-            Self::NotFound => 404
+            Self::Created => 201,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::NotModified => 304,
+            Self::BadRequest => 400,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::Conflict => 409,
+            Self::PreconditionFailed => 412,
+            Self::PayloadTooLarge => 413,
+            Self::RangeNotSatisfiable => 416,
+            Self::PreconditionRequired => 428,
+            Self::TooManyRequests => 429,
+            Self::InternalServerError => 500,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::GatewayTimeout => 504
         }
     }
 
+    /// Status code of the value, `None` for the ones the server never answers with
+    pub fn from_value(value: usize) -> Option<Self> {
+        let status_code = match value {
+            200 => Self::Ok,
+            201 => Self::Created,
+            204 => Self::NoContent,
+            206 => Self::PartialContent,
+            304 => Self::NotModified,
+            400 => Self::BadRequest,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            405 => Self::MethodNotAllowed,
+            408 => Self::RequestTimeout,
+            409 => Self::Conflict,
+            412 => Self::PreconditionFailed,
+            413 => Self::PayloadTooLarge,
+            416 => Self::RangeNotSatisfiable,
+            428 => Self::PreconditionRequired,
+            429 => Self::TooManyRequests,
+            500 => Self::InternalServerError,
+            502 => Self::BadGateway,
+            503 => Self::ServiceUnavailable,
+            504 => Self::GatewayTimeout,
+            _ => return None
+        };
+        Some(status_code)
+    }
+
     pub fn get_desc(&self) -> &str {
         match self {
             Self::Ok => "200 OK",
-            Self::NotFound => "404 Not Found"
+            Self::Created => "201 Created",
+            Self::NoContent => "204 No Content",
+            Self::PartialContent => "206 Partial Content",
+            Self::NotModified => "304 Not Modified",
+            Self::BadRequest => "400 Bad Request",
+            Self::Forbidden => "403 Forbidden",
+            Self::NotFound => "404 Not Found",
+            Self::MethodNotAllowed => "405 Method Not Allowed",
+            Self::RequestTimeout => "408 Request Timeout",
+            Self::Conflict => "409 Conflict",
+            Self::PreconditionFailed => "412 Precondition Failed",
+            Self::PayloadTooLarge => "413 Payload Too Large",
+            Self::RangeNotSatisfiable => "416 Range Not Satisfiable",
+            Self::PreconditionRequired => "428 Precondition Required",
+            Self::TooManyRequests => "429 Too Many Requests",
+            Self::InternalServerError => "500 Internal Server Error",
+            Self::BadGateway => "502 Bad Gateway",
+            Self::ServiceUnavailable => "503 Service Unavailable",
+            Self::GatewayTimeout => "504 Gateway Timeout"
         }
     }
 }
-
@@ -0,0 +1,270 @@
+use std::fs;
+use std::io::{self, prelude::*, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    io::{AsRawFd, RawFd},
+    net::{UnixListener, UnixStream}
+};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::request::RequestReader;
+
+/// Listening socket of the server, either TCP or a Unix domain socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf)
+}
+
+/// Accepted client connection, the request handling pipeline only relies on it
+/// being readable and writable so both kinds of sockets are served the same way
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl Listener {
+    /// Binds a Unix domain socket at the path, a stale socket file left behind
+    /// by a previous run is removed but a socket still in use, or any other
+    /// kind of file, is never taken over
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> Result<Self, String> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{path:?} exists and is not a socket"));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("Socket {path:?} is already in use by another server"));
+            }
+            fs::remove_file(path)
+                .map_err(|err| format!("Unable to remove stale socket {path:?}: {err}"))?;
+        }
+
+        let listener = UnixListener::bind(path).map_err(|err| match err.kind() {
+            ErrorKind::PermissionDenied => format!("Permission denied to create socket {path:?}"),
+            ErrorKind::NotFound => format!("Directory of socket {path:?} doesn't exist"),
+            _ => format!("Unable to listen on socket {path:?}: {err}")
+        })?;
+
+        Ok(Self::Unix(listener, path.to_owned()))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener.accept().map(|(stream, _)| Stream::Unix(stream))
+        }
+    }
+
+    /// Bound address of TCP listener, `None` for Unix domain socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(..) => None
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener.set_nonblocking(nonblocking)
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(err) => format!("an unknown address ({err})")
+            },
+            #[cfg(unix)]
+            Self::Unix(_, path) => format!("unix:{}", path.display())
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout)
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_write_timeout(timeout)
+        }
+    }
+
+    /// IP address of the client as access logs show it, "-" when unknown
+    /// such as for clients connected through a Unix domain socket
+    pub fn remote_addr(&self) -> String {
+        match self {
+            Self::Tcp(stream) => stream
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|_| "-".to_owned()),
+            #[cfg(unix)]
+            Self::Unix(_) => "-".to_owned()
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking)
+        }
+    }
+}
+
+/// Reads the request off a blocking stream, the headers and the body each have
+/// to arrive within their own timeout no matter how the client trickles them in
+pub struct DeadlineReader<'a> {
+    stream: &'a mut Stream,
+    deadline: Instant,
+    body_timeout: Duration
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub fn new(stream: &'a mut Stream, header_timeout: Duration, body_timeout: Duration) -> Self {
+        Self { stream, deadline: Instant::now() + header_timeout, body_timeout }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+impl RequestReader for DeadlineReader<'_> {
+    fn start_body(&mut self) {
+        self.deadline = Instant::now() + self.body_timeout;
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener, _) => listener.as_raw_fd()
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(stream) => stream.as_raw_fd(),
+            Self::Unix(stream) => stream.as_raw_fd()
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush()
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn it_serves_unix_socket_and_removes_it_once_dropped() {
+        let dir = TestDir::new();
+        let path = dir.path().join("server.sock");
+
+        let listener = Listener::bind_unix(&path).unwrap();
+        assert_eq!(listener.describe(), format!("unix:{}", path.display()));
+        assert_eq!(listener.local_addr(), None);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(stream.remote_addr(), "-");
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_replaces_stale_socket_but_not_one_in_use() {
+        let dir = TestDir::new();
+        let path = dir.path().join("server.sock");
+
+        // Left behind as by a crashed server, nothing accepts on it anymore
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind_unix(&path).unwrap();
+
+        let err = Listener::bind_unix(&path).err().unwrap();
+        assert_eq!(err, format!("Socket {path:?} is already in use by another server"));
+        drop(listener);
+
+        // A file given by mistake is left alone
+        let file = dir.write("db.json", "[]");
+        let err = Listener::bind_unix(&file).err().unwrap();
+        assert_eq!(err, format!("{file:?} exists and is not a socket"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "[]");
+
+        let err = Listener::bind_unix(&dir.path().join("missing/server.sock")).err().unwrap();
+        assert!(err.starts_with("Directory of socket"), "{err}");
+    }
+}
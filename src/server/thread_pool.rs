@@ -1,5 +1,7 @@
 use std::{
+    panic::{self, AssertUnwindSafe},
     sync::{
+        atomic::{AtomicUsize, Ordering},
         mpsc::{self, Receiver},
         Mutex,
         Arc
@@ -9,11 +11,39 @@ use std::{
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>
+    sender: Option<mpsc::Sender<Job>>,
+    stats: Arc<PoolStats>
+}
+
+/// Live figures of the pool, shared with whoever reports them
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    pub capacity: usize,
+    /// Jobs waiting for a worker to pick them up
+    pub queued: AtomicUsize,
+    /// Workers running a job right now
+    pub busy: AtomicUsize
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Counts a worker as busy for as long as it lives, so that a job which
+/// panics still gives its worker back in the figures
+struct Busy<'a>(&'a PoolStats);
+
+impl<'a> Busy<'a> {
+    fn new(stats: &'a PoolStats) -> Self {
+        stats.busy.fetch_add(1, Ordering::Relaxed);
+        Self(stats)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 struct Worker {
     _id: usize,
     thread: Option<JoinHandle<()>>
@@ -33,20 +63,26 @@ impl ThreadPool {
         let mut workers = Vec::with_capacity(capacity);
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(PoolStats { capacity, ..Default::default() });
 
         for id in 0..capacity {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&stats)));
         }
 
-        Self { workers, sender: Some(sender) }
+        Self { workers, sender: Some(sender), stats }
     }
 
     pub fn execute<F>(&self, f: F)
     where F: FnOnce() + Send + 'static
     {
         let job = Box::new(f);
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.stats)
+    }
 }
 
 impl Drop for ThreadPool {
@@ -56,19 +92,24 @@ impl Drop for ThreadPool {
 
         for worker in &mut self.workers {
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                let _ = thread.join();
             }
         }
     }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Self {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, stats: Arc<PoolStats>) -> Self {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv();
 
             match message {
-                Ok(job) => job(),        
+                Ok(job) => {
+                    stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    let _busy = Busy::new(&stats);
+                    // The panic is reported by the hook already, the worker goes on with the next job
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                },
                 Err(_) => break
             }
         });
@@ -76,3 +117,45 @@ impl Worker {
         Self { _id: id, thread: Some(thread) }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline { return false; }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn it_counts_busy_workers() {
+        let pool = ThreadPool::new(2);
+        let stats = pool.stats();
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        pool.execute(move || { let _ = receiver.recv(); });
+        assert!(wait_until(|| stats.busy.load(Ordering::Relaxed) == 1));
+        assert_eq!(stats.queued.load(Ordering::Relaxed), 0);
+
+        drop(sender);
+        assert!(wait_until(|| stats.busy.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn it_releases_busy_workers_whose_job_panicked() {
+        let pool = ThreadPool::new(1);
+        let stats = pool.stats();
+
+        pool.execute(|| panic!("job failed"));
+        assert!(wait_until(|| stats.queued.load(Ordering::Relaxed) == 0 && stats.busy.load(Ordering::Relaxed) == 0));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(()));
+    }
+}